@@ -0,0 +1,75 @@
+//! Peak memory of decoding a large commit, with and without `DecodeOptions::lazy_blocks`.
+//!
+//! ```sh
+//! cargo bench --bench car_memory --features test-util
+//! ```
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bluesky_firehose_stream::{
+    DecodeOptions, FirehoseMessage,
+    frame::Frame,
+    test_util::{CommitBuilder, TEST_DID},
+};
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, keeping track of the bytes allocated and of their peak.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Bytes allocated at the peak of decoding `frame`, on top of those allocated before.
+fn peak_decode_bytes(frame: &Frame, options: &DecodeOptions) -> usize {
+    let frame = frame.clone();
+    let baseline = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    let message = FirehoseMessage::try_from_with_options(frame, options).expect("failed to decode");
+    let peak = PEAK.load(Ordering::Relaxed) - baseline;
+    drop(message);
+    peak
+}
+
+fn main() {
+    // 100 posts along with 4 MiB of blocks no operation refers to, like the MST nodes of a
+    // commit
+    let posts = (0..100).fold(CommitBuilder::new(TEST_DID), |builder, rkey| {
+        builder.create_post(&format!("3k{rkey}"), "hello")
+    });
+    let frame = (0..=u8::MAX)
+        .fold(posts, |builder, i| builder.extra_block(vec![i; 16 * 1024]))
+        .build();
+    let frame_bytes = frame.to_bytes().expect("failed to encode").len();
+
+    for (name, lazy_blocks) in [("eager", false), ("lazy", true)] {
+        let options = DecodeOptions {
+            lazy_blocks,
+            ..Default::default()
+        };
+        let peak = peak_decode_bytes(&frame, &options);
+        println!(
+            "{name}: peak of {} KiB decoding a {} KiB frame",
+            peak / 1024,
+            frame_bytes / 1024
+        );
+    }
+}
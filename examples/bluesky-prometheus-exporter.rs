@@ -7,12 +7,11 @@ use axum::{
     Router,
 };
 use bluesky_firehose_stream::{
-    frame::Frame, metrics::create_counter_with_labels, subscription::RepoSubscription,
-    FirehoseMessage,
+    metrics::create_counter_with_labels, subscription::ReconnectingSubscription, FirehoseMessage,
 };
 use lazy_static::lazy_static;
 use prometheus::{Encoder, IntCounterVec, TextEncoder};
-use tracing::{error, info, warn};
+use tracing::{error, info};
 use tracing_subscriber::{fmt::SubscriberBuilder, util::SubscriberInitExt, EnvFilter};
 
 #[tokio::main]
@@ -60,60 +59,35 @@ pub fn generate_metrics() -> String {
     String::from_utf8(buffer).unwrap()
 }
 async fn firehose_consume_loop() {
-    loop {
-        info!("Connecting to the bluesky firehose, let's stream");
-
-        let mut subscription = match RepoSubscription::new("bsky.network").await {
-            Ok(sub) => sub,
-            Err(e) => {
-                error!("Connecting to the bluesky firehose, {e}");
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                continue;
-            }
-        };
-        info!("Connected to the bluesky firehose, let's stream");
-
-        let timeout_duration = tokio::time::Duration::from_secs(30);
-
-        while let Ok(Some(frame)) =
-            tokio::time::timeout(timeout_duration, subscription.next()).await
-        {
-            let frame = match frame {
-                Ok(f) => f,
-                Err(e) => {
-                    warn!("Unable to decode frame, skipping it! {e}");
-                    continue;
-                }
-            };
-            if let Err(e) = handle_frame(frame) {
-                error!("Unable to handle frame: {e}");
-            }
+    info!("Connecting to the bluesky firehose, let's stream");
+    // reconnects with backoff when the connection drops or stalls, resuming from the cursor
+    let mut subscription = ReconnectingSubscription::new("bsky.network");
+    while let Some(message) = subscription.next().await {
+        match message {
+            Ok(message) => handle_message(&message),
+            Err(e) => error!("Unable to decode message: {e}"),
         }
-        error!("Timeout occurred, reconnecting...");
     }
 }
 
-fn handle_frame(frame: Frame) -> Result<(), bluesky_firehose_stream::Error> {
-    let message = FirehoseMessage::try_from(frame)?;
+fn handle_message(message: &FirehoseMessage) {
     FIREHOSE_FRAME_COUNTER
         .with_label_values(&[message.kind().as_str()])
         .inc();
 
-    if let FirehoseMessage::Commit {
-        did: _,
-        operations,
-        rev: _,
-        time: _,
-        commit: _,
-    } = &message
-    {
+    if let FirehoseMessage::Info { name, message } = message {
+        info!(
+            "Relay info {name}: {}",
+            message.as_deref().unwrap_or_default()
+        );
+    }
+    if let FirehoseMessage::Commit { operations, .. } = message {
         for op in operations {
             FIREHOSE_COMMIT_COUNTER
                 .with_label_values(&[op.kind().as_str(), op.operation_meta().collection.as_str()])
                 .inc();
         }
     }
-    Ok(())
 }
 
 /// Reset commit counter every hour to avoid storing bunch of rare labels
@@ -6,9 +6,9 @@ use atrium_api::{
     types::Union,
 };
 use bluesky_firehose_stream::{
-    frame::Frame, subscription::RepoSubscription, FirehoseMessage, Operation, Record,
+    subscription::ReconnectingSubscription, FirehoseMessage, Operation, Record,
 };
-use tracing::{error, info, warn};
+use tracing::{error, info};
 use tracing_subscriber::{fmt::SubscriberBuilder, util::SubscriberInitExt, EnvFilter};
 
 #[tokio::main]
@@ -25,42 +25,32 @@ async fn main() -> anyhow::Result<()> {
         .finish()
         .init();
 
-    loop {
-        let mut subscription = RepoSubscription::new("bsky.network").await.unwrap();
+    let mut subscription = ReconnectingSubscription::new("bsky.network");
+    info!("Streaming the firehose");
 
-        info!("Connected to firehose, let's stream");
-
-        let timeout_duration = tokio::time::Duration::from_secs(30);
-
-        while let Ok(Some(frame)) =
-            tokio::time::timeout(timeout_duration, subscription.next()).await
-        {
-            let frame = match frame {
-                Ok(f) => f,
-                Err(e) => {
-                    warn!("Unable to decode frame, skipping it! {e}");
-                    continue;
-                }
-            };
-            if let Err(e) = handle_frame(frame) {
-                error!("Unable to handle frame: {e}");
-            }
+    while let Some(message) = subscription.next().await {
+        match message {
+            Ok(message) => handle_message(message),
+            Err(e) => error!("Unable to decode message: {e}"),
         }
-        error!("Timeout occurred, reconnecting...");
     }
+    Ok(())
 }
-fn handle_frame(frame: Frame) -> Result<(), bluesky_firehose_stream::Error> {
-    let message = FirehoseMessage::try_from(frame)?;
-
+fn handle_message(message: FirehoseMessage) {
     // for now do nothing
     if let FirehoseMessage::Identity(_identity) = &message {
         //println!("{}", serde_json::to_string(&identity).unwrap())
     }
     if let FirehoseMessage::Commit {
         did: _,
+        seq: _,
         operations,
         rev: _,
         time: _,
+        decode_errors: _,
+        truncated_ops: _,
+        too_big: _,
+        received_at: _,
         commit: _,
     } = &message
     {
@@ -69,6 +59,7 @@ fn handle_frame(frame: Frame) -> Result<(), bluesky_firehose_stream::Error> {
                 operation_meta: _,
                 record,
                 cid: _,
+                ipld: _,
             } = op
             {
                 if let Record::Starterpack(pack) = record {
@@ -123,5 +114,4 @@ fn handle_frame(frame: Frame) -> Result<(), bluesky_firehose_stream::Error> {
             }
         }
     }
-    Ok(())
 }
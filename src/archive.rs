@@ -0,0 +1,330 @@
+//! Record raw firehose frames to rotating segment files, to be replayed later.
+//!
+//! A segment is a sequence of frames, each prefixed by its length as a big-endian `u32`.
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::frame::{self, Frame};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid frame: {0}")]
+    Frame(#[from] frame::Error),
+}
+
+/// When to start a new file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rotation {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+impl Rotation {
+    pub(crate) fn is_due(&self, bytes: u64, opened_at: Instant) -> bool {
+        self.max_bytes.is_some_and(|max_bytes| bytes >= max_bytes)
+            || self
+                .max_age
+                .is_some_and(|max_age| opened_at.elapsed() >= max_age)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArchiveOptions {
+    pub rotation: Rotation,
+    /// Compress completed segments with zstd, appending `.zst` to their name.
+    #[cfg(feature = "compress")]
+    pub compress: bool,
+}
+
+struct Segment {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    bytes: u64,
+    opened_at: Instant,
+}
+
+/// Write frames to segment files named `{started}.{segment}.frames` in `dir`, `started` being
+/// the unix timestamp of the archive creation and `segment` incremented on each rotation.
+///
+/// The current segment is completed by [`close`](Self::close), or when the archive is
+/// dropped.
+pub struct FrameArchive {
+    dir: PathBuf,
+    options: ArchiveOptions,
+    started: u64,
+    next_segment: u64,
+    current: Option<Segment>,
+}
+
+impl FrameArchive {
+    pub fn new(dir: impl Into<PathBuf>, options: ArchiveOptions) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FrameArchive {
+            dir,
+            options,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            next_segment: 0,
+            current: None,
+        })
+    }
+    /// Append a frame, as received from the websocket.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let rotation = &self.options.rotation;
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|segment| rotation.is_due(segment.bytes, segment.opened_at))
+        {
+            self.finish_segment()?;
+        }
+        let segment = match self.current.take() {
+            Some(segment) => segment,
+            None => self.open_segment()?,
+        };
+        let segment = self.current.insert(segment);
+        let len = u32::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+        segment.writer.write_all(&len.to_be_bytes())?;
+        segment.writer.write_all(data)?;
+        segment.bytes += 4 + data.len() as u64;
+        Ok(())
+    }
+    /// Append a decoded frame, encoded back with [`Frame::to_bytes`].
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        self.write(&frame.to_bytes()?)
+    }
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if let Some(segment) = &mut self.current {
+            segment.writer.flush()?;
+        }
+        Ok(())
+    }
+    /// Flush and complete the current segment, compressing it if enabled. Frames written
+    /// afterwards go to a new segment.
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.finish_segment()
+    }
+    fn open_segment(&mut self) -> Result<Segment, Error> {
+        let path = self
+            .dir
+            .join(format!("{}.{}.frames", self.started, self.next_segment));
+        self.next_segment += 1;
+        Ok(Segment {
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            bytes: 0,
+            opened_at: Instant::now(),
+        })
+    }
+    fn finish_segment(&mut self) -> Result<(), Error> {
+        let Some(segment) = self.current.take() else {
+            return Ok(());
+        };
+        segment
+            .writer
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        tracing::debug!("Completed frame archive segment {}", segment.path.display());
+        #[cfg(feature = "compress")]
+        if self.options.compress {
+            compress(&segment.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FrameArchive {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish_segment() {
+            tracing::error!("Unable to complete the frame archive segment: {e}");
+        }
+    }
+}
+
+#[cfg(feature = "compress")]
+fn compress(path: &Path) -> io::Result<()> {
+    let mut compressed = path.as_os_str().to_owned();
+    compressed.push(".zst");
+    zstd::stream::copy_encode(File::open(path)?, File::create(compressed)?, 0)?;
+    fs::remove_file(path)
+}
+
+/// Frames of a segment file, decompressed when its name ends with `.zst`.
+///
+/// A frame cut short at the end of the segment, e.g. by a crash of the recorder, is an error,
+/// be it in its length prefix or its data.
+pub struct SegmentReader {
+    reader: Box<dyn Read + Send>,
+}
+
+impl SegmentReader {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let file = BufReader::new(File::open(path)?);
+        let reader: Box<dyn Read + Send> = if path.extension().is_some_and(|ext| ext == "zst") {
+            #[cfg(feature = "compress")]
+            {
+                Box::new(zstd::stream::read::Decoder::with_buffer(file)?)
+            }
+            #[cfg(not(feature = "compress"))]
+            {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "compressed segment, the `compress` feature is disabled",
+                )));
+            }
+        } else {
+            Box::new(file)
+        };
+        Ok(SegmentReader { reader })
+    }
+    /// The next frame as written, `None` at the end of the segment.
+    pub fn next_raw(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let mut len = Vec::with_capacity(4);
+        match self.read_up_to(4, &mut len)? {
+            0 => return Ok(None),
+            4 => {}
+            _ => return Err(truncated_frame()),
+        }
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        // grown as data is read, a corrupt length cannot allocate more than the segment holds
+        let mut data = Vec::new();
+        if self.read_up_to(len, &mut data)? < len {
+            return Err(truncated_frame());
+        }
+        Ok(Some(data))
+    }
+    fn read_up_to(&mut self, len: usize, buf: &mut Vec<u8>) -> io::Result<usize> {
+        (&mut self.reader).take(len as u64).read_to_end(buf)
+    }
+}
+
+fn truncated_frame() -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "frame cut short at the end of the segment",
+    ))
+}
+
+impl Iterator for SegmentReader {
+    type Item = Result<Frame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_raw()
+            .transpose()
+            .map(|data| Ok(Frame::try_from(&data?[..])?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{CommitBuilder, TEST_DID};
+
+    fn segments(dir: &Path) -> Vec<PathBuf> {
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn recorded_frames_are_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+        let frames = (0..3)
+            .map(|seq| CommitBuilder::new(TEST_DID).seq(seq).build())
+            .collect::<Vec<_>>();
+        let mut archive = FrameArchive::new(dir.path(), ArchiveOptions::default()).unwrap();
+        for frame in &frames {
+            archive.write_frame(frame).unwrap();
+        }
+        archive.close().unwrap();
+
+        let files = segments(dir.path());
+        assert_eq!(files.len(), 1);
+        let replayed = SegmentReader::open(&files[0])
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(replayed, frames);
+    }
+
+    #[test]
+    fn segments_rotate_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = ArchiveOptions {
+            rotation: Rotation {
+                max_bytes: Some(1),
+                max_age: None,
+            },
+            ..Default::default()
+        };
+        let mut archive = FrameArchive::new(dir.path(), options).unwrap();
+        for seq in 0..2 {
+            archive
+                .write(&CommitBuilder::new(TEST_DID).seq(seq).to_bytes())
+                .unwrap();
+        }
+        // dropping the archive completes the current segment
+        drop(archive);
+
+        let files = segments(dir.path());
+        assert_eq!(files.len(), 2);
+        for file in files {
+            assert_eq!(SegmentReader::open(file).unwrap().count(), 1);
+        }
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let frame = CommitBuilder::new(TEST_DID).to_bytes();
+        let mut segment = (frame.len() as u32).to_be_bytes().to_vec();
+        segment.extend(&frame);
+        for tail in [&[0, 0][..], &u32::MAX.to_be_bytes(), &[0, 0, 0, 8, 1, 2]] {
+            let path = dir.path().join("truncated");
+            fs::write(&path, [&segment[..], tail].concat()).unwrap();
+            let mut reader = SegmentReader::open(&path).unwrap();
+            assert_eq!(reader.next_raw().unwrap(), Some(frame.clone()));
+            let Err(Error::Io(error)) = reader.next_raw() else {
+                panic!("expected a truncated frame");
+            };
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[cfg(feature = "compress")]
+    #[test]
+    fn completed_segments_are_compressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = ArchiveOptions {
+            compress: true,
+            ..Default::default()
+        };
+        let mut archive = FrameArchive::new(dir.path(), options).unwrap();
+        let frame = CommitBuilder::new(TEST_DID).build();
+        archive.write_frame(&frame).unwrap();
+        archive.close().unwrap();
+
+        let files = segments(dir.path());
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "zst");
+        let replayed = SegmentReader::open(&files[0])
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(replayed, [frame]);
+    }
+}
@@ -0,0 +1,247 @@
+//! Zero-copy CARv1 reader keeping only the blocks a commit refers to.
+//!
+//! `rs_car_sync::car_read_all` copies every block of the CAR file into its own `Vec`. Commits
+//! also carry MST nodes that are never decoded, so [`read_blocks`] instead walks the CAR file
+//! in place and borrows the wanted blocks, peak memory staying at the size of the frame.
+//! `benches/car_memory.rs` measures both paths on a commit carrying 4 MiB of unreferenced
+//! blocks.
+use std::{
+    collections::{HashMap, HashSet},
+    io::Cursor,
+};
+
+use ipld_core::cid::Cid;
+use sha2::{Digest, Sha256};
+
+const SHA2_256: u64 = 0x12;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid varint in CAR data")]
+    InvalidVarint,
+    #[error("Truncated CAR data")]
+    Truncated,
+    #[error("Invalid block CID: {0}")]
+    InvalidCid(#[from] ipld_core::cid::Error),
+    #[error("Unsupported block hash {0:#x}")]
+    UnsupportedHash(u64),
+    #[error("Block data does not match its CID {0}")]
+    HashMismatch(Cid),
+}
+
+/// Blocks of `car` whose CID is in `wanted`, hash checked and borrowed from `car`.
+///
+/// A malformed CAR file may hold a block more than once: the first one is kept, the duplicates
+/// are logged, counted and skipped without being hash checked. Lookups in CAR files read with
+/// `rs_car_sync` keep the first block too.
+pub fn read_blocks<'a>(
+    car: &'a [u8],
+    wanted: &HashSet<Cid>,
+) -> Result<HashMap<Cid, &'a [u8]>, Error> {
+    let mut rest = car;
+    let header_len = read_varint(&mut rest)?;
+    rest = rest.get(header_len..).ok_or(Error::Truncated)?;
+
+    let mut blocks = HashMap::with_capacity(wanted.len());
+    while !rest.is_empty() {
+        let section_len = read_varint(&mut rest)?;
+        let section = rest.get(..section_len).ok_or(Error::Truncated)?;
+        rest = &rest[section_len..];
+
+        let mut cursor = Cursor::new(section);
+        let cid = Cid::read_bytes(&mut cursor)?;
+        if !wanted.contains(&cid) {
+            continue;
+        }
+        if blocks.contains_key(&cid) {
+            tracing::warn!("Duplicate block {cid} in CAR data, keeping the first one");
+            #[cfg(feature = "prometheus")]
+            crate::metrics::CAR_DUPLICATE_BLOCKS.inc();
+            continue;
+        }
+        let data = &section[cursor.position() as usize..];
+        if cid.hash().code() != SHA2_256 {
+            return Err(Error::UnsupportedHash(cid.hash().code()));
+        }
+        if Sha256::digest(data).as_slice() != cid.hash().digest() {
+            return Err(Error::HashMismatch(cid));
+        }
+        blocks.insert(cid, data);
+    }
+    Ok(blocks)
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<usize, Error> {
+    let mut value = 0u64;
+    for (i, byte) in data.iter().enumerate().take(9) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *data = &data[i + 1..];
+            return usize::try_from(value).map_err(|_| Error::InvalidVarint);
+        }
+    }
+    Err(Error::InvalidVarint)
+}
+
+/// Every block of the CAR file of a commit, for consumers keeping them (e.g. to rebuild the
+/// MST or audit the commit later), see
+/// [`FirehoseMessage::try_from_with_blocks`](crate::FirehoseMessage::try_from_with_blocks).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarBlocks {
+    /// CID of the signed commit block.
+    pub root: Cid,
+    /// In the order of the CAR file, duplicates included.
+    pub blocks: Vec<(Cid, Vec<u8>)>,
+}
+
+/// Decoded blocks of a commit, either copied by `rs_car_sync` or borrowed by [`read_blocks`].
+pub(crate) enum Blocks<'a> {
+    Owned(HashMap<Cid, Vec<u8>>),
+    Borrowed(HashMap<Cid, &'a [u8]>),
+}
+
+impl<'a> Blocks<'a> {
+    /// Index the blocks read by `rs_car_sync`, keeping the first of duplicates as
+    /// [`read_blocks`] does.
+    pub(crate) fn owned(blocks: Vec<(Cid, Vec<u8>)>) -> Self {
+        let mut index = HashMap::with_capacity(blocks.len());
+        for (cid, data) in blocks {
+            index.entry(cid).or_insert(data);
+        }
+        Blocks::Owned(index)
+    }
+    /// Index blocks read by `rs_car_sync` and kept by the caller, keeping the first of
+    /// duplicates too.
+    pub(crate) fn indexed(blocks: &'a [(Cid, Vec<u8>)]) -> Self {
+        let mut index = HashMap::with_capacity(blocks.len());
+        for (cid, data) in blocks {
+            index.entry(*cid).or_insert(data.as_slice());
+        }
+        Blocks::Borrowed(index)
+    }
+    pub(crate) fn get(&self, cid: &Cid) -> Option<&[u8]> {
+        match self {
+            Blocks::Owned(blocks) => blocks.get(cid).map(Vec::as_slice),
+            Blocks::Borrowed(blocks) => blocks.get(cid).copied(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        DecodeOptions, FirehoseMessage,
+        test_util::{CommitBuilder, TEST_DID, car_bytes, cid_for},
+    };
+
+    #[test]
+    fn only_wanted_blocks_are_read() {
+        let blocks = vec![b"root".to_vec(), b"wanted".to_vec(), b"unwanted".to_vec()];
+        let car = car_bytes(&cid_for(b"root"), &blocks);
+        let wanted = HashSet::from([cid_for(b"wanted")]);
+
+        let read = read_blocks(&car, &wanted).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read.get(&cid_for(b"wanted")), Some(&&b"wanted"[..]));
+    }
+
+    #[test]
+    fn owned_blocks_are_indexed() {
+        let blocks = Blocks::owned(vec![
+            (cid_for(b"a"), b"a".to_vec()),
+            (cid_for(b"b"), b"b".to_vec()),
+            (cid_for(b"a"), b"duplicate".to_vec()),
+        ]);
+        assert_eq!(blocks.get(&cid_for(b"a")), Some(&b"a"[..]));
+        assert_eq!(blocks.get(&cid_for(b"b")), Some(&b"b"[..]));
+        assert_eq!(blocks.get(&cid_for(b"c")), None);
+    }
+
+    #[test]
+    fn duplicate_blocks_keep_the_first_one() {
+        let blocks = vec![b"wanted".to_vec(), b"wanted".to_vec()];
+        let mut car = car_bytes(&cid_for(b"root"), &blocks);
+        // tamper with the duplicate: it is not even read
+        let last = car.len() - 1;
+        car[last] ^= 0xff;
+        let wanted = HashSet::from([cid_for(b"wanted")]);
+
+        #[cfg(feature = "prometheus")]
+        let before = crate::metrics::CAR_DUPLICATE_BLOCKS.get();
+        let read = read_blocks(&car, &wanted).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read.get(&cid_for(b"wanted")), Some(&&b"wanted"[..]));
+        #[cfg(feature = "prometheus")]
+        assert_eq!(crate::metrics::CAR_DUPLICATE_BLOCKS.get() - before, 1);
+    }
+
+    #[test]
+    fn tampered_block_is_rejected() {
+        let mut car = car_bytes(&cid_for(b"root"), &[b"wanted".to_vec()]);
+        let last = car.len() - 1;
+        car[last] ^= 0xff;
+        let wanted = HashSet::from([cid_for(b"wanted")]);
+        assert!(matches!(
+            read_blocks(&car, &wanted),
+            Err(Error::HashMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn lazy_decode_matches_eager_decode() {
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .update(
+                "app.bsky.feed.post",
+                "3kdef",
+                crate::test_util::post_record("edited"),
+            )
+            .delete("app.bsky.feed.like", "3kghi")
+            .extra_block(vec![0xa0; 4096])
+            .build();
+        let operations = |options: &DecodeOptions| {
+            let FirehoseMessage::Commit { operations, .. } =
+                FirehoseMessage::try_from_with_options(frame.clone(), options).unwrap()
+            else {
+                panic!("expected a commit");
+            };
+            ipld_core::serde::to_ipld(&operations).unwrap()
+        };
+        let lazy = DecodeOptions {
+            lazy_blocks: true,
+            ..Default::default()
+        };
+        assert_eq!(operations(&lazy), operations(&DecodeOptions::default()));
+    }
+
+    #[test]
+    fn commit_blocks_are_kept() {
+        let builder = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .extra_block(b"mst node".to_vec());
+        let (message, car) =
+            FirehoseMessage::try_from_with_blocks(builder.build(), &DecodeOptions::default())
+                .unwrap();
+        let car = car.unwrap();
+        let FirehoseMessage::Commit {
+            operations, commit, ..
+        } = message
+        else {
+            panic!("expected a commit");
+        };
+        assert_eq!(operations.len(), 1);
+        assert_eq!(car.root, commit.commit.0);
+        assert_eq!(car.blocks.len(), 3);
+        assert_eq!(car.blocks[0].0, car.root);
+        assert!(
+            car.blocks
+                .contains(&(cid_for(b"mst node"), b"mst node".to_vec()))
+        );
+
+        let identity = crate::test_util::identity_frame(TEST_DID, 1, None);
+        let (_, car) =
+            FirehoseMessage::try_from_with_blocks(identity, &DecodeOptions::default()).unwrap();
+        assert_eq!(car, None);
+    }
+}
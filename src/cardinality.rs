@@ -0,0 +1,93 @@
+//! Approximate count of distinct repositories over a sliding window.
+use std::{collections::VecDeque, time::Duration};
+
+use atrium_api::types::string::Did;
+use tokio::time::Instant;
+
+use crate::partition::fnv1a;
+
+/// HyperLogLog precision: `2^12` registers, a standard error of about 1.6%.
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+/// Number of sketches the window is split into.
+const SLOTS: u32 = 6;
+
+/// Distinct DIDs seen during the last `window`, estimated with HyperLogLog sketches.
+///
+/// Memory is bounded whatever the number of DIDs (a few tens of KiB). The window slides by
+/// steps of `window / 6`: a DID is forgotten between five sixths of `window` and `window`
+/// after it was last seen.
+pub struct DistinctDidCounter {
+    window: Duration,
+    slot_duration: Duration,
+    /// Start of each slot and its registers, oldest first.
+    slots: VecDeque<(Instant, Box<[u8]>)>,
+}
+
+impl DistinctDidCounter {
+    pub fn new(window: Duration) -> Self {
+        DistinctDidCounter {
+            window,
+            slot_duration: window / SLOTS,
+            slots: VecDeque::new(),
+        }
+    }
+    pub fn insert(&mut self, did: &Did, now: Instant) {
+        self.expire(now);
+        let slot_ended = self
+            .slots
+            .back()
+            .is_none_or(|(start, _)| *start + self.slot_duration <= now);
+        if slot_ended {
+            self.slots
+                .push_back((now, vec![0; REGISTERS].into_boxed_slice()));
+        }
+        let current = &mut self.slots.back_mut().unwrap().1;
+        let hash = mix(fnv1a(did.as_str()));
+        let index = (hash >> (64 - PRECISION)) as usize;
+        let rank = ((hash << PRECISION).leading_zeros() + 1).min(64 - PRECISION + 1) as u8;
+        current[index] = current[index].max(rank);
+    }
+    /// Estimated number of distinct DIDs inserted during the window ending at `now`.
+    pub fn estimate(&mut self, now: Instant) -> u64 {
+        self.expire(now);
+        if self.slots.is_empty() {
+            return 0;
+        }
+        let mut merged = vec![0u8; REGISTERS];
+        for (_, registers) in &self.slots {
+            for (merged, register) in merged.iter_mut().zip(registers.iter()) {
+                *merged = (*merged).max(*register);
+            }
+        }
+        let m = REGISTERS as f64;
+        let sum = merged
+            .iter()
+            .map(|register| 2f64.powi(-i32::from(*register)))
+            .sum::<f64>();
+        let raw = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let zeros = merged.iter().filter(|register| **register == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            // linear counting is more accurate for small cardinalities
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+    fn expire(&mut self, now: Instant) {
+        while let Some((start, _)) = self.slots.front()
+            && *start + self.window <= now
+        {
+            self.slots.pop_front();
+        }
+    }
+}
+
+/// Spread the bits of the FNV hash, HyperLogLog relies on the leading ones.
+fn mix(mut hash: u64) -> u64 {
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
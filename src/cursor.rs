@@ -0,0 +1,149 @@
+//! Persist the sequence number of the last message processed, to resume from it after a
+//! restart.
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// Where a consumer keeps its cursor, e.g. a file or a key of a database.
+pub trait CursorStore {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// The stored cursor, `None` before the first message was processed.
+    fn load(&mut self) -> Result<Option<i64>, Self::Error>;
+    /// Store the sequence number of a processed message.
+    fn store(&mut self, seq: i64) -> Result<(), Self::Error>;
+}
+
+pub(crate) type BoxedCursorStore =
+    Box<dyn CursorStore<Error = Box<dyn std::error::Error + Send + Sync>> + Send>;
+
+/// Erase the error type of a store, to keep it in a non-generic subscription.
+struct BoxedErrors<S>(S);
+
+impl<S: CursorStore> CursorStore for BoxedErrors<S> {
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn load(&mut self) -> Result<Option<i64>, Self::Error> {
+        Ok(self.0.load()?)
+    }
+    fn store(&mut self, seq: i64) -> Result<(), Self::Error> {
+        Ok(self.0.store(seq)?)
+    }
+}
+
+pub(crate) fn boxed(store: impl CursorStore + Send + 'static) -> BoxedCursorStore {
+    Box::new(BoxedErrors(store))
+}
+
+/// Keep the cursor in a file, replaced atomically by writing a temporary file next to it
+/// then renaming it.
+///
+/// With [`debounce`](Self::debounce), a cursor is only written when the previous write is
+/// older than the delay: the others are kept in memory until the next write,
+/// [`flush`](Self::flush) or drop. A crash then resumes from a slightly older cursor, replaying
+/// a few messages.
+pub struct FileCursorStore {
+    path: PathBuf,
+    debounce: Duration,
+    written_at: Option<Instant>,
+    pending: Option<i64>,
+}
+
+impl FileCursorStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileCursorStore {
+            path: path.into(),
+            debounce: Duration::ZERO,
+            written_at: None,
+            pending: None,
+        }
+    }
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+    /// Write the cursor kept in memory by the debouncing, if any. It is kept in memory until
+    /// written successfully.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let Some(seq) = self.pending else {
+            return Ok(());
+        };
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(seq.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.pending = None;
+        self.written_at = Some(Instant::now());
+        Ok(())
+    }
+}
+
+impl CursorStore for FileCursorStore {
+    type Error = io::Error;
+
+    fn load(&mut self) -> io::Result<Option<i64>> {
+        if let Some(seq) = self.pending {
+            return Ok(Some(seq));
+        }
+        match fs::read_to_string(&self.path) {
+            Ok(content) => content
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    fn store(&mut self, seq: i64) -> io::Result<()> {
+        self.pending = Some(seq);
+        if self
+            .written_at
+            .is_some_and(|written_at| written_at.elapsed() < self.debounce)
+        {
+            return Ok(());
+        }
+        self.flush()
+    }
+}
+
+impl Drop for FileCursorStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            tracing::error!("Unable to write the cursor to {}: {e}", self.path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_cursor_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cursor");
+        let mut store = FileCursorStore::new(&path).debounce(Duration::from_secs(3600));
+        assert_eq!(store.load().unwrap(), None);
+
+        store.store(1).unwrap();
+        store.store(2).unwrap();
+        // the second cursor is debounced
+        assert_eq!(FileCursorStore::new(&path).load().unwrap(), Some(1));
+        assert_eq!(store.load().unwrap(), Some(2));
+
+        drop(store);
+        assert_eq!(FileCursorStore::new(&path).load().unwrap(), Some(2));
+        assert!(!dir.path().join("cursor.tmp").exists());
+
+        let mut store = FileCursorStore::new(dir.path().join("missing").join("cursor"));
+        assert!(store.store(3).is_err());
+        // still pending, retried by the next write
+        assert_eq!(store.load().unwrap(), Some(3));
+    }
+}
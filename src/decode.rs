@@ -0,0 +1,738 @@
+//! Options tuning how frames are decoded into [`FirehoseMessage`](crate::FirehoseMessage)s.
+use std::{borrow::Cow, collections::HashSet, ops::ControlFlow, sync::Arc};
+
+use atrium_api::{
+    com::atproto::sync::subscribe_repos::{Commit, RepoOp},
+    types::string::Did,
+};
+use ipld_core::ipld::Ipld;
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+
+use crate::{
+    Error, Operation,
+    frame::{Frame, MessageFrame},
+};
+
+/// What to do with operations failing one of the [`DecodeOptions`] checks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DecodeMode {
+    /// Drop the offending operation (with a warning) and keep the rest of the commit, the
+    /// dropped operation being reported in
+    /// [`decode_errors`](crate::FirehoseMessage::Commit::decode_errors).
+    ///
+    /// Operation paths are barely validated: the first segment is used as the collection and
+    /// the second one as the rkey, any extra segment is ignored. Operations without an rkey are
+    /// dropped.
+    #[default]
+    Lenient,
+    /// Fail the whole message. Operation paths must also be exactly `collection/rkey`,
+    /// [`Error::InvalidOpPath`](crate::Error::InvalidOpPath) otherwise.
+    Strict,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    pub mode: DecodeMode,
+    /// Only decode commits of these repositories. Commits of other repositories
+    /// are returned as [`FirehoseMessage::Skipped`](crate::FirehoseMessage::Skipped)
+    /// without reading their CAR file.
+    pub wanted_dids: Option<HashSet<String>>,
+    /// Maximum size of a record block, checked before decoding it.
+    pub max_record_bytes: Option<usize>,
+    /// Read the commit CAR file in place, keeping only the blocks referenced by its
+    /// operations instead of copying all of them. See [`crate::car`].
+    pub lazy_blocks: bool,
+    /// Maximum number of operations of a commit, checked before reading its CAR file.
+    /// Extra operations are dropped in [`DecodeMode::Lenient`].
+    pub max_ops_per_commit: Option<usize>,
+    /// Normalize operation collections with [`normalize_nsid`] before decoding their record:
+    /// [`OperationMeta::collection`](crate::OperationMeta::collection) may then differ from
+    /// the path sent by the relay.
+    pub normalize_collections: bool,
+    /// Keep the wall-clock receipt time of commits in
+    /// [`FirehoseMessage::Commit::received_at`](crate::FirehoseMessage::Commit).
+    pub receipt_time: bool,
+    /// Only decode operations on these collections, the others are dropped before their
+    /// record is read (and before the CAR file is, if none is left).
+    pub collections: Option<CollectionFilter>,
+    /// Also decode records as [`Ipld`], see [`Operation::ipld`](crate::Operation::ipld): each
+    /// record block is then parsed twice.
+    pub keep_ipld: bool,
+    /// Permits to decode a commit, typically shared by the subscriptions of an application to
+    /// bound the CPU spent reading CAR files. Only awaited by
+    /// [`FirehoseMessage::try_from_limited`](crate::FirehoseMessage::try_from_limited), which
+    /// the subscriptions and stream adapters of this crate use.
+    pub decode_permits: Option<Arc<Semaphore>>,
+    /// Check that the `$type` of each record is its collection, e.g. that no like is stored
+    /// under `app.bsky.feed.post`. Each record block is then parsed twice.
+    pub check_record_type: bool,
+}
+
+/// Allowlist of collections (NSIDs), see [`DecodeOptions::collections`].
+#[derive(Debug, Clone, Default)]
+pub struct CollectionFilter {
+    pub collections: HashSet<String>,
+    /// Keep deletes of the other collections: they carry no record to decode, but a
+    /// mirror may need them.
+    pub keep_deletes: bool,
+}
+
+impl CollectionFilter {
+    pub fn new<S: Into<String>>(collections: impl IntoIterator<Item = S>) -> Self {
+        CollectionFilter {
+            collections: collections.into_iter().map(Into::into).collect(),
+            keep_deletes: false,
+        }
+    }
+    pub fn wants(&self, action: &str, collection: &str) -> bool {
+        self.collections.contains(collection) || (self.keep_deletes && action == "delete")
+    }
+}
+
+impl DecodeOptions {
+    pub(crate) fn wants_did(&self, did: &Did) -> bool {
+        self.wanted_dids
+            .as_ref()
+            .is_none_or(|wanted_dids| wanted_dids.contains(did.as_str()))
+    }
+    pub(crate) fn wants_op(&self, op: &RepoOp) -> bool {
+        let Some(filter) = &self.collections else {
+            return true;
+        };
+        let nsid = op.path.split('/').next().unwrap_or_default();
+        if self.normalize_collections {
+            filter.wants(&op.action, &normalize_nsid(nsid))
+        } else {
+            filter.wants(&op.action, nsid)
+        }
+    }
+}
+
+/// Trim an NSID and lowercase its domain authority, e.g. ` App.Bsky.Feed.post` becomes
+/// `app.bsky.feed.post`. The name (last segment) is case-sensitive and kept as is.
+pub fn normalize_nsid(nsid: &str) -> Cow<'_, str> {
+    let nsid = nsid.trim();
+    let authority_len = nsid.rfind('.').unwrap_or_default();
+    if !nsid[..authority_len]
+        .bytes()
+        .any(|b| b.is_ascii_uppercase())
+    {
+        return Cow::Borrowed(nsid);
+    }
+    Cow::Owned(nsid[..authority_len].to_ascii_lowercase() + &nsid[authority_len..])
+}
+
+/// Decode the operations of a `#commit` frame one at a time, handing each one to `f` as soon
+/// as it is decoded instead of collecting them: `f` can process and drop it, or stop the
+/// decoding by returning [`ControlFlow::Break`].
+///
+/// Returns the commit, `None` for other frames and for commits skipped by
+/// [`DecodeOptions::wanted_dids`]. Operations dropped in [`DecodeMode::Lenient`] are only
+/// logged.
+pub fn decode_commit_with(
+    frame: &Frame,
+    options: &DecodeOptions,
+    f: impl FnMut(Operation) -> ControlFlow<()>,
+) -> Result<Option<Commit>, Error> {
+    let Frame::Message(Some(t), message_frame) = frame else {
+        return Ok(None);
+    };
+    if t != "#commit" {
+        return Ok(None);
+    }
+    let commit = decode_commit(message_frame)?;
+    if !options.wants_did(&commit.repo) {
+        return Ok(None);
+    }
+    crate::decode_operations(&commit, options, None, f)?;
+    Ok(Some(commit))
+}
+
+/// Only looks at the `$type` of a record, the rest of the block is skipped.
+#[derive(Deserialize)]
+struct RecordTypeProbe {
+    #[serde(rename = "$type")]
+    record_type: Option<String>,
+}
+
+/// `$type` of a record block, `None` when missing or when the block is not a map.
+pub(crate) fn record_type(block: &[u8]) -> Option<String> {
+    serde_ipld_dagcbor::from_slice::<RecordTypeProbe>(block)
+        .ok()?
+        .record_type
+}
+
+/// Layout of `#commit` bodies, which changed with the sync v1.1 relay protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CommitSchema {
+    /// Carries the `tooBig`, `rebase` and `blobs` fields.
+    Legacy,
+    /// Deprecated fields dropped, `prevData` added.
+    Sync11,
+}
+
+/// Only looks at the fields telling the schemas apart, the rest of the body is skipped.
+#[derive(Deserialize)]
+struct CommitProbe {
+    #[serde(rename = "tooBig")]
+    too_big: Option<bool>,
+    rebase: Option<bool>,
+}
+
+impl CommitProbe {
+    fn schema(&self) -> CommitSchema {
+        if self.too_big.is_some() && self.rebase.is_some() {
+            CommitSchema::Legacy
+        } else {
+            CommitSchema::Sync11
+        }
+    }
+}
+
+/// Decode the body of a `#commit` frame, whichever schema the relay emits.
+///
+/// The atrium `Commit` still requires the deprecated fields: they are filled with their
+/// default values for sync v1.1 commits, at the cost of an intermediate [`Ipld`] decoding.
+pub(crate) fn decode_commit(message_frame: &MessageFrame) -> Result<Commit, Error> {
+    let body = message_frame.body.as_slice();
+    let decode_error = |e| Error::DagCborDecodeError(e, message_frame.clone());
+    let probe = serde_ipld_dagcbor::from_slice::<CommitProbe>(body).map_err(decode_error)?;
+    match probe.schema() {
+        CommitSchema::Legacy => serde_ipld_dagcbor::from_slice(body).map_err(decode_error),
+        CommitSchema::Sync11 => {
+            let mut ipld = serde_ipld_dagcbor::from_slice::<Ipld>(body).map_err(decode_error)?;
+            if let Ipld::Map(map) = &mut ipld {
+                for (field, default) in [
+                    ("tooBig", Ipld::Bool(false)),
+                    ("rebase", Ipld::Bool(false)),
+                    ("blobs", Ipld::List(Vec::new())),
+                ] {
+                    map.entry(field.to_string()).or_insert(default);
+                }
+            }
+            ipld_core::serde::from_ipld(ipld)
+                .map_err(|e| Error::IpldDecodeError(e, message_frame.clone()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        FirehoseMessage,
+        test_util::{
+            CommitBuilder, TEST_DID, car_bytes, cid_for, ipld_map, like_record, message_frame,
+            post_record,
+        },
+    };
+
+    fn commit_operations(message: FirehoseMessage) -> Vec<crate::Operation> {
+        match message {
+            FirehoseMessage::Commit { operations, .. } => operations,
+            _ => panic!("expected a commit"),
+        }
+    }
+
+    #[test]
+    fn unwanted_did_skips_car_parsing() {
+        // garbage CAR data: decoding it would fail
+        let frame = CommitBuilder::new(TEST_DID)
+            .seq(7)
+            .raw_blocks(vec![0xff, 0x00, 0x42])
+            .build();
+        let options = DecodeOptions {
+            wanted_dids: Some(HashSet::from(["did:plc:someoneelse".to_string()])),
+            ..Default::default()
+        };
+        match FirehoseMessage::try_from_with_options(frame.clone(), &options) {
+            Ok(FirehoseMessage::Skipped { did, seq }) => {
+                assert_eq!(did.as_str(), TEST_DID);
+                assert_eq!(seq, 7);
+            }
+            _ => panic!("expected a skipped commit"),
+        }
+
+        let options = DecodeOptions {
+            wanted_dids: Some(HashSet::from([TEST_DID.to_string()])),
+            ..Default::default()
+        };
+        assert!(matches!(
+            FirehoseMessage::try_from_with_options(frame, &options),
+            Err(Error::CarDecodeError(..))
+        ));
+    }
+
+    #[test]
+    fn oversized_records() {
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3kbig", &"a".repeat(1000))
+            .create_post("3ksmall", "hello")
+            .build();
+
+        let lenient = DecodeOptions {
+            max_record_bytes: Some(200),
+            ..Default::default()
+        };
+        let operations = commit_operations(
+            FirehoseMessage::try_from_with_options(frame.clone(), &lenient).unwrap(),
+        );
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].operation_meta().rkey, "3ksmall");
+
+        let strict = DecodeOptions {
+            mode: DecodeMode::Strict,
+            ..lenient
+        };
+        assert!(matches!(
+            FirehoseMessage::try_from_with_options(frame, &strict),
+            Err(Error::RecordTooLarge {
+                max_record_bytes: 200,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn extra_path_segments() {
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc/extra", "hello")
+            .build();
+
+        let operations = commit_operations(FirehoseMessage::try_from(frame.clone()).unwrap());
+        assert_eq!(operations.len(), 1);
+        assert_eq!(
+            operations[0].operation_meta().collection,
+            "app.bsky.feed.post"
+        );
+        assert_eq!(operations[0].operation_meta().rkey, "3kabc");
+
+        let strict = DecodeOptions {
+            mode: DecodeMode::Strict,
+            ..Default::default()
+        };
+        match FirehoseMessage::try_from_with_options(frame, &strict) {
+            Err(Error::InvalidOpPath { path }) => {
+                assert_eq!(path, "app.bsky.feed.post/3kabc/extra")
+            }
+            _ => panic!("expected an invalid path error"),
+        }
+    }
+
+    #[test]
+    fn record_type_must_match_the_collection() {
+        let frame = CommitBuilder::new(TEST_DID)
+            .create(
+                "com.example.thing",
+                "3kabc",
+                like_record(
+                    "at://did:plc:other/app.bsky.feed.post/3kabc",
+                    &cid_for(b"post"),
+                ),
+            )
+            .create(
+                "app.bsky.feed.like",
+                "3kabd",
+                like_record(
+                    "at://did:plc:other/app.bsky.feed.post/3kabd",
+                    &cid_for(b"post"),
+                ),
+            )
+            .build();
+
+        // not checked by default
+        let message = FirehoseMessage::try_from(frame.clone()).unwrap();
+        assert_eq!(commit_operations(message).len(), 2);
+
+        let options = DecodeOptions {
+            check_record_type: true,
+            ..Default::default()
+        };
+        let FirehoseMessage::Commit {
+            operations,
+            decode_errors,
+            ..
+        } = FirehoseMessage::try_from_with_options(frame.clone(), &options).unwrap()
+        else {
+            panic!("expected a commit");
+        };
+        assert_eq!(operations.len(), 1);
+        assert_eq!(
+            operations[0].operation_meta().collection,
+            "app.bsky.feed.like"
+        );
+        assert_eq!(decode_errors.len(), 1);
+        assert_eq!(decode_errors[0].path, "com.example.thing/3kabc");
+
+        let strict = DecodeOptions {
+            mode: DecodeMode::Strict,
+            ..options
+        };
+        match FirehoseMessage::try_from_with_options(frame, &strict) {
+            Err(Error::TypeCollectionMismatch { path, record_type }) => {
+                assert_eq!(path, "com.example.thing/3kabc");
+                assert_eq!(record_type.as_deref(), Some("app.bsky.feed.like"));
+            }
+            _ => panic!("expected a type mismatch"),
+        }
+    }
+
+    #[test]
+    fn missing_rkey() {
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("", "hello")
+            .create_post("3kabc", "hello")
+            .build();
+
+        let FirehoseMessage::Commit {
+            operations,
+            decode_errors,
+            ..
+        } = FirehoseMessage::try_from(frame.clone()).unwrap()
+        else {
+            panic!("expected a commit");
+        };
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].operation_meta().rkey, "3kabc");
+        assert_eq!(decode_errors.len(), 1);
+        assert_eq!(decode_errors[0].path, "app.bsky.feed.post/");
+
+        let strict = DecodeOptions {
+            mode: DecodeMode::Strict,
+            ..Default::default()
+        };
+        assert!(matches!(
+            FirehoseMessage::try_from_with_options(frame, &strict),
+            Err(Error::InvalidOpPath { .. })
+        ));
+    }
+
+    #[test]
+    fn commit_schemas() {
+        let builder = CommitBuilder::new(TEST_DID).create_post("3kabc", "hello");
+        let legacy = builder.build();
+
+        let Ipld::Map(mut body) = builder.body() else {
+            panic!("expected a map");
+        };
+        for field in ["tooBig", "rebase", "blobs"] {
+            body.remove(field);
+        }
+        body.insert("prevData".to_string(), Ipld::Link(cid_for(b"previous")));
+        let sync11 = message_frame("#commit", &Ipld::Map(body));
+
+        for (frame, schema) in [
+            (legacy, CommitSchema::Legacy),
+            (sync11, CommitSchema::Sync11),
+        ] {
+            let Frame::Message(_, message_frame) = &frame else {
+                panic!("expected a message frame");
+            };
+            let probe = serde_ipld_dagcbor::from_slice::<CommitProbe>(&message_frame.body);
+            assert_eq!(probe.unwrap().schema(), schema);
+            let operations = commit_operations(FirehoseMessage::try_from(frame).unwrap());
+            assert_eq!(operations.len(), 1, "{schema:?} commit");
+            assert_eq!(operations[0].operation_meta().rkey, "3kabc");
+        }
+    }
+
+    #[test]
+    fn malformed_record() {
+        let malformed = ipld_map([
+            ("$type", Ipld::String("app.bsky.feed.post".into())),
+            ("text", Ipld::Integer(42)),
+        ]);
+        let frame = CommitBuilder::new(TEST_DID)
+            .create("app.bsky.feed.post", "3kbad", malformed)
+            .create_post("3kgood", "hello")
+            .build();
+
+        let FirehoseMessage::Commit {
+            operations,
+            decode_errors,
+            ..
+        } = FirehoseMessage::try_from(frame.clone()).unwrap()
+        else {
+            panic!("expected a commit");
+        };
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].operation_meta().rkey, "3kgood");
+        assert_eq!(decode_errors.len(), 1);
+        assert_eq!(decode_errors[0].path, "app.bsky.feed.post/3kbad");
+
+        let strict = DecodeOptions {
+            mode: DecodeMode::Strict,
+            ..Default::default()
+        };
+        match FirehoseMessage::try_from_with_options(frame, &strict) {
+            Err(Error::RecordDecodeError {
+                collection,
+                rkey,
+                cid,
+                block,
+                ..
+            }) => {
+                assert_eq!(collection, "app.bsky.feed.post");
+                assert_eq!(rkey, "3kbad");
+                assert_eq!(cid, cid_for(&block).to_string());
+            }
+            _ => panic!("expected a record decode error"),
+        }
+    }
+
+    #[test]
+    fn unknown_action() {
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3ka", "hello")
+            .action("upsert", "app.bsky.feed.post", "3kb", post_record("hi"))
+            .create_post("3kc", "world")
+            .build();
+
+        let FirehoseMessage::Commit {
+            operations,
+            decode_errors,
+            ..
+        } = FirehoseMessage::try_from(frame.clone()).unwrap()
+        else {
+            panic!("expected a commit");
+        };
+        let rkeys = operations
+            .iter()
+            .map(|operation| operation.operation_meta().rkey.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(rkeys, ["3ka", "3kc"]);
+        assert_eq!(decode_errors.len(), 1);
+        assert_eq!(decode_errors[0].action, "upsert");
+        assert_eq!(decode_errors[0].path, "app.bsky.feed.post/3kb");
+
+        let strict = DecodeOptions {
+            mode: DecodeMode::Strict,
+            ..Default::default()
+        };
+        assert!(matches!(
+            FirehoseMessage::try_from_with_options(frame, &strict),
+            Err(Error::UnknownCommitOperation { operation, .. }) if operation == "upsert"
+        ));
+    }
+
+    #[test]
+    fn too_many_operations() {
+        let frame = (0..5)
+            .fold(CommitBuilder::new(TEST_DID), |builder, i| {
+                builder.create_post(&format!("3k{i}"), "hello")
+            })
+            .build();
+
+        let lenient = DecodeOptions {
+            max_ops_per_commit: Some(3),
+            ..Default::default()
+        };
+        let FirehoseMessage::Commit {
+            operations,
+            truncated_ops,
+            ..
+        } = FirehoseMessage::try_from_with_options(frame.clone(), &lenient).unwrap()
+        else {
+            panic!("expected a commit");
+        };
+        let rkeys = operations
+            .iter()
+            .map(|operation| operation.operation_meta().rkey.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(rkeys, vec!["3k0", "3k1", "3k2"]);
+        assert_eq!(truncated_ops, 2);
+
+        let strict = DecodeOptions {
+            mode: DecodeMode::Strict,
+            ..lenient
+        };
+        assert!(matches!(
+            FirehoseMessage::try_from_with_options(frame, &strict),
+            Err(Error::TooManyOperations {
+                count: 5,
+                max_ops_per_commit: 3,
+            })
+        ));
+    }
+
+    #[test]
+    fn normalized_collections() {
+        assert_eq!(normalize_nsid("app.bsky.feed.post"), "app.bsky.feed.post");
+        assert_eq!(
+            normalize_nsid(" App.BSKY.feed.post\n"),
+            "app.bsky.feed.post"
+        );
+        assert_eq!(normalize_nsid("com.Example.fooBar"), "com.example.fooBar");
+
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .delete("App.Bsky.Feed.like", "3kdef")
+            .build();
+        let options = DecodeOptions {
+            normalize_collections: true,
+            ..Default::default()
+        };
+        let operations = commit_operations(
+            FirehoseMessage::try_from_with_options(frame.clone(), &options).unwrap(),
+        );
+        let collections = operations
+            .iter()
+            .map(|operation| operation.operation_meta().collection.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            collections,
+            vec!["app.bsky.feed.post", "app.bsky.feed.like"]
+        );
+
+        let operations = commit_operations(FirehoseMessage::try_from(frame).unwrap());
+        assert_eq!(
+            operations[1].operation_meta().collection,
+            "App.Bsky.Feed.like"
+        );
+    }
+
+    #[test]
+    fn too_big_commits() {
+        let builder = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .delete("app.bsky.feed.post", "3kdef")
+            .raw_blocks(car_bytes(&cid_for(b"commit"), &[]));
+        let strict = DecodeOptions {
+            mode: DecodeMode::Strict,
+            ..Default::default()
+        };
+        assert!(matches!(
+            FirehoseMessage::try_from_with_options(builder.build(), &strict),
+            Err(Error::NoBlockForCommit { .. })
+        ));
+        // the other operations are kept, the missing block is reported
+        let FirehoseMessage::Commit {
+            operations,
+            decode_errors,
+            ..
+        } = FirehoseMessage::try_from(builder.build()).unwrap()
+        else {
+            panic!("expected a commit");
+        };
+        assert_eq!(operations.len(), 1);
+        assert_eq!(decode_errors.len(), 1);
+        assert_eq!(decode_errors[0].path, "app.bsky.feed.post/3kabc");
+
+        let FirehoseMessage::Commit {
+            operations,
+            decode_errors,
+            too_big,
+            ..
+        } = FirehoseMessage::try_from(builder.too_big(true).build()).unwrap()
+        else {
+            panic!("expected a commit");
+        };
+        assert!(too_big);
+        assert!(decode_errors.is_empty());
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].operation_meta().rkey, "3kdef");
+    }
+
+    #[test]
+    fn operation_callback() {
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3ka", "first")
+            .create_post("3kb", "second")
+            .delete("app.bsky.feed.post", "3kc")
+            .build();
+
+        let mut rkeys = Vec::new();
+        let commit = decode_commit_with(&frame, &DecodeOptions::default(), |operation| {
+            rkeys.push(operation.operation_meta().rkey.clone());
+            ControlFlow::Continue(())
+        })
+        .unwrap()
+        .expect("expected a commit");
+        assert_eq!(commit.repo.as_str(), TEST_DID);
+        assert_eq!(rkeys, vec!["3ka", "3kb", "3kc"]);
+
+        let mut calls = 0;
+        decode_commit_with(&frame, &DecodeOptions::default(), |_| {
+            calls += 1;
+            ControlFlow::Break(())
+        })
+        .unwrap();
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn ipld_alongside_typed_records() {
+        let post =
+            crate::test_util::post_with("hello", [("via", Ipld::String("some client".into()))]);
+        let frame = CommitBuilder::new(TEST_DID)
+            .create("app.bsky.feed.post", "3kabc", post)
+            .build();
+
+        let options = DecodeOptions {
+            keep_ipld: true,
+            ..Default::default()
+        };
+        let operations = commit_operations(
+            FirehoseMessage::try_from_with_options(frame.clone(), &options).unwrap(),
+        );
+        assert_eq!(
+            operations[0].record().and_then(crate::Record::post_text),
+            Some("hello")
+        );
+        let Some(Ipld::Map(ipld)) = operations[0].ipld() else {
+            panic!("expected the record as a map");
+        };
+        assert_eq!(ipld["via"], Ipld::String("some client".into()));
+
+        let operations = commit_operations(FirehoseMessage::try_from(frame).unwrap());
+        assert_eq!(operations[0].ipld(), None);
+    }
+
+    #[test]
+    fn collection_filter() {
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .create(
+                "app.bsky.feed.like",
+                "3kdef",
+                like_record("at://did:plc:b/app.bsky.feed.post/3k", &cid_for(b"post")),
+            )
+            .delete("app.bsky.feed.like", "3kghi")
+            .build();
+        let rkeys = |filter: CollectionFilter| {
+            let options = DecodeOptions {
+                collections: Some(filter),
+                ..Default::default()
+            };
+            commit_operations(
+                FirehoseMessage::try_from_with_options(frame.clone(), &options).unwrap(),
+            )
+            .iter()
+            .map(|operation| operation.operation_meta().rkey.clone())
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            rkeys(CollectionFilter::new(["app.bsky.feed.post"])),
+            vec!["3kabc"]
+        );
+        let keep_deletes = CollectionFilter {
+            keep_deletes: true,
+            ..CollectionFilter::new(["app.bsky.feed.post"])
+        };
+        assert_eq!(rkeys(keep_deletes), vec!["3kabc", "3kghi"]);
+
+        // nothing wanted: the CAR file is not read
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .raw_blocks(vec![0xff, 0x00, 0x42])
+            .build();
+        let options = DecodeOptions {
+            collections: Some(CollectionFilter::new(["app.bsky.graph.follow"])),
+            ..Default::default()
+        };
+        let operations =
+            commit_operations(FirehoseMessage::try_from_with_options(frame, &options).unwrap());
+        assert!(operations.is_empty());
+    }
+}
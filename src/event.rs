@@ -0,0 +1,108 @@
+//! Commits flattened into one event per operation.
+use atrium_api::types::string::{Datetime, Did, Tid};
+use serde::Serialize;
+
+use crate::{FirehoseMessage, Operation};
+
+/// A single operation along with the context of the commit carrying it.
+#[derive(Serialize)]
+pub struct OperationEvent {
+    pub did: Did,
+    pub rev: Tid,
+    pub seq: i64,
+    pub time: Datetime,
+    /// Position of the operation in the commit `ops`.
+    pub index: usize,
+    #[serde(flatten)]
+    pub operation: Operation,
+}
+
+/// Operations of a repository grouped over a short window, see
+/// [`FirehoseStreamExt::coalesce_by_did`](crate::stream::FirehoseStreamExt::coalesce_by_did).
+#[derive(Serialize)]
+pub struct OperationBatch {
+    pub did: Did,
+    /// In the order they were received, possibly from several commits.
+    pub events: Vec<OperationEvent>,
+}
+
+impl OperationEvent {
+    pub fn collection(&self) -> &str {
+        &self.operation.operation_meta().collection
+    }
+}
+
+impl FirehoseMessage {
+    /// One event per operation of a commit, nothing for other messages.
+    pub fn into_operation_events(self) -> Vec<OperationEvent> {
+        let FirehoseMessage::Commit {
+            did,
+            seq,
+            rev,
+            time,
+            operations,
+            commit,
+            ..
+        } = self
+        else {
+            return Vec::new();
+        };
+        operations
+            .into_iter()
+            .enumerate()
+            .map(|(position, operation)| {
+                // operations may have been dropped while decoding, look the op up by path
+                let meta = operation.operation_meta();
+                let index = commit
+                    .ops
+                    .iter()
+                    .position(|op| {
+                        op.path.split_once('/')
+                            == Some((meta.collection.as_str(), meta.rkey.as_str()))
+                    })
+                    .unwrap_or(position);
+                OperationEvent {
+                    did: did.clone(),
+                    rev: rev.clone(),
+                    seq,
+                    time: time.clone(),
+                    index,
+                    operation,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        DecodeOptions, FirehoseMessage,
+        test_util::{CommitBuilder, TEST_DID},
+    };
+
+    #[test]
+    fn operation_indices() {
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3ka", &"a".repeat(1000))
+            .create_post("3kb", "second")
+            .delete("app.bsky.feed.like", "3kc")
+            .build();
+        let indices = |options: &DecodeOptions| {
+            FirehoseMessage::try_from_with_options(frame.clone(), options)
+                .unwrap()
+                .into_operation_events()
+                .iter()
+                .map(|event| event.index)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(indices(&DecodeOptions::default()), vec![0, 1, 2]);
+
+        // dropped operations do not shift the others
+        let options = DecodeOptions {
+            max_record_bytes: Some(200),
+            ..Default::default()
+        };
+        assert_eq!(indices(&options), vec![1, 2]);
+    }
+}
@@ -1,5 +1,10 @@
 use ipld_core::ipld::Ipld;
-use std::{convert::Infallible, io::Cursor};
+use std::{
+    collections::{BTreeMap, TryReserveError},
+    convert::Infallible,
+    io::Cursor,
+    time::Instant,
+};
 
 // original definition:
 //```
@@ -24,8 +29,19 @@ pub enum Error {
     InvalidFrameData(Vec<u8>),
     #[error("Invalid frame type: {0:?}")]
     InvalidFrameType(Ipld),
+    #[error("Frame header has no `op`")]
+    MissingOp,
+    #[error("Frame header `op` is not an integer: {0:?}")]
+    NonIntegerOp(Ipld),
+    #[error("Unknown frame header `op`: {0}")]
+    UnknownOp(i128),
     #[error("Failed to decode CBOR (How!?): {0}")]
     CborDecode(#[from] serde_ipld_dagcbor::DecodeError<Infallible>),
+    #[error("Failed to encode CBOR: {0}")]
+    CborEncode(#[from] serde_ipld_dagcbor::EncodeError<TryReserveError>),
+    #[cfg(feature = "websocket")]
+    #[error("Not a binary websocket message: {0}")]
+    NotBinaryMessage(&'static str),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -34,27 +50,27 @@ enum FrameHeader {
     Error,
 }
 
-impl TryFrom<Ipld> for FrameHeader {
+impl TryFrom<&Ipld> for FrameHeader {
     type Error = Error;
 
-    fn try_from(value: Ipld) -> Result<Self, Error> {
-        if let Ipld::Map(map) = &value {
-            if let Some(Ipld::Integer(i)) = map.get("op") {
-                match i {
-                    1 => {
-                        let t = if let Some(Ipld::String(s)) = map.get("t") {
-                            Some(s.clone())
-                        } else {
-                            None
-                        };
-                        return Ok(FrameHeader::Message(t));
-                    }
-                    -1 => return Ok(FrameHeader::Error),
-                    _ => {}
-                }
+    fn try_from(value: &Ipld) -> Result<Self, Error> {
+        let Ipld::Map(map) = value else {
+            return Err(Error::InvalidFrameType(value.clone()));
+        };
+        match map.get("op") {
+            Some(Ipld::Integer(1)) => {
+                let t = if let Some(Ipld::String(s)) = map.get("t") {
+                    Some(s.clone())
+                } else {
+                    None
+                };
+                Ok(FrameHeader::Message(t))
             }
+            Some(Ipld::Integer(-1)) => Ok(FrameHeader::Error),
+            Some(Ipld::Integer(op)) => Err(Error::UnknownOp(*op)),
+            Some(op) => Err(Error::NonIntegerOp(op.clone())),
+            None => Err(Error::MissingOp),
         }
-        Err(Error::InvalidFrameType(value))
     }
 }
 
@@ -64,15 +80,174 @@ pub enum Frame {
     Error(ErrorFrame),
 }
 
+impl Frame {
+    /// Message type discriminator (`#commit`, `#identity`...) of message frames.
+    pub fn message_type(&self) -> Option<&str> {
+        match self {
+            Frame::Message(t, _) => t.as_deref(),
+            Frame::Error(_) => None,
+        }
+    }
+    /// Repository a message frame is about (`repo` of commits, `did` of other messages),
+    /// read without decoding the rest of the body: the fields before it are skipped and the
+    /// ones after it are not read at all.
+    pub fn did(&self) -> Option<String> {
+        let Frame::Message(_, message_frame) = self else {
+            return None;
+        };
+        let mut body = CborReader(&message_frame.body);
+        let (MAJOR_MAP, len) = body.head()? else {
+            return None;
+        };
+        for _ in 0..len {
+            match body.text()? {
+                "repo" | "did" => return body.text().map(str::to_string),
+                _ => body.skip(0)?,
+            }
+        }
+        None
+    }
+    /// Header of message frames, including fields this crate does not know about.
+    pub fn header_fields(&self) -> Option<&BTreeMap<String, Ipld>> {
+        match self {
+            Frame::Message(_, message_frame) => Some(&message_frame.header_fields),
+            Frame::Error(_) => None,
+        }
+    }
+    /// When the frame was received from the websocket, if known.
+    pub fn received_at(&self) -> Option<Instant> {
+        match self {
+            Frame::Message(_, message_frame) => message_frame.received_at,
+            Frame::Error(_) => None,
+        }
+    }
+    /// Encode the frame back as sent by the relay: the header then the body. The `op` and
+    /// `t` of frames built with [`MessageFrame::new`] are added to their header.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let (header, mut body) = match self {
+            Frame::Message(t, message_frame) => {
+                let mut header = message_frame.header_fields.clone();
+                header.entry("op".to_string()).or_insert(Ipld::Integer(1));
+                if let Some(t) = t {
+                    header
+                        .entry("t".to_string())
+                        .or_insert_with(|| Ipld::String(t.clone()));
+                }
+                (Ipld::Map(header), message_frame.body.clone())
+            }
+            Frame::Error(error_frame) => (
+                Ipld::Map(BTreeMap::from([("op".to_string(), Ipld::Integer(-1))])),
+                serde_ipld_dagcbor::to_vec(error_frame)?,
+            ),
+        };
+        let mut bytes = serde_ipld_dagcbor::to_vec(&header)?;
+        bytes.append(&mut body);
+        Ok(bytes)
+    }
+}
+
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+/// Nesting of the values skipped by [`CborReader::skip`], well above that of firehose bodies.
+const MAX_SKIP_DEPTH: usize = 64;
+
+/// Walks DAG-CBOR items without decoding them, `None` on anything malformed.
+struct CborReader<'a>(&'a [u8]);
+
+impl<'a> CborReader<'a> {
+    /// Major type and argument of the next item: the value of integers, the length of
+    /// strings and collections.
+    fn head(&mut self) -> Option<(u8, u64)> {
+        let (&initial, rest) = self.0.split_first()?;
+        let info = initial & 0x1f;
+        let size = match info {
+            0..=23 => 0,
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            // indefinite lengths are not allowed in DAG-CBOR
+            _ => return None,
+        };
+        let argument = rest.get(..size)?;
+        self.0 = &rest[size..];
+        let argument = match size {
+            0 => info as u64,
+            _ => argument.iter().fold(0, |value, &b| (value << 8) | b as u64),
+        };
+        Some((initial >> 5, argument))
+    }
+    fn bytes(&mut self, len: u64) -> Option<&'a [u8]> {
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= self.0.len())?;
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+    fn text(&mut self) -> Option<&'a str> {
+        let (MAJOR_TEXT, len) = self.head()? else {
+            return None;
+        };
+        std::str::from_utf8(self.bytes(len)?).ok()
+    }
+    fn skip(&mut self, depth: usize) -> Option<()> {
+        if depth > MAX_SKIP_DEPTH {
+            return None;
+        }
+        match self.head()? {
+            (MAJOR_BYTES | MAJOR_TEXT, len) => {
+                self.bytes(len)?;
+            }
+            (MAJOR_ARRAY, len) => {
+                for _ in 0..len {
+                    self.skip(depth + 1)?;
+                }
+            }
+            (MAJOR_MAP, len) => {
+                for _ in 0..len {
+                    self.skip(depth + 1)?;
+                    self.skip(depth + 1)?;
+                }
+            }
+            (MAJOR_TAG, _) => self.skip(depth + 1)?,
+            // integers, simple values and floats are held by the head
+            _ => {}
+        }
+        Some(())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MessageFrame {
     pub body: Vec<u8>,
+    /// See [`Frame::received_at`].
+    pub(crate) received_at: Option<Instant>,
+    /// The whole decoded header, `op` and `t` included, see [`Frame::header_fields`].
+    pub(crate) header_fields: BTreeMap<String, Ipld>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl MessageFrame {
+    /// A frame holding `body`, with no reception time nor header fields.
+    pub fn new(body: Vec<u8>) -> Self {
+        MessageFrame {
+            body,
+            received_at: None,
+            header_fields: BTreeMap::new(),
+        }
+    }
+}
+
+/// Body of an error frame, the relay closes the connection after sending it.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct ErrorFrame {
-    // TODO
-    // body: Value,
+    /// Error name, e.g. `FutureCursor` or `ConsumerTooSlow`, `Unknown` when the body has none.
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
 }
 
 impl TryFrom<&[u8]> for Frame {
@@ -89,16 +264,53 @@ impl TryFrom<&[u8]> for Frame {
                 return Err(Error::InvalidFrameData(value.to_vec()));
             }
         };
-        let header = FrameHeader::try_from(serde_ipld_dagcbor::from_slice::<Ipld>(left)?)?;
-        if let FrameHeader::Message(t) = &header {
+        let header_ipld = serde_ipld_dagcbor::from_slice::<Ipld>(left)?;
+        let header = FrameHeader::try_from(&header_ipld)?;
+        if let (FrameHeader::Message(t), Ipld::Map(header_fields)) = (&header, header_ipld) {
             Ok(Frame::Message(
                 t.clone(),
                 MessageFrame {
                     body: right.to_vec(),
+                    received_at: None,
+                    header_fields,
                 },
             ))
         } else {
-            Ok(Frame::Error(ErrorFrame {}))
+            // still an error frame when the relay sends an unexpected body, keeping what it has
+            let body = serde_ipld_dagcbor::from_slice::<Ipld>(right).ok();
+            let field = |name: &str| match &body {
+                Some(Ipld::Map(map)) => match map.get(name) {
+                    Some(Ipld::String(value)) => Some(value.clone()),
+                    _ => None,
+                },
+                _ => None,
+            };
+            Ok(Frame::Error(ErrorFrame {
+                error: field("error").unwrap_or_else(|| "Unknown".to_string()),
+                message: field("message"),
+            }))
+        }
+    }
+}
+
+/// Decode a websocket message received on a connection managed by the caller.
+///
+/// Firehose frames are always sent as binary messages: any other message, control frames
+/// included, is rejected with [`Error::NotBinaryMessage`] and should be handled (or ignored)
+/// by the caller.
+#[cfg(feature = "websocket")]
+impl TryFrom<&tokio_tungstenite::tungstenite::Message> for Frame {
+    type Error = Error;
+
+    fn try_from(message: &tokio_tungstenite::tungstenite::Message) -> Result<Self, Error> {
+        use tokio_tungstenite::tungstenite::Message;
+        match message {
+            Message::Binary(data) => Frame::try_from(data.as_ref()),
+            Message::Text(_) => Err(Error::NotBinaryMessage("text")),
+            Message::Ping(_) => Err(Error::NotBinaryMessage("ping")),
+            Message::Pong(_) => Err(Error::NotBinaryMessage("pong")),
+            Message::Close(_) => Err(Error::NotBinaryMessage("close")),
+            Message::Frame(_) => Err(Error::NotBinaryMessage("frame")),
         }
     }
 }
@@ -125,7 +337,7 @@ mod tests {
         // {"op": 1, "t": "#commit"}
         let data = serialized_data("a2626f700161746723636f6d6d6974");
         let ipld = serde_ipld_dagcbor::from_slice::<Ipld>(&data).expect("failed to deserialize");
-        let result = FrameHeader::try_from(ipld);
+        let result = FrameHeader::try_from(&ipld);
         assert_eq!(
             result.expect("failed to deserialize"),
             FrameHeader::Message(Some(String::from("#commit")))
@@ -137,10 +349,42 @@ mod tests {
         // {"op": -1}
         let data = serialized_data("a1626f7020");
         let ipld = serde_ipld_dagcbor::from_slice::<Ipld>(&data).expect("failed to deserialize");
-        let result = FrameHeader::try_from(ipld);
+        let result = FrameHeader::try_from(&ipld);
         assert_eq!(result.expect("failed to deserialize"), FrameHeader::Error);
     }
 
+    #[test]
+    fn frame_message_type() {
+        // {"op": 1, "t": "#commit"} followed by an empty map body
+        let data = serialized_data("a2626f700161746723636f6d6d6974a0");
+        let frame = Frame::try_from(data.as_slice()).expect("failed to decode frame");
+        assert_eq!(frame.message_type(), Some("#commit"));
+    }
+
+    #[test]
+    fn frame_header_fields() {
+        // {"op": 1, "t": "#commit"} followed by an empty map body
+        let data = serialized_data("a2626f700161746723636f6d6d6974a0");
+        let frame = Frame::try_from(data.as_slice()).expect("failed to decode frame");
+        let header = frame.header_fields().expect("no header");
+        assert_eq!(header.get("op"), Some(&Ipld::Integer(1)));
+        assert_eq!(header.get("t"), Some(&Ipld::String("#commit".into())));
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn frame_from_websocket_message() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let data = serialized_data("a2626f700161746723636f6d6d6974a0");
+        let frame = Frame::try_from(&Message::Binary(data.into())).expect("failed to decode");
+        assert_eq!(frame.message_type(), Some("#commit"));
+        assert!(matches!(
+            Frame::try_from(&Message::Ping(Default::default())),
+            Err(Error::NotBinaryMessage("ping"))
+        ));
+    }
+
     #[test]
     fn deserialize_invalid_frame_header() {
         {
@@ -148,16 +392,115 @@ mod tests {
             let data = serialized_data("a2626f700261746723636f6d6d6974");
             let ipld =
                 serde_ipld_dagcbor::from_slice::<Ipld>(&data).expect("failed to deserialize");
-            let result = FrameHeader::try_from(ipld);
-            assert!(result.is_err());
+            let result = FrameHeader::try_from(&ipld);
+            assert!(matches!(result, Err(Error::UnknownOp(2))));
         }
         {
             // {"op": -2}
             let data = serialized_data("a1626f7021");
             let ipld =
                 serde_ipld_dagcbor::from_slice::<Ipld>(&data).expect("failed to deserialize");
-            let result = FrameHeader::try_from(ipld);
-            assert!(result.is_err());
+            let result = FrameHeader::try_from(&ipld);
+            assert!(matches!(result, Err(Error::UnknownOp(-2))));
         }
     }
+
+    #[test]
+    fn frame_header_without_integer_op() {
+        use crate::test_util::ipld_map;
+
+        let header = ipld_map([("t", Ipld::String("#commit".into()))]);
+        assert!(matches!(
+            FrameHeader::try_from(&header),
+            Err(Error::MissingOp)
+        ));
+        let header = ipld_map([("op", Ipld::String("1".into()))]);
+        assert!(matches!(
+            FrameHeader::try_from(&header),
+            Err(Error::NonIntegerOp(Ipld::String(op))) if op == "1"
+        ));
+    }
+
+    #[test]
+    fn built_frame_to_bytes() {
+        // an empty map body
+        let frame = Frame::Message(Some("#commit".into()), MessageFrame::new(vec![0xa0]));
+        let decoded = Frame::try_from(frame.to_bytes().unwrap().as_slice()).unwrap();
+        assert_eq!(decoded.message_type(), Some("#commit"));
+        let header = decoded.header_fields().unwrap();
+        assert_eq!(header.get("op"), Some(&Ipld::Integer(1)));
+        let Frame::Message(_, message_frame) = decoded else {
+            panic!("expected a message frame");
+        };
+        assert_eq!(message_frame.body, [0xa0]);
+    }
+
+    #[test]
+    fn frame_did() {
+        use crate::test_util::{CommitBuilder, TEST_DID, identity_frame, message_frame};
+
+        let commit = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .build();
+        assert_eq!(commit.did().as_deref(), Some(TEST_DID));
+        let identity = identity_frame("did:plc:someoneelse", 1, None);
+        assert_eq!(identity.did().as_deref(), Some("did:plc:someoneelse"));
+
+        let mut nested = Ipld::Null;
+        for _ in 0..=MAX_SKIP_DEPTH {
+            nested = Ipld::List(vec![nested]);
+        }
+        let body =
+            crate::test_util::ipld_map([("a", nested), ("did", Ipld::String(TEST_DID.into()))]);
+        assert_eq!(message_frame("#identity", &body).did(), None);
+    }
+
+    #[test]
+    fn error_frame_body() {
+        use crate::test_util::ipld_map;
+
+        let error_frame = |body: Ipld| {
+            let mut data =
+                serde_ipld_dagcbor::to_vec(&ipld_map([("op", Ipld::Integer(-1))])).unwrap();
+            data.extend(serde_ipld_dagcbor::to_vec(&body).unwrap());
+            Frame::try_from(data.as_slice()).expect("failed to decode frame")
+        };
+        assert_eq!(
+            error_frame(ipld_map([
+                ("error", Ipld::String("FutureCursor".into())),
+                ("message", Ipld::String("Cursor in the future.".into())),
+            ])),
+            Frame::Error(ErrorFrame {
+                error: "FutureCursor".to_string(),
+                message: Some("Cursor in the future.".to_string()),
+            })
+        );
+        assert_eq!(
+            error_frame(ipld_map([(
+                "error",
+                Ipld::String("ConsumerTooSlow".into())
+            )])),
+            Frame::Error(ErrorFrame {
+                error: "ConsumerTooSlow".to_string(),
+                message: None,
+            })
+        );
+        assert_eq!(
+            error_frame(ipld_map([(
+                "message",
+                Ipld::String("Something went wrong.".into())
+            )])),
+            Frame::Error(ErrorFrame {
+                error: "Unknown".to_string(),
+                message: Some("Something went wrong.".to_string()),
+            })
+        );
+        assert_eq!(
+            error_frame(Ipld::String("not a map".into())),
+            Frame::Error(ErrorFrame {
+                error: "Unknown".to_string(),
+                message: None,
+            })
+        );
+    }
 }
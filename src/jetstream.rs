@@ -0,0 +1,326 @@
+//! Consume [Jetstream](https://github.com/bluesky-social/jetstream), a relay re-encoding the
+//! firehose as JSON.
+//!
+//! Jetstream skips the CAR and DAG-CBOR decoding of the firehose, at the cost of the
+//! cryptographic material: commits cannot be verified and carry a single operation.
+//!
+//! Jetstream events are resumed from their `time_us`, returned along with each message as
+//! [`JetstreamEvent::time_us`]. The [`seq`](FirehoseMessage::seq) of the messages is not a
+//! Jetstream cursor: commits carry no sequence number and reuse `time_us`, identity and
+//! account events keep the sequence number of the relay.
+use atrium_api::{
+    com::atproto::sync::subscribe_repos::{Account, Identity},
+    types::string::{Datetime, Did, Tid},
+};
+use futures::StreamExt;
+use ipld_core::cid::Cid;
+use serde::{Deserialize, de::DeserializeSeed};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, connect_async,
+    tungstenite::{self, Message},
+};
+
+use crate::{FirehoseMessage, Operation, OperationMeta, Record, RecordSeed, commit_envelope};
+
+pub const JETSTREAM_DOMAIN: &str = "jetstream2.us-east.bsky.network";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to connect to websocket: {0}")]
+    Connect(#[from] tungstenite::Error),
+    #[error("Invalid Jetstream event: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid record CID {0}")]
+    InvalidCid(String),
+    #[error("Unknown commit operation `{0}`")]
+    UnknownCommitOperation(String),
+    #[error("Commit operation `{0}` without its record or CID")]
+    IncompleteCommitOperation(String),
+    #[error("Invalid commit: {0}")]
+    InvalidCommit(ipld_core::serde::SerdeError),
+    #[cfg(feature = "compress")]
+    #[error("Failed to decompress event: {0}")]
+    Decompress(std::io::Error),
+}
+
+#[derive(Deserialize)]
+struct Event {
+    did: Did,
+    time_us: i64,
+    #[serde(flatten)]
+    payload: Payload,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Payload {
+    Commit { commit: JetstreamCommit },
+    Identity { identity: Identity },
+    Account { account: Account },
+}
+
+#[derive(Deserialize)]
+struct JetstreamCommit {
+    rev: Tid,
+    operation: String,
+    collection: String,
+    rkey: String,
+    record: Option<serde_json::Value>,
+    cid: Option<String>,
+}
+
+/// A decoded Jetstream event.
+pub struct JetstreamEvent {
+    /// When Jetstream received the event, the cursor to resume from after processing it.
+    pub time_us: i64,
+    pub message: FirehoseMessage,
+}
+
+/// Decode a Jetstream event.
+pub fn decode(json: &str) -> Result<JetstreamEvent, Error> {
+    let event = serde_json::from_str::<Event>(json)?;
+    let message = match event.payload {
+        Payload::Commit { commit } => decode_commit(event.did, event.time_us, commit)?,
+        Payload::Identity { identity } => FirehoseMessage::Identity(identity),
+        Payload::Account { account } => FirehoseMessage::Account(account),
+    };
+    Ok(JetstreamEvent {
+        time_us: event.time_us,
+        message,
+    })
+}
+
+fn decode_commit(
+    did: Did,
+    time_us: i64,
+    jetstream_commit: JetstreamCommit,
+) -> Result<FirehoseMessage, Error> {
+    let JetstreamCommit {
+        rev,
+        operation,
+        collection,
+        rkey,
+        record,
+        cid,
+    } = jetstream_commit;
+    let time = Datetime::new(
+        chrono::DateTime::from_timestamp_micros(time_us)
+            .unwrap_or_default()
+            .fixed_offset(),
+    );
+    let cid = cid
+        .map(|cid| Cid::try_from(cid.as_str()).map_err(|_| Error::InvalidCid(cid)))
+        .transpose()?;
+
+    let operation_meta = OperationMeta { collection, rkey };
+    let operation = match (operation.as_str(), record, cid) {
+        ("delete", _, _) => Operation::Delete(operation_meta),
+        ("create", Some(record), Some(cid)) => Operation::Create {
+            record: decode_record(&operation_meta.collection, record)?,
+            operation_meta,
+            cid: cid.to_string(),
+            ipld: None,
+        },
+        ("update", Some(record), Some(cid)) => Operation::Update {
+            record: decode_record(&operation_meta.collection, record)?,
+            operation_meta,
+            cid: cid.to_string(),
+            ipld: None,
+        },
+        (other @ ("create" | "update"), _, _) => {
+            return Err(Error::IncompleteCommitOperation(other.to_string()));
+        }
+        (other, _, _) => return Err(Error::UnknownCommitOperation(other.to_string())),
+    };
+    let operations = vec![operation];
+    let commit = commit_envelope(&did, time_us, &rev, &time, false, &operations)
+        .map_err(Error::InvalidCommit)?;
+    Ok(FirehoseMessage::Commit {
+        did,
+        seq: time_us,
+        rev,
+        time,
+        operations,
+        decode_errors: Vec::new(),
+        truncated_ops: 0,
+        too_big: false,
+        received_at: None,
+        commit,
+    })
+}
+
+fn decode_record(nsid: &str, record: serde_json::Value) -> Result<Record, serde_json::Error> {
+    RecordSeed(nsid).deserialize(record)
+}
+
+#[cfg(feature = "compress")]
+fn decompress(data: &[u8], dictionary: &[u8]) -> Result<String, Error> {
+    use crate::subscription::{MAX_DECOMPRESSED_BYTES, inflate};
+
+    let json = zstd::stream::read::Decoder::with_dictionary(data, dictionary)
+        .and_then(|decoder| inflate(decoder, MAX_DECOMPRESSED_BYTES))
+        .map_err(Error::Decompress)?;
+    String::from_utf8(json)
+        .map_err(|e| Error::Decompress(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))
+}
+
+pub struct JetstreamSubscription {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    connected: bool,
+    #[cfg(feature = "compress")]
+    dictionary: Option<Vec<u8>>,
+}
+
+impl JetstreamSubscription {
+    pub async fn new(host: &str) -> Result<Self, Error> {
+        Self::new_with_cursor(host, None).await
+    }
+    /// Resume from `cursor`, the [`JetstreamEvent::time_us`] of the last event processed.
+    pub async fn new_with_cursor(host: &str, cursor: Option<i64>) -> Result<Self, Error> {
+        let url = match cursor {
+            Some(cursor) => format!("wss://{host}/subscribe?cursor={cursor}"),
+            None => format!("wss://{host}/subscribe"),
+        };
+        let (stream, res) = connect_async(url).await?;
+        tracing::debug!("Connected to Jetstream: {:?}", res);
+        Ok(JetstreamSubscription {
+            stream,
+            connected: true,
+            #[cfg(feature = "compress")]
+            dictionary: None,
+        })
+    }
+    /// Ask Jetstream to compress events with zstd, using `dictionary`: the dictionary
+    /// published in the Jetstream repository, events cannot be decompressed without it.
+    #[cfg(feature = "compress")]
+    pub async fn new_compressed(
+        host: &str,
+        cursor: Option<i64>,
+        dictionary: Vec<u8>,
+    ) -> Result<Self, Error> {
+        let mut url = format!("wss://{host}/subscribe?compress=true");
+        if let Some(cursor) = cursor {
+            url.push_str(&format!("&cursor={cursor}"));
+        }
+        let (stream, res) = connect_async(url).await?;
+        tracing::debug!("Connected to Jetstream: {:?}", res);
+        Ok(JetstreamSubscription {
+            stream,
+            connected: true,
+            dictionary: Some(dictionary),
+        })
+    }
+    /// Cleanly close the websocket.
+    pub async fn close(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.stream.close(None).await?;
+        Ok(())
+    }
+    pub async fn next(&mut self) -> Option<Result<JetstreamEvent, Error>> {
+        if !self.connected {
+            return None;
+        }
+        loop {
+            match self.stream.next().await {
+                Some(Ok(Message::Text(text))) => return Some(decode(&text)),
+                #[cfg(feature = "compress")]
+                Some(Ok(Message::Binary(data))) if self.dictionary.is_some() => {
+                    let dictionary = self.dictionary.as_deref().unwrap_or_default();
+                    return Some(decompress(&data, dictionary).and_then(|json| decode(&json)));
+                }
+                // pings are answered by tungstenite itself
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                _ => {
+                    self.connected = false;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TEST_DID;
+
+    #[test]
+    fn decode_commits() {
+        let create = format!(
+            r#"{{"did":"{TEST_DID}","time_us":1725911162329308,"kind":"commit","commit":{{
+                "rev":"3l3qo2vutsw2b","operation":"create","collection":"app.bsky.feed.post",
+                "rkey":"3l3qo2vuowo2b","cid":"bafyreidwaivazkwu67xztlmuobx35hs2lnfh3kolmgfmucldvhd3sgzcqi",
+                "record":{{"$type":"app.bsky.feed.post","createdAt":"2024-09-09T19:46:02.102Z",
+                "langs":["en"],"text":"hello jetstream"}}}}}}"#
+        );
+        let event = decode(&create).unwrap();
+        assert_eq!(event.time_us, 1725911162329308);
+        let message = event.message;
+        let FirehoseMessage::Commit {
+            did,
+            time,
+            operations,
+            commit,
+            ..
+        } = &message
+        else {
+            panic!("expected a commit");
+        };
+        assert_eq!(did.as_str(), TEST_DID);
+        assert!(time.as_str().starts_with("2024-09-09T19:46:02"));
+        assert_eq!(commit.ops[0].path, "app.bsky.feed.post/3l3qo2vuowo2b");
+        assert_eq!(
+            operations[0].record().and_then(Record::post_text),
+            Some("hello jetstream")
+        );
+
+        let delete = format!(
+            r#"{{"did":"{TEST_DID}","time_us":1725911162329309,"kind":"commit","commit":{{
+                "rev":"3l3qo2vutsw2c","operation":"delete","collection":"app.bsky.feed.like",
+                "rkey":"3l3qo2vuowo2c"}}}}"#
+        );
+        let FirehoseMessage::Commit { operations, .. } = decode(&delete).unwrap().message else {
+            panic!("expected a commit");
+        };
+        assert!(matches!(&operations[0], Operation::Delete(meta) if meta.rkey == "3l3qo2vuowo2c"));
+
+        let incomplete = format!(
+            r#"{{"did":"{TEST_DID}","time_us":1725911162329310,"kind":"commit","commit":{{
+                "rev":"3l3qo2vutsw2d","operation":"update","collection":"app.bsky.feed.post",
+                "rkey":"3l3qo2vuowo2d"}}}}"#
+        );
+        assert!(matches!(
+            decode(&incomplete),
+            Err(Error::IncompleteCommitOperation(operation)) if operation == "update"
+        ));
+    }
+
+    #[test]
+    fn decode_identity_and_account() {
+        let identity = format!(
+            r#"{{"did":"{TEST_DID}","time_us":1725516665234703,"kind":"identity","identity":{{
+                "did":"{TEST_DID}","handle":"alice.bsky.social","seq":1409752997,
+                "time":"2024-09-05T06:11:04.870Z"}}}}"#
+        );
+        let event = decode(&identity).unwrap();
+        // the cursor is time_us, whatever the sequence number of the relay
+        assert_eq!(event.time_us, 1725516665234703);
+        let FirehoseMessage::Identity(identity) = event.message else {
+            panic!("expected an identity");
+        };
+        assert_eq!(
+            identity.handle.as_ref().map(|handle| handle.as_str()),
+            Some("alice.bsky.social")
+        );
+
+        let account = format!(
+            r#"{{"did":"{TEST_DID}","time_us":1725516665333808,"kind":"account","account":{{
+                "active":true,"did":"{TEST_DID}","seq":1409753013,
+                "time":"2024-09-05T06:11:04.870Z"}}}}"#
+        );
+        let event = decode(&account).unwrap();
+        assert!(matches!(&event.message, FirehoseMessage::Account(account) if account.active));
+        assert_eq!(event.time_us, 1725516665333808);
+    }
+}
@@ -0,0 +1,198 @@
+//! Produce decoded messages to a Kafka topic, keyed by DID.
+//!
+//! Using the DID as record key sends all the events of a repository to the same Kafka
+//! partition, so that consumers see them in firehose order.
+use std::future::Future;
+
+use futures::{Stream, StreamExt};
+use rdkafka::{producer::FutureRecord, util::Timeout};
+use serde::Serialize;
+
+use crate::partition::PartitionKey;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("JSON serialization error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Delivery of record keyed {key} failed: {source}")]
+    Delivery {
+        key: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+/// Sends records to Kafka, implemented by rdkafka's `FutureProducer`.
+pub trait Producer {
+    type Error: std::error::Error + Send + Sync + 'static;
+    /// Send one record and resolve once it has been acknowledged by the broker.
+    fn send(
+        &self,
+        topic: &str,
+        key: &str,
+        payload: &[u8],
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+impl Producer for rdkafka::producer::FutureProducer {
+    type Error = rdkafka::error::KafkaError;
+
+    fn send(
+        &self,
+        topic: &str,
+        key: &str,
+        payload: &[u8],
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let record = FutureRecord::to(topic).key(key).payload(payload);
+        async move {
+            // wait for room in the producer queue instead of failing with `QueueFull`
+            rdkafka::producer::FutureProducer::send(self, record, Timeout::Never)
+                .await
+                .map(|_delivery| ())
+                .map_err(|(error, _message)| error)
+        }
+    }
+}
+
+/// Serialize messages or operation events as JSON and produce them to `topic`.
+///
+/// Each record is awaited until delivered before the next one is sent: a slow broker slows
+/// down the consumption of the stream instead of buffering it, and ordering is kept.
+pub struct KafkaSink<P> {
+    producer: P,
+    topic: String,
+}
+
+impl<P: Producer> KafkaSink<P> {
+    pub fn new(producer: P, topic: impl Into<String>) -> Self {
+        KafkaSink {
+            producer,
+            topic: topic.into(),
+        }
+    }
+
+    pub async fn send<T: Serialize + PartitionKey>(&self, item: &T) -> Result<(), Error> {
+        let payload = serde_json::to_vec(item)?;
+        let key = item.partition_key();
+        self.producer
+            .send(&self.topic, key, &payload)
+            .await
+            .map_err(|e| Error::Delivery {
+                key: key.to_string(),
+                source: Box::new(e),
+            })
+    }
+
+    /// Send every item of `stream`, stopping at the first failure. Returns the number of
+    /// records delivered.
+    pub async fn send_all<T, S>(&self, stream: S) -> Result<u64, Error>
+    where
+        T: Serialize + PartitionKey,
+        S: Stream<Item = T>,
+    {
+        let mut stream = std::pin::pin!(stream);
+        let mut delivered = 0;
+        while let Some(item) = stream.next().await {
+            self.send(&item).await?;
+            delivered += 1;
+        }
+        Ok(delivered)
+    }
+
+    pub fn into_inner(self) -> P {
+        self.producer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::Mutex};
+
+    use super::*;
+    use crate::{
+        FirehoseMessage,
+        stream::FirehoseStreamExt,
+        test_util::{CommitBuilder, TEST_DID, identity_frame},
+    };
+
+    #[derive(Default)]
+    struct MockProducer {
+        records: Mutex<Vec<(String, String, serde_json::Value)>>,
+        failing_key: Option<String>,
+    }
+
+    impl Producer for MockProducer {
+        type Error = io::Error;
+
+        fn send(
+            &self,
+            topic: &str,
+            key: &str,
+            payload: &[u8],
+        ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+            let result = if self.failing_key.as_deref() == Some(key) {
+                Err(io::Error::other("broker unavailable"))
+            } else {
+                self.records.lock().unwrap().push((
+                    topic.to_string(),
+                    key.to_string(),
+                    serde_json::from_slice(payload).unwrap(),
+                ));
+                Ok(())
+            };
+            async { result }
+        }
+    }
+
+    const OTHER_DID: &str = "did:plc:someoneelse";
+
+    fn messages() -> Vec<FirehoseMessage> {
+        vec![
+            CommitBuilder::new(TEST_DID)
+                .create_post("3ka", "first")
+                .create_post("3kb", "second")
+                .build(),
+            identity_frame(OTHER_DID, 2, Some("alice.test")),
+        ]
+        .into_iter()
+        .map(|frame| FirehoseMessage::try_from(frame).unwrap())
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn records_are_keyed_by_did() {
+        let sink = KafkaSink::new(MockProducer::default(), "firehose");
+        let stream = futures::stream::iter(messages()).operation_events();
+        assert_eq!(sink.send_all(stream).await.unwrap(), 2);
+        for message in messages() {
+            sink.send(&message).await.unwrap();
+        }
+
+        let records = sink.into_inner().records.into_inner().unwrap();
+        let keys = records
+            .iter()
+            .map(|(topic, key, _)| {
+                assert_eq!(topic, "firehose");
+                key.as_str()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![TEST_DID, TEST_DID, TEST_DID, OTHER_DID]);
+        assert_eq!(records[1].2["rkey"], "3kb");
+        assert_eq!(records[1].2["record"]["text"], "second");
+        assert_eq!(records[3].2["kind"], "identity");
+        assert_eq!(records[3].2["handle"], "alice.test");
+    }
+
+    #[tokio::test]
+    async fn delivery_errors_stop_the_stream() {
+        let producer = MockProducer {
+            failing_key: Some(OTHER_DID.to_string()),
+            ..Default::default()
+        };
+        let sink = KafkaSink::new(producer, "firehose");
+        match sink.send_all(futures::stream::iter(messages())).await {
+            Err(Error::Delivery { key, .. }) => assert_eq!(key, OTHER_DID),
+            _ => panic!("expected a delivery error"),
+        }
+        assert_eq!(sink.into_inner().records.into_inner().unwrap().len(), 1);
+    }
+}
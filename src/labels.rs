@@ -0,0 +1,145 @@
+//! Consume the `com.atproto.label.subscribeLabels` stream of a labeler.
+//!
+//! Label frames share the envelope of the firehose: the transport is a [`RepoSubscription`]
+//! connected to the labels endpoint, only the bodies differ.
+use std::convert::Infallible;
+
+use atrium_api::com::atproto::label::subscribe_labels::{Info, Labels, NSID};
+use serde_ipld_dagcbor::DecodeError;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+use crate::{
+    frame::{ErrorFrame, Frame},
+    subscription::{self, ConnectOptions, RepoSubscription},
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Subscription(#[from] subscription::Error),
+    #[error("Frame decode error {0}")]
+    DagCborDecodeError(#[from] DecodeError<Infallible>),
+    #[error("Unknown frame type {0}")]
+    UnknownFrameType(String),
+    #[error("No type in frame")]
+    NoTypeInFrame,
+    #[error("Error frame {}", .0.error)]
+    FrameError(ErrorFrame),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LabelMessage {
+    /// Labels created or negated by the labeler.
+    Labels(Labels),
+    /// Informational message from the labeler, e.g. `OutdatedCursor` when resuming from a
+    /// cursor older than its retention.
+    Info(Info),
+}
+
+impl LabelMessage {
+    /// Sequence number to resume from, `None` for info messages.
+    pub fn seq(&self) -> Option<i64> {
+        match self {
+            LabelMessage::Labels(labels) => Some(labels.seq),
+            LabelMessage::Info(_) => None,
+        }
+    }
+}
+
+impl TryFrom<Frame> for LabelMessage {
+    type Error = Error;
+
+    fn try_from(frame: Frame) -> Result<Self, Error> {
+        match frame {
+            Frame::Message(Some(t), message_frame) => match t.as_str() {
+                "#labels" => Ok(LabelMessage::Labels(serde_ipld_dagcbor::from_slice(
+                    &message_frame.body,
+                )?)),
+                "#info" => Ok(LabelMessage::Info(serde_ipld_dagcbor::from_slice(
+                    &message_frame.body,
+                )?)),
+                t => Err(Error::UnknownFrameType(t.to_string())),
+            },
+            Frame::Message(None, _) => Err(Error::NoTypeInFrame),
+            Frame::Error(error_frame) => Err(Error::FrameError(error_frame)),
+        }
+    }
+}
+
+pub struct LabelSubscription {
+    subscription: RepoSubscription,
+}
+
+impl LabelSubscription {
+    pub async fn new(labeler: &str) -> Result<Self, Error> {
+        Self::new_with_options(labeler, &ConnectOptions::default()).await
+    }
+    /// Connect to the labeler host, e.g. `mod.bsky.app`, resuming from the cursor of
+    /// `options` if any.
+    pub async fn new_with_options(labeler: &str, options: &ConnectOptions) -> Result<Self, Error> {
+        let url =
+            subscription::with_cursor(&format!("wss://{labeler}/xrpc/{NSID}"), options.cursor);
+        let request = url
+            .into_client_request()
+            .map_err(subscription::Error::from)?;
+        Ok(LabelSubscription {
+            subscription: RepoSubscription::from_request(request, options).await?,
+        })
+    }
+    /// Next message of the labeler, `None` once the connection is closed.
+    pub async fn next(&mut self) -> Option<Result<LabelMessage, Error>> {
+        let frame = match self.subscription.next().await? {
+            Ok(frame) => frame,
+            Err(e) => return Some(Err(e.into())),
+        };
+        Some(LabelMessage::try_from(frame))
+    }
+    /// Cleanly close the websocket.
+    pub async fn close(&mut self) -> Result<(), Error> {
+        Ok(self.subscription.close().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ipld_core::ipld::Ipld;
+
+    use super::*;
+    use crate::test_util::{TEST_TIME, ipld_map, message_frame};
+
+    #[test]
+    fn labels_are_decoded() {
+        let label = ipld_map([
+            ("src", Ipld::String("did:plc:labeler".into())),
+            (
+                "uri",
+                Ipld::String("at://did:plc:spammer/app.bsky.feed.post/3kabc".into()),
+            ),
+            ("val", Ipld::String("spam".into())),
+            ("cts", Ipld::String(TEST_TIME.into())),
+        ]);
+        let frame = message_frame(
+            "#labels",
+            &ipld_map([
+                ("seq", Ipld::Integer(7)),
+                ("labels", Ipld::List(vec![label])),
+            ]),
+        );
+        let LabelMessage::Labels(labels) = LabelMessage::try_from(frame).unwrap() else {
+            panic!("expected labels");
+        };
+        assert_eq!(labels.seq, 7);
+        assert_eq!(labels.labels.len(), 1);
+        assert_eq!(labels.labels[0].val, "spam");
+        assert_eq!(labels.labels[0].src.as_str(), "did:plc:labeler");
+
+        let frame = message_frame(
+            "#info",
+            &ipld_map([("name", Ipld::String("OutdatedCursor".into()))]),
+        );
+        let LabelMessage::Info(info) = LabelMessage::try_from(frame).unwrap() else {
+            panic!("expected info");
+        };
+        assert_eq!(info.name, "OutdatedCursor");
+    }
+}
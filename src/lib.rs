@@ -1,4 +1,6 @@
-use std::{convert::Infallible, io::Cursor};
+use std::{
+    borrow::Cow, collections::BTreeMap, convert::Infallible, fmt, io::Cursor, ops::ControlFlow,
+};
 
 //re-export atrium_api
 pub use atrium_api;
@@ -12,27 +14,83 @@ use atrium_api::{
     },
 };
 use rs_car_sync::CarDecodeError;
-use serde::Serialize;
+use serde::{
+    Deserialize, Deserializer, Serialize,
+    de::{self, DeserializeSeed, IgnoredAny, MapAccess, Visitor},
+};
 use serde_ipld_dagcbor::DecodeError;
-use tracing::{error, warn};
+use tracing::{error, warn, warn_span};
 
+use car::{Blocks, CarBlocks};
+pub use decode::{CollectionFilter, DecodeMode, DecodeOptions};
+
+pub mod archive;
+pub mod car;
+pub mod cardinality;
+pub mod cursor;
+pub mod decode;
+pub mod event;
 pub mod frame;
+#[cfg(feature = "jetstream")]
+pub mod jetstream;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "websocket")]
+pub mod labels;
+pub mod partition;
+pub mod pretty;
+pub mod rate;
+pub mod record;
+pub mod resolver;
+#[cfg(feature = "json")]
+pub mod sink;
+pub mod store;
+pub mod stream;
 #[cfg(feature = "websocket")]
 pub mod subscription;
+pub mod thread;
+pub mod tid;
+pub mod uri;
+#[cfg(feature = "verify")]
+pub mod verify;
 
 #[cfg(feature = "prometheus")]
 pub mod metrics;
 
-#[derive(Serialize)]
-#[serde(tag = "kind")]
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+
+/// Messages serialized by this crate deserialize back, e.g. on the consumer side of a Kafka
+/// topic. The commit envelope of a deserialized commit is rebuilt from the serialized
+/// fields, without blocks nor commit CID (see [`Commit`](FirehoseMessage::Commit)).
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", try_from = "SerializedFirehoseMessage")]
 #[non_exhaustive]
 pub enum FirehoseMessage {
     #[serde(rename = "commit")]
     Commit {
         did: Did,
+        seq: i64,
         rev: Tid,
         time: Datetime,
         operations: Vec<Operation>,
+        /// Operations dropped in [`DecodeMode::Lenient`]: their record is missing, too large or
+        /// failed to decode, their path has no rkey or their action is unknown.
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        decode_errors: Vec<OpError>,
+        /// Operations left out in [`DecodeMode::Lenient`] because the commit had more than
+        /// [`DecodeOptions::max_ops_per_commit`] of them.
+        #[serde(skip_serializing_if = "is_zero")]
+        truncated_ops: usize,
+        /// Set by the relay when the commit was too big to carry all its blocks: operations
+        /// whose record block is missing are then expected, and dropped without being reported
+        /// as [`Error::NoBlockForCommit`].
+        #[serde(skip_serializing_if = "is_false")]
+        too_big: bool,
+        /// Wall-clock time at which the frame was received, when
+        /// [`DecodeOptions::receipt_time`] is set and the frame comes from a subscription.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        received_at: Option<Datetime>,
         #[serde(skip)]
         commit: Commit,
     },
@@ -40,6 +98,16 @@ pub enum FirehoseMessage {
     Identity(Identity),
     #[serde(rename = "account")]
     Account(Account),
+    /// A commit left undecoded because of the [`DecodeOptions`] filters.
+    #[serde(rename = "skipped")]
+    Skipped { did: Did, seq: i64 },
+    /// Informational message from the relay, e.g. `OutdatedCursor` when resuming from a
+    /// cursor older than the events it retains.
+    #[serde(rename = "info")]
+    Info {
+        name: String,
+        message: Option<String>,
+    },
 }
 
 impl FirehoseMessage {
@@ -48,15 +116,233 @@ impl FirehoseMessage {
             FirehoseMessage::Commit { .. } => FirehoseMessageKind::Commit,
             FirehoseMessage::Identity(_object) => FirehoseMessageKind::Identity,
             FirehoseMessage::Account(_object) => FirehoseMessageKind::Account,
+            FirehoseMessage::Skipped { .. } => FirehoseMessageKind::Skipped,
+            FirehoseMessage::Info { .. } => FirehoseMessageKind::Info,
+        }
+    }
+    /// Repository the message is about, `None` for relay messages.
+    pub fn did(&self) -> Option<&Did> {
+        match self {
+            FirehoseMessage::Commit { did, .. } => Some(did),
+            FirehoseMessage::Identity(identity) => Some(&identity.did),
+            FirehoseMessage::Account(account) => Some(&account.did),
+            FirehoseMessage::Skipped { did, .. } => Some(did),
+            FirehoseMessage::Info { .. } => None,
+        }
+    }
+    /// Sequence number of the message in the relay stream, to be used as a cursor.
+    pub fn seq(&self) -> Option<i64> {
+        match self {
+            FirehoseMessage::Commit { seq, .. } => Some(*seq),
+            FirehoseMessage::Identity(identity) => Some(identity.seq),
+            FirehoseMessage::Account(account) => Some(account.seq),
+            FirehoseMessage::Skipped { seq, .. } => Some(*seq),
+            FirehoseMessage::Info { .. } => None,
+        }
+    }
+    /// Revision of a commit parsed as a [`Tid`](tid::Tid): its timestamp tells when the PDS
+    /// wrote the commit, a cross-check for the commit `time` which some PDSs set
+    /// inconsistently. `None` for other messages or a malformed revision.
+    pub fn rev(&self) -> Option<tid::Tid> {
+        match self {
+            FirehoseMessage::Commit { rev, .. } => tid::Tid::try_from(rev).ok(),
+            _ => None,
+        }
+    }
+    /// Fields of identity and account events this crate has no typed field for (e.g. the PDS
+    /// or `alsoKnownAs` of an identity), as raw IPLD.
+    pub fn extra_fields(&self) -> Option<&BTreeMap<String, ipld_core::ipld::Ipld>> {
+        let extra_data = match self {
+            FirehoseMessage::Identity(identity) => &identity.extra_data,
+            FirehoseMessage::Account(account) => &account.extra_data,
+            _ => return None,
+        };
+        match extra_data {
+            ipld_core::ipld::Ipld::Map(fields) => Some(fields),
+            _ => None,
+        }
+    }
+    /// Delay between the commit `time` set by the PDS and the receipt of the frame, see
+    /// [`DecodeOptions::receipt_time`]. Tells clock skew and relay lag apart from the
+    /// processing latency.
+    pub fn receipt_delay(&self) -> Option<chrono::TimeDelta> {
+        match self {
+            FirehoseMessage::Commit {
+                time,
+                received_at: Some(received_at),
+                ..
+            } => Some(*received_at.as_ref() - *time.as_ref()),
+            _ => None,
+        }
+    }
+    /// Operations of a commit as a JSON array, each along with the `uri` of its record, for
+    /// consumers forwarding operations without the commit envelope. `Ok(None)` for other
+    /// messages; a record JSON cannot represent is returned as the serialization error.
+    #[cfg(feature = "json")]
+    pub fn operations_json(&self) -> Result<Option<String>, serde_json::Error> {
+        #[derive(Serialize)]
+        struct OperationWithUri<'a> {
+            uri: String,
+            #[serde(flatten)]
+            operation: &'a Operation,
         }
+        let FirehoseMessage::Commit {
+            did, operations, ..
+        } = self
+        else {
+            return Ok(None);
+        };
+        let operations = operations
+            .iter()
+            .map(|operation| OperationWithUri {
+                uri: operation.operation_meta().uri(did),
+                operation,
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_string(&operations).map(Some)
     }
 }
+/// Deserializable form of [`FirehoseMessage`], its commit envelope not being serialized.
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum SerializedFirehoseMessage {
+    Commit {
+        did: Did,
+        seq: i64,
+        rev: Tid,
+        time: Datetime,
+        operations: Vec<Operation>,
+        #[serde(default)]
+        decode_errors: Vec<OpError>,
+        #[serde(default)]
+        truncated_ops: usize,
+        #[serde(default)]
+        too_big: bool,
+        #[serde(default)]
+        received_at: Option<Datetime>,
+    },
+    Identity(Identity),
+    Account(Account),
+    Skipped {
+        did: Did,
+        seq: i64,
+    },
+    Info {
+        name: String,
+        message: Option<String>,
+    },
+}
+impl TryFrom<SerializedFirehoseMessage> for FirehoseMessage {
+    type Error = ipld_core::serde::SerdeError;
+
+    fn try_from(message: SerializedFirehoseMessage) -> Result<Self, Self::Error> {
+        Ok(match message {
+            SerializedFirehoseMessage::Commit {
+                did,
+                seq,
+                rev,
+                time,
+                operations,
+                decode_errors,
+                truncated_ops,
+                too_big,
+                received_at,
+            } => FirehoseMessage::Commit {
+                commit: commit_envelope(&did, seq, &rev, &time, too_big, &operations)?,
+                did,
+                seq,
+                rev,
+                time,
+                operations,
+                decode_errors,
+                truncated_ops,
+                too_big,
+                received_at,
+            },
+            SerializedFirehoseMessage::Identity(identity) => FirehoseMessage::Identity(identity),
+            SerializedFirehoseMessage::Account(account) => FirehoseMessage::Account(account),
+            SerializedFirehoseMessage::Skipped { did, seq } => {
+                FirehoseMessage::Skipped { did, seq }
+            }
+            SerializedFirehoseMessage::Info { name, message } => {
+                FirehoseMessage::Info { name, message }
+            }
+        })
+    }
+}
+/// The firehose commit envelope of a commit which did not come from the firehose, without
+/// blocks nor commit CID.
+pub(crate) fn commit_envelope(
+    did: &Did,
+    seq: i64,
+    rev: &Tid,
+    time: &Datetime,
+    too_big: bool,
+    operations: &[Operation],
+) -> Result<Commit, ipld_core::serde::SerdeError> {
+    use ipld_core::{cid::Cid, ipld::Ipld};
+
+    fn map<const N: usize>(entries: [(&str, Ipld); N]) -> Ipld {
+        Ipld::Map(entries.map(|(k, v)| (k.to_string(), v)).into())
+    }
+    let placeholder_cid = Cid::new_v1(0x71, multihash::Multihash::<64>::wrap(0x00, &[]).unwrap());
+    let ops = operations
+        .iter()
+        .map(|operation| {
+            let cid = match operation {
+                Operation::Create { cid, .. } | Operation::Update { cid, .. } => {
+                    Cid::try_from(cid.as_str()).ok()
+                }
+                Operation::Delete(_) => None,
+            };
+            let OperationMeta { collection, rkey } = operation.operation_meta();
+            map([
+                (
+                    "action",
+                    Ipld::String(operation.kind().as_str().to_string()),
+                ),
+                ("path", Ipld::String(format!("{collection}/{rkey}"))),
+                ("cid", cid.map(Ipld::Link).unwrap_or(Ipld::Null)),
+            ])
+        })
+        .collect();
+    ipld_core::serde::from_ipld(map([
+        ("seq", Ipld::Integer(seq.into())),
+        ("rebase", Ipld::Bool(false)),
+        ("tooBig", Ipld::Bool(too_big)),
+        ("repo", Ipld::String(did.as_str().to_string())),
+        ("commit", Ipld::Link(placeholder_cid)),
+        ("rev", Ipld::String(rev.as_str().to_string())),
+        ("since", Ipld::Null),
+        ("blocks", Ipld::Bytes(Vec::new())),
+        ("ops", Ipld::List(ops)),
+        ("blobs", Ipld::List(Vec::new())),
+        ("time", Ipld::String(time.as_str().to_string())),
+    ]))
+}
+fn is_zero(count: &usize) -> bool {
+    *count == 0
+}
+fn is_false(flag: &bool) -> bool {
+    !*flag
+}
+/// An operation dropped while decoding: its record is missing, too large or could not be
+/// decoded, or its path has no rkey.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OpError {
+    pub action: String,
+    pub path: String,
+    pub error: String,
+}
+
 #[derive(Serialize, Debug, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum FirehoseMessageKind {
     Commit,
     Identity,
     Account,
+    Skipped,
+    Info,
 }
 impl FirehoseMessageKind {
     pub fn as_str(&self) -> &str {
@@ -64,6 +350,8 @@ impl FirehoseMessageKind {
             FirehoseMessageKind::Commit => "commit",
             FirehoseMessageKind::Identity => "identity",
             FirehoseMessageKind::Account => "account",
+            FirehoseMessageKind::Skipped => "skipped",
+            FirehoseMessageKind::Info => "info",
         }
     }
 }
@@ -82,6 +370,68 @@ pub enum Record {
     Profile(atrium_api::types::Object<bsky::actor::profile::RecordData>),
     List(atrium_api::types::Object<bsky::graph::list::RecordData>),
     Starterpack(atrium_api::types::Object<bsky::graph::starterpack::RecordData>),
+    Threadgate(atrium_api::types::Object<bsky::feed::threadgate::RecordData>),
+    Postgate(atrium_api::types::Object<bsky::feed::postgate::RecordData>),
+    LabelerService(atrium_api::types::Object<bsky::labeler::service::RecordData>),
+}
+impl Record {
+    pub fn kind(&self) -> RecordKind {
+        match self {
+            Record::Unknown(_) => RecordKind::Unknown,
+            Record::Post(_) => RecordKind::Post,
+            Record::Follow(_) => RecordKind::Follow,
+            Record::Block(_) => RecordKind::Block,
+            Record::Repost(_) => RecordKind::Repost,
+            Record::Like(_) => RecordKind::Like,
+            Record::Listitem(_) => RecordKind::Listitem,
+            Record::Generator(_) => RecordKind::Generator,
+            Record::Profile(_) => RecordKind::Profile,
+            Record::List(_) => RecordKind::List,
+            Record::Starterpack(_) => RecordKind::Starterpack,
+            Record::Threadgate(_) => RecordKind::Threadgate,
+            Record::Postgate(_) => RecordKind::Postgate,
+            Record::LabelerService(_) => RecordKind::LabelerService,
+        }
+    }
+    /// Collection of a typed record, `None` for [`Record::Unknown`].
+    pub fn nsid(&self) -> Option<&'static str> {
+        self.kind().nsid()
+    }
+}
+/// Deserialize a [`Record`] of the collection `nsid`: the variants of a serialized record are
+/// told apart by the collection only.
+pub(crate) struct RecordSeed<'a>(pub &'a str);
+impl<'de> DeserializeSeed<'de> for RecordSeed<'_> {
+    type Value = Record;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Record, D::Error> {
+        Ok(match self.0 {
+            bsky::feed::Post::NSID => Record::Post(Deserialize::deserialize(deserializer)?),
+            bsky::graph::Follow::NSID => Record::Follow(Deserialize::deserialize(deserializer)?),
+            bsky::graph::Block::NSID => Record::Block(Deserialize::deserialize(deserializer)?),
+            bsky::feed::Repost::NSID => Record::Repost(Deserialize::deserialize(deserializer)?),
+            bsky::feed::Like::NSID => Record::Like(Deserialize::deserialize(deserializer)?),
+            bsky::graph::Listitem::NSID => {
+                Record::Listitem(Deserialize::deserialize(deserializer)?)
+            }
+            bsky::feed::Generator::NSID => {
+                Record::Generator(Deserialize::deserialize(deserializer)?)
+            }
+            bsky::actor::Profile::NSID => Record::Profile(Deserialize::deserialize(deserializer)?),
+            bsky::graph::List::NSID => Record::List(Deserialize::deserialize(deserializer)?),
+            bsky::graph::Starterpack::NSID => {
+                Record::Starterpack(Deserialize::deserialize(deserializer)?)
+            }
+            bsky::feed::Threadgate::NSID => {
+                Record::Threadgate(Deserialize::deserialize(deserializer)?)
+            }
+            bsky::feed::Postgate::NSID => Record::Postgate(Deserialize::deserialize(deserializer)?),
+            bsky::labeler::Service::NSID => {
+                Record::LabelerService(Deserialize::deserialize(deserializer)?)
+            }
+            _ => Record::Unknown(Deserialize::deserialize(deserializer)?),
+        })
+    }
 }
 
 #[derive(Serialize)]
@@ -92,12 +442,19 @@ pub enum Operation {
         operation_meta: OperationMeta,
         record: Record,
         cid: String,
+        /// The record decoded as [`Ipld`](ipld_core::ipld::Ipld) too, fields the typed
+        /// `record` does not model included. Only set with [`DecodeOptions::keep_ipld`].
+        #[serde(skip)]
+        ipld: Option<ipld_core::ipld::Ipld>,
     },
     Update {
         #[serde(flatten)]
         operation_meta: OperationMeta,
         record: Record,
         cid: String,
+        /// See [`Operation::Create::ipld`](Operation::Create).
+        #[serde(skip)]
+        ipld: Option<ipld_core::ipld::Ipld>,
     },
     Delete(OperationMeta),
 }
@@ -115,15 +472,237 @@ impl Operation {
                 operation_meta,
                 record: _,
                 cid: _,
+                ipld: _,
             } => operation_meta,
             Operation::Update {
                 operation_meta,
                 record: _,
                 cid: _,
+                ipld: _,
             } => operation_meta,
             Operation::Delete(operation_meta) => operation_meta,
         }
     }
+    pub fn record_path(&self, did: &Did) -> String {
+        self.operation_meta().record_path(did)
+    }
+    /// Whether the operation changes the social graph (follows, blocks, list memberships).
+    pub fn is_graph_event(&self) -> bool {
+        GRAPH_COLLECTIONS.contains(&self.operation_meta().collection.as_str())
+    }
+    /// See [`DecodeOptions::keep_ipld`].
+    pub fn ipld(&self) -> Option<&ipld_core::ipld::Ipld> {
+        match self {
+            Operation::Create { ipld, .. } | Operation::Update { ipld, .. } => ipld.as_ref(),
+            Operation::Delete(_) => None,
+        }
+    }
+    pub fn record(&self) -> Option<&Record> {
+        match self {
+            Operation::Create { record, .. } | Operation::Update { record, .. } => Some(record),
+            Operation::Delete(_) => None,
+        }
+    }
+    /// The deletion along with the kind of the deleted record, `None` for other operations.
+    pub fn delete_event(&self) -> Option<DeleteEvent> {
+        match self {
+            Operation::Delete(operation_meta) => Some(DeleteEvent {
+                kind: RecordKind::of_collection(&operation_meta.collection),
+                operation_meta: operation_meta.clone(),
+            }),
+            _ => None,
+        }
+    }
+    /// Both timestamps available for this operation, `commit_time` being the `time` of the
+    /// commit carrying it.
+    pub fn timestamps(&self, commit_time: &Datetime) -> Timestamps {
+        Timestamps {
+            record_created_at: self.record().and_then(Record::created_at),
+            commit_time: commit_time.clone(),
+        }
+    }
+}
+/// The record is decoded according to the collection, which must come first as it does in
+/// serialized operations. `ipld` is not serialized and left unset.
+impl<'de> Deserialize<'de> for Operation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(OperationVisitor)
+    }
+}
+struct OperationVisitor;
+impl<'de> Visitor<'de> for OperationVisitor {
+    type Value = Operation;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an operation")
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Operation, A::Error> {
+        let mut operation: Option<String> = None;
+        let mut collection: Option<String> = None;
+        let mut rkey = None;
+        let mut record = None;
+        let mut cid = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "operation" => operation = Some(map.next_value()?),
+                "collection" => collection = Some(map.next_value()?),
+                "rkey" => rkey = Some(map.next_value()?),
+                "record" => {
+                    let nsid = collection
+                        .as_deref()
+                        .ok_or_else(|| de::Error::custom("record before its collection"))?;
+                    record = Some(map.next_value_seed(RecordSeed(nsid))?);
+                }
+                "cid" => cid = Some(map.next_value()?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        let operation_meta = OperationMeta {
+            collection: collection.ok_or_else(|| de::Error::missing_field("collection"))?,
+            rkey: rkey.ok_or_else(|| de::Error::missing_field("rkey"))?,
+        };
+        let operation = operation.ok_or_else(|| de::Error::missing_field("operation"))?;
+        if operation == "delete" {
+            return Ok(Operation::Delete(operation_meta));
+        }
+        let record = record.ok_or_else(|| de::Error::missing_field("record"))?;
+        let cid = cid.ok_or_else(|| de::Error::missing_field("cid"))?;
+        match operation.as_str() {
+            "create" => Ok(Operation::Create {
+                operation_meta,
+                record,
+                cid,
+                ipld: None,
+            }),
+            "update" => Ok(Operation::Update {
+                operation_meta,
+                record,
+                cid,
+                ipld: None,
+            }),
+            other => Err(de::Error::unknown_variant(
+                other,
+                &["create", "update", "delete"],
+            )),
+        }
+    }
+}
+
+/// Timestamps of an operation.
+///
+/// `record_created_at` is the `createdAt` field of the record: it is set by the client and can
+/// hold any value (backdated posts, clock skew...). `commit_time` is the `time` of the commit as
+/// emitted by the PDS and relayed by the firehose: prefer it when ordering or trusting events.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Timestamps {
+    pub record_created_at: Option<Datetime>,
+    pub commit_time: Datetime,
+}
+/// Collections whose records are edges of the social graph.
+pub const GRAPH_COLLECTIONS: [&str; 4] = [
+    bsky::graph::Follow::NSID,
+    bsky::graph::Block::NSID,
+    bsky::graph::Listitem::NSID,
+    bsky::graph::Listblock::NSID,
+];
+
+/// Lexicon snapshot the typed [`Record`] variants are decoded against.
+#[derive(Debug, Clone, Copy)]
+pub struct LexiconInfo {
+    /// Version requirement of the `atrium-api` crate providing the lexicon types.
+    pub atrium_api: &'static str,
+    /// Bumped each time the set of typed collections changes.
+    pub revision: u32,
+    /// Collections decoded into a typed [`Record`], others end up in [`Record::Unknown`].
+    pub nsids: &'static [&'static str],
+}
+
+pub const LEXICON_INFO: LexiconInfo = LexiconInfo {
+    atrium_api: "0.25",
+    revision: 2,
+    nsids: &[
+        bsky::feed::Post::NSID,
+        bsky::graph::Follow::NSID,
+        bsky::graph::Block::NSID,
+        bsky::feed::Repost::NSID,
+        bsky::feed::Like::NSID,
+        bsky::graph::Listitem::NSID,
+        bsky::feed::Generator::NSID,
+        bsky::actor::Profile::NSID,
+        bsky::graph::List::NSID,
+        bsky::graph::Starterpack::NSID,
+        bsky::feed::Threadgate::NSID,
+        bsky::feed::Postgate::NSID,
+        bsky::labeler::Service::NSID,
+    ],
+};
+/// Kind of a record, as told by its collection.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordKind {
+    Post,
+    Follow,
+    Block,
+    Repost,
+    Like,
+    Listitem,
+    Generator,
+    Profile,
+    List,
+    Starterpack,
+    Threadgate,
+    Postgate,
+    LabelerService,
+    Unknown,
+}
+impl RecordKind {
+    pub fn of_collection(collection: &str) -> Self {
+        match collection {
+            bsky::feed::Post::NSID => RecordKind::Post,
+            bsky::graph::Follow::NSID => RecordKind::Follow,
+            bsky::graph::Block::NSID => RecordKind::Block,
+            bsky::feed::Repost::NSID => RecordKind::Repost,
+            bsky::feed::Like::NSID => RecordKind::Like,
+            bsky::graph::Listitem::NSID => RecordKind::Listitem,
+            bsky::feed::Generator::NSID => RecordKind::Generator,
+            bsky::actor::Profile::NSID => RecordKind::Profile,
+            bsky::graph::List::NSID => RecordKind::List,
+            bsky::graph::Starterpack::NSID => RecordKind::Starterpack,
+            bsky::feed::Threadgate::NSID => RecordKind::Threadgate,
+            bsky::feed::Postgate::NSID => RecordKind::Postgate,
+            bsky::labeler::Service::NSID => RecordKind::LabelerService,
+            _ => RecordKind::Unknown,
+        }
+    }
+    /// Collection of the records of this kind, `None` for [`RecordKind::Unknown`].
+    pub fn nsid(&self) -> Option<&'static str> {
+        Some(match self {
+            RecordKind::Post => bsky::feed::Post::NSID,
+            RecordKind::Follow => bsky::graph::Follow::NSID,
+            RecordKind::Block => bsky::graph::Block::NSID,
+            RecordKind::Repost => bsky::feed::Repost::NSID,
+            RecordKind::Like => bsky::feed::Like::NSID,
+            RecordKind::Listitem => bsky::graph::Listitem::NSID,
+            RecordKind::Generator => bsky::feed::Generator::NSID,
+            RecordKind::Profile => bsky::actor::Profile::NSID,
+            RecordKind::List => bsky::graph::List::NSID,
+            RecordKind::Starterpack => bsky::graph::Starterpack::NSID,
+            RecordKind::Threadgate => bsky::feed::Threadgate::NSID,
+            RecordKind::Postgate => bsky::feed::Postgate::NSID,
+            RecordKind::LabelerService => bsky::labeler::Service::NSID,
+            RecordKind::Unknown => return None,
+        })
+    }
+}
+/// A deletion classified by the kind of the deleted record, which is otherwise only known
+/// from the record itself when it was created.
+#[derive(Serialize, Debug, Clone)]
+pub struct DeleteEvent {
+    pub kind: RecordKind,
+    #[serde(flatten)]
+    pub operation_meta: OperationMeta,
 }
 #[derive(Debug, Clone, Copy)]
 pub enum OperationKind {
@@ -140,23 +719,90 @@ impl OperationKind {
         }
     }
 }
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OperationMeta {
     pub collection: String,
     pub rkey: String,
 }
+impl OperationMeta {
+    /// `{did}/{collection}/{rkey}` key of the record, suitable for key-value stores or
+    /// filesystem layouts mirroring the repository (this is not the `at://` URI).
+    ///
+    /// Characters outside of the record key charset are percent-encoded, so that a malformed
+    /// rkey can neither add path segments nor be `.`/`..`.
+    pub fn record_path(&self, did: &Did) -> String {
+        let rkey = if self.rkey == "." || self.rkey == ".." {
+            self.rkey.replace('.', "%2E")
+        } else {
+            self.rkey
+                .bytes()
+                .map(|b| {
+                    if is_rkey_byte(b) {
+                        (b as char).to_string()
+                    } else {
+                        format!("%{b:02X}")
+                    }
+                })
+                .collect()
+        };
+        format!("{}/{}/{rkey}", did.as_str(), self.collection)
+    }
+    /// `at://{did}/{collection}/{rkey}` URI of the record.
+    pub fn uri(&self, did: &Did) -> String {
+        format!("at://{}/{}/{}", did.as_str(), self.collection, self.rkey)
+    }
+    /// Which of the record key formats the rkey follows.
+    pub fn rkey_kind(&self) -> RkeyKind {
+        let rkey = self.rkey.as_str();
+        if rkey.is_empty()
+            || rkey.len() > 512
+            || rkey == "."
+            || rkey == ".."
+            || !rkey.bytes().all(is_rkey_byte)
+        {
+            RkeyKind::Invalid
+        } else if rkey == "self" {
+            RkeyKind::Literal
+        } else if rkey.parse::<tid::Tid>().is_ok() {
+            RkeyKind::Tid
+        } else {
+            RkeyKind::Any
+        }
+    }
+}
+/// Format of a record key, see [`OperationMeta::rkey_kind`].
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RkeyKind {
+    /// A [`Tid`](tid::Tid), the key of most records.
+    Tid,
+    /// `self`, the key of singleton records such as profiles.
+    Literal,
+    /// Any other valid record key, e.g. the URL-like keys of feed generators.
+    Any,
+    /// Outside of the record key syntax: 1 to 512 characters among `A-Za-z0-9.-_:~`, and
+    /// neither `.` nor `..`.
+    Invalid,
+}
+fn is_rkey_byte(b: u8) -> bool {
+    matches!(b, b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b':' | b'~')
+}
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Unknown frame type {0}")]
     UnknownFrameType(String, crate::frame::MessageFrame),
     #[error("No type in frame")]
     NoTypeInFrame(crate::frame::MessageFrame),
-    #[error("Error Frame")]
+    #[error("Error frame {}", .0.error)]
     FrameError(crate::frame::ErrorFrame),
     #[error("Frame decode error {0}")]
     DagCborDecodeError(DecodeError<Infallible>, crate::frame::MessageFrame),
+    #[error("Frame decode error {0}")]
+    IpldDecodeError(ipld_core::serde::SerdeError, crate::frame::MessageFrame),
     #[error("CAR decode error {0}")]
     CarDecodeError(CarDecodeError, Commit),
+    #[error("CAR decode error {0}")]
+    InvalidCar(car::Error, Commit),
     #[error("No block found for commit {did:?} {0} {operation} {path}", rev.as_str())]
     NoBlockForCommit {
         operation: String,
@@ -171,151 +817,169 @@ pub enum Error {
         record: Record,
         cid: String,
     },
+    #[error("Record {path} is {size} bytes, more than the allowed {max_record_bytes}")]
+    RecordTooLarge {
+        path: String,
+        size: usize,
+        max_record_bytes: usize,
+    },
+    #[error("Commit has {count} operations, more than the allowed {max_ops_per_commit}")]
+    TooManyOperations {
+        count: usize,
+        max_ops_per_commit: usize,
+    },
+    #[error("Operation path {path} is not `collection/rkey`")]
+    InvalidOpPath { path: String },
+    /// See [`DecodeOptions::check_record_type`].
+    #[error("Record {path} has $type {record_type:?}, not its collection")]
+    TypeCollectionMismatch {
+        path: String,
+        record_type: Option<String>,
+    },
+    /// The record of an operation failed to decode, `block` holds its raw DAG-CBOR bytes.
+    #[error("Record {collection}/{rkey} ({cid}) decode error {source}")]
+    RecordDecodeError {
+        collection: String,
+        rkey: String,
+        cid: String,
+        block: Vec<u8>,
+        source: DecodeError<Infallible>,
+    },
+    #[error("Expected an unknown record, got a {0:?} record")]
+    NotAnUnknownRecord(RecordKind),
+    #[error("Unknown record decode error {0}")]
+    UnknownRecordDecodeError(ipld_core::serde::SerdeError),
+    #[cfg(feature = "verify")]
+    #[error("Unable to verify the commit signature: {0}")]
+    SignatureVerificationFailed(String),
 }
 
 impl TryFrom<crate::frame::Frame> for FirehoseMessage {
     type Error = Error;
 
     fn try_from(frame: crate::frame::Frame) -> Result<Self, Self::Error> {
-        match frame {
-            crate::frame::Frame::Message(Some(t), message_frame) => match t.as_str() {
-                "#commit" => {
-                    let commit =
-                        serde_ipld_dagcbor::from_slice::<Commit>(message_frame.body.as_slice())
-                            .map_err(|e| Error::DagCborDecodeError(e, message_frame.clone()))?;
+        FirehoseMessage::try_from_with_options(frame, &DecodeOptions::default())
+    }
+}
 
-                    let mut block_reader = Cursor::new(&commit.blocks);
-                    let (blocks, _) = rs_car_sync::car_read_all(&mut block_reader, true)
-                        .map_err(|e| Error::CarDecodeError(e, commit.clone()))?;
+impl FirehoseMessage {
+    pub fn try_from_with_options(
+        frame: crate::frame::Frame,
+        options: &DecodeOptions,
+    ) -> Result<Self, Error> {
+        Ok(FirehoseMessage::decode_counted(frame, options, false)?.0)
+    }
 
-                    let mut operations = Vec::new();
+    /// [`try_from_with_options`](Self::try_from_with_options) also returning every block of
+    /// the CAR file of a commit along with its root, as read to decode the operations. `None`
+    /// for other messages and for commits skipped by the [`DecodeOptions`] filters.
+    pub fn try_from_with_blocks(
+        frame: crate::frame::Frame,
+        options: &DecodeOptions,
+    ) -> Result<(Self, Option<CarBlocks>), Error> {
+        FirehoseMessage::decode_counted(frame, options, true)
+    }
 
-                    for op in &commit.ops {
-                        let (nsid, rkey) = {
-                            let mut split = op.path.split("/");
-                            (split.next().unwrap(), split.next())
-                        };
-                        if op.action == "delete" {
-                            operations.push(Operation::Delete(OperationMeta {
-                                collection: nsid.to_string(),
-                                rkey: rkey.unwrap_or_default().to_string(),
-                            }));
-                            continue;
-                        }
-                        let Some(op_cid_acid) = &op.cid else {
-                            if op.action != "delete" {
-                                warn!("No block cid for op {} {}", op.action, op.path);
-                            } else {
-                            }
-                            continue;
-                        };
-                        let op_cid = op_cid_acid.0;
-
-                        let record = match blocks.iter().find(|(cid, _data)| cid == &op_cid) {
-                            Some(block) => match nsid {
-                                bsky::feed::Post::NSID => {
-                                    Record::Post(serde_ipld_dagcbor::from_slice(&block.1).map_err(
-                                        |e| Error::DagCborDecodeError(e, message_frame.clone()),
-                                    )?)
-                                }
-                                bsky::graph::Follow::NSID => Record::Follow(
-                                    serde_ipld_dagcbor::from_slice(&block.1).map_err(|e| {
-                                        Error::DagCborDecodeError(e, message_frame.clone())
-                                    })?,
-                                ),
-                                bsky::graph::Block::NSID => Record::Block(
-                                    serde_ipld_dagcbor::from_slice(&block.1).map_err(|e| {
-                                        Error::DagCborDecodeError(e, message_frame.clone())
-                                    })?,
-                                ),
-                                bsky::feed::Repost::NSID => Record::Repost(
-                                    serde_ipld_dagcbor::from_slice(&block.1).map_err(|e| {
-                                        Error::DagCborDecodeError(e, message_frame.clone())
-                                    })?,
-                                ),
-                                bsky::feed::Like::NSID => {
-                                    Record::Like(serde_ipld_dagcbor::from_slice(&block.1).map_err(
-                                        |e| Error::DagCborDecodeError(e, message_frame.clone()),
-                                    )?)
-                                }
-                                bsky::graph::Listitem::NSID => Record::Listitem(
-                                    serde_ipld_dagcbor::from_slice(&block.1).map_err(|e| {
-                                        Error::DagCborDecodeError(e, message_frame.clone())
-                                    })?,
-                                ),
-                                bsky::feed::Generator::NSID => Record::Generator(
-                                    serde_ipld_dagcbor::from_slice(&block.1).map_err(|e| {
-                                        Error::DagCborDecodeError(e, message_frame.clone())
-                                    })?,
-                                ),
-                                bsky::actor::Profile::NSID => Record::Profile(
-                                    serde_ipld_dagcbor::from_slice(&block.1).map_err(|e| {
-                                        Error::DagCborDecodeError(e, message_frame.clone())
-                                    })?,
-                                ),
-                                bsky::graph::List::NSID => {
-                                    Record::List(serde_ipld_dagcbor::from_slice(&block.1).map_err(
-                                        |e| Error::DagCborDecodeError(e, message_frame.clone()),
-                                    )?)
-                                }
-                                bsky::graph::Starterpack::NSID => Record::Starterpack(
-                                    serde_ipld_dagcbor::from_slice(&block.1).map_err(|e| {
-                                        Error::DagCborDecodeError(e, message_frame.clone())
-                                    })?,
-                                ),
-
-                                _ => Record::Unknown(
-                                    serde_ipld_dagcbor::from_slice::<ipld_core::ipld::Ipld>(
-                                        &block.1,
-                                    )
-                                    .map_err(|e| {
-                                        Error::DagCborDecodeError(e, message_frame.clone())
-                                    })?,
-                                ),
-                            },
-                            None => Err(Error::NoBlockForCommit {
-                                operation: op.action.clone(),
-                                rev: commit.rev.clone(),
-                                did: commit.repo.clone(),
-                                path: op.path.clone(),
-                            })?,
-                        };
-                        let operation = match op.action.as_str() {
-                            "create" => Operation::Create {
-                                operation_meta: OperationMeta {
-                                    collection: nsid.to_string(),
-                                    rkey: rkey.unwrap_or_default().to_string(),
-                                },
-                                record,
-                                cid: op_cid.to_string(),
-                            },
-                            "update" => Operation::Update {
-                                operation_meta: OperationMeta {
-                                    collection: nsid.to_string(),
-                                    rkey: rkey.unwrap_or_default().to_string(),
-                                },
-                                record,
-                                cid: op_cid.to_string(),
-                            },
-                            other => Err(Error::UnknownCommitOperation {
-                                operation: other.to_string(),
-                                operation_meta: OperationMeta {
-                                    collection: nsid.to_string(),
-                                    rkey: rkey.unwrap_or_default().to_string(),
-                                },
-                                record,
-                                cid: op_cid.to_string(),
-                            })?,
+    fn decode_counted(
+        frame: crate::frame::Frame,
+        options: &DecodeOptions,
+        keep_blocks: bool,
+    ) -> Result<(Self, Option<CarBlocks>), Error> {
+        #[cfg(feature = "prometheus")]
+        let received_at = frame.received_at();
+        let message = FirehoseMessage::decode(frame, options, keep_blocks);
+        // failures of an operation are counted by decode_operations, along with their collection
+        #[cfg(feature = "prometheus")]
+        match &message {
+            Err(Error::DagCborDecodeError(..) | Error::IpldDecodeError(..)) => {
+                metrics::count_decode_error("dag_cbor", "")
+            }
+            Err(Error::CarDecodeError(..) | Error::InvalidCar(..)) => {
+                metrics::count_decode_error("car", "")
+            }
+            _ => {}
+        }
+        let message = message?;
+        #[cfg(feature = "prometheus")]
+        if let Some(received_at) = received_at {
+            metrics::DECODE_LATENCY.observe(received_at.elapsed().as_secs_f64());
+        }
+        Ok(message)
+    }
+
+    /// [`try_from_with_options`](Self::try_from_with_options) holding one of the
+    /// [`DecodeOptions::decode_permits`] while decoding a commit.
+    pub async fn try_from_limited(
+        frame: crate::frame::Frame,
+        options: &DecodeOptions,
+    ) -> Result<Self, Error> {
+        let _permit = match &options.decode_permits {
+            // a closed semaphore no longer limits anything
+            Some(permits) if frame.message_type() == Some("#commit") => {
+                permits.acquire().await.ok()
+            }
+            _ => None,
+        };
+        FirehoseMessage::try_from_with_options(frame, options)
+    }
+
+    fn decode(
+        frame: crate::frame::Frame,
+        options: &DecodeOptions,
+        keep_blocks: bool,
+    ) -> Result<(Self, Option<CarBlocks>), Error> {
+        let message = match frame {
+            crate::frame::Frame::Message(Some(t), message_frame) => match t.as_str() {
+                "#commit" => {
+                    let commit = decode::decode_commit(&message_frame)?;
+
+                    // reading the CAR file is the expensive part, bail out before it
+                    if !options.wants_did(&commit.repo) {
+                        let skipped = FirehoseMessage::Skipped {
+                            did: commit.repo.clone(),
+                            seq: commit.seq,
                         };
-                        operations.push(operation);
+                        return Ok((skipped, None));
                     }
-                    Ok(FirehoseMessage::Commit {
+
+                    let car = if keep_blocks {
+                        let mut block_reader = Cursor::new(&commit.blocks);
+                        let (blocks, _) = rs_car_sync::car_read_all(&mut block_reader, true)
+                            .map_err(|e| Error::CarDecodeError(e, commit.clone()))?;
+                        Some(CarBlocks {
+                            root: commit.commit.0,
+                            blocks,
+                        })
+                    } else {
+                        None
+                    };
+                    let read_blocks = car.as_ref().map(|car| car.blocks.as_slice());
+                    let mut operations = Vec::new();
+                    let (decode_errors, truncated_ops) =
+                        decode_operations(&commit, options, read_blocks, |operation| {
+                            operations.push(operation);
+                            ControlFlow::Continue(())
+                        })?;
+                    let received_at = message_frame
+                        .received_at
+                        .filter(|_| options.receipt_time)
+                        .map(|received_at| {
+                            let elapsed = chrono::TimeDelta::from_std(received_at.elapsed());
+                            Datetime::new((chrono::Utc::now() - elapsed.unwrap_or_default()).into())
+                        });
+                    let message = FirehoseMessage::Commit {
                         operations,
+                        decode_errors,
+                        truncated_ops,
+                        too_big: commit.too_big,
+                        received_at,
+                        seq: commit.seq,
                         rev: commit.rev.clone(),
                         time: commit.time.clone(),
                         did: commit.repo.clone(),
                         commit,
-                    })
+                    };
+                    return Ok((message, car));
                 }
                 "#account" => Ok(FirehoseMessage::Account(
                     serde_ipld_dagcbor::from_slice(message_frame.body.as_slice())
@@ -326,12 +990,644 @@ impl TryFrom<crate::frame::Frame> for FirehoseMessage {
                     serde_ipld_dagcbor::from_slice(message_frame.body.as_slice())
                         .map_err(|e| Error::DagCborDecodeError(e, message_frame))?,
                 )),
+                "#info" => {
+                    #[derive(serde::Deserialize)]
+                    struct Info {
+                        name: String,
+                        message: Option<String>,
+                    }
+                    let info: Info = serde_ipld_dagcbor::from_slice(message_frame.body.as_slice())
+                        .map_err(|e| Error::DagCborDecodeError(e, message_frame))?;
+                    Ok(FirehoseMessage::Info {
+                        name: info.name,
+                        message: info.message,
+                    })
+                }
                 t => Err(Error::UnknownFrameType(t.to_string(), message_frame))?,
             },
             crate::frame::Frame::Message(None, message_frame) => {
                 Err(Error::NoTypeInFrame(message_frame))
             }
             crate::frame::Frame::Error(error_frame) => Err(Error::FrameError(error_frame)),
+        };
+        Ok((message?, None))
+    }
+}
+
+/// Decode the operations of `commit`, handing them to `f` one at a time until it breaks.
+/// `read_blocks` are the blocks of its CAR file when the caller already read them.
+///
+/// Returns the operations dropped in [`DecodeMode::Lenient`]: the ones whose record failed to
+/// decode, and the number of truncated ones.
+///
+/// Runs in a `commit` span carrying the `did`, `rev` and `seq` of the commit, the warnings
+/// about an operation also carry its `collection` and `rkey`.
+pub(crate) fn decode_operations(
+    commit: &Commit,
+    options: &DecodeOptions,
+    read_blocks: Option<&[(ipld_core::cid::Cid, Vec<u8>)]>,
+    mut f: impl FnMut(Operation) -> ControlFlow<()>,
+) -> Result<(Vec<OpError>, usize), Error> {
+    // at the level of the warnings, which would lose their context in a disabled span
+    let _span = warn_span!(
+        "commit",
+        did = commit.repo.as_str(),
+        rev = commit.rev.as_str(),
+        seq = commit.seq
+    )
+    .entered();
+    let mut ops = commit.ops.as_slice();
+    let mut truncated_ops = 0;
+    if let Some(max_ops_per_commit) = options.max_ops_per_commit
+        && ops.len() > max_ops_per_commit
+    {
+        match options.mode {
+            DecodeMode::Strict => Err(Error::TooManyOperations {
+                count: ops.len(),
+                max_ops_per_commit,
+            })?,
+            DecodeMode::Lenient => {
+                warn!(
+                    "Truncating commit of {} with {} operations",
+                    commit.repo.as_str(),
+                    ops.len()
+                );
+                truncated_ops = ops.len() - max_ops_per_commit;
+                ops = &ops[..max_ops_per_commit];
+            }
+        }
+    }
+
+    let ops = ops
+        .iter()
+        .filter(|op| options.wants_op(op))
+        .collect::<Vec<_>>();
+
+    let blocks = if let Some(read_blocks) = read_blocks {
+        Blocks::indexed(read_blocks)
+    } else if options.collections.is_some() && ops.iter().all(|op| op.cid.is_none()) {
+        // deletes only, or nothing left
+        Blocks::Borrowed(Default::default())
+    } else if options.lazy_blocks {
+        let wanted = ops
+            .iter()
+            .filter_map(|op| op.cid.as_ref().map(|cid| cid.0))
+            .collect();
+        Blocks::Borrowed(
+            car::read_blocks(&commit.blocks, &wanted)
+                .map_err(|e| Error::InvalidCar(e, commit.clone()))?,
+        )
+    } else {
+        let mut block_reader = Cursor::new(&commit.blocks);
+        let (blocks, _) = rs_car_sync::car_read_all(&mut block_reader, true)
+            .map_err(|e| Error::CarDecodeError(e, commit.clone()))?;
+        Blocks::owned(blocks)
+    };
+
+    let mut decode_errors = Vec::new();
+
+    for op in ops {
+        let (nsid, rkey) = {
+            let mut split = op.path.split("/");
+            (split.next().unwrap(), split.next())
+        };
+        let nsid = if options.normalize_collections {
+            decode::normalize_nsid(nsid)
+        } else {
+            Cow::Borrowed(nsid)
+        };
+        if options.mode == DecodeMode::Strict && op.path.split('/').count() != 2 {
+            return Err(Error::InvalidOpPath {
+                path: op.path.clone(),
+            });
+        }
+        // an empty rkey would collide with the other malformed paths of the collection
+        let Some(rkey) = rkey.filter(|rkey| !rkey.is_empty()) else {
+            let error = Error::InvalidOpPath {
+                path: op.path.clone(),
+            };
+            if options.mode == DecodeMode::Strict {
+                return Err(error);
+            }
+            warn!(collection = %nsid, "Skipping {} {}: {error}", op.action, op.path);
+            decode_errors.push(OpError {
+                action: op.action.clone(),
+                path: op.path.clone(),
+                error: error.to_string(),
+            });
+            continue;
+        };
+        if op.action == "delete" {
+            let operation = Operation::Delete(OperationMeta {
+                collection: nsid.to_string(),
+                rkey: rkey.to_string(),
+            });
+            if f(operation).is_break() {
+                break;
+            }
+            continue;
+        }
+        let block = op.cid.as_ref().and_then(|cid| blocks.get(&cid.0));
+        if let (Some(max_record_bytes), Some(data)) = (options.max_record_bytes, block)
+            && data.len() > max_record_bytes
+        {
+            let error = Error::RecordTooLarge {
+                path: op.path.clone(),
+                size: data.len(),
+                max_record_bytes,
+            };
+            if options.mode == DecodeMode::Strict {
+                return Err(error);
+            }
+            warn!(collection = %nsid, rkey, "Skipping {} {}: {error}", op.action, op.path);
+            decode_errors.push(OpError {
+                action: op.action.clone(),
+                path: op.path.clone(),
+                error: error.to_string(),
+            });
+            continue;
+        }
+
+        let (Some(op_cid), Some(block)) = (&op.cid, block) else {
+            if commit.too_big {
+                warn!(
+                    collection = %nsid,
+                    rkey,
+                    "Skipping {} {}, its block was left out of a too big commit",
+                    op.action, op.path
+                );
+                continue;
+            }
+            let error = Error::NoBlockForCommit {
+                operation: op.action.clone(),
+                rev: commit.rev.clone(),
+                did: commit.repo.clone(),
+                path: op.path.clone(),
+            };
+            #[cfg(feature = "prometheus")]
+            metrics::count_decode_error("no_block", &nsid);
+            if options.mode == DecodeMode::Strict {
+                return Err(error);
+            }
+            warn!(collection = %nsid, rkey, "Skipping {} {}: {error}", op.action, op.path);
+            decode_errors.push(OpError {
+                action: op.action.clone(),
+                path: op.path.clone(),
+                error: error.to_string(),
+            });
+            continue;
+        };
+        let op_cid = op_cid.0;
+        if options.check_record_type {
+            let record_type = decode::record_type(block);
+            if record_type.as_deref() != Some(nsid.as_ref()) {
+                let error = Error::TypeCollectionMismatch {
+                    path: op.path.clone(),
+                    record_type,
+                };
+                if options.mode == DecodeMode::Strict {
+                    return Err(error);
+                }
+                warn!(collection = %nsid, rkey, "Skipping {} {}: {error}", op.action, op.path);
+                decode_errors.push(OpError {
+                    action: op.action.clone(),
+                    path: op.path.clone(),
+                    error: error.to_string(),
+                });
+                continue;
+            }
+        }
+        let record = decode_record(&nsid, block);
+        #[cfg(feature = "prometheus")]
+        match &record {
+            Ok(_) => metrics::count_decoded_record(&nsid),
+            Err(_) => metrics::count_decode_error("record", &nsid),
         }
+        let record = match record {
+            Ok(record) => record,
+            Err(e) if options.mode == DecodeMode::Strict => {
+                return Err(Error::RecordDecodeError {
+                    collection: nsid.to_string(),
+                    rkey: rkey.to_string(),
+                    cid: op_cid.to_string(),
+                    block: block.to_vec(),
+                    source: e,
+                });
+            }
+            Err(e) => {
+                warn!(
+                    collection = %nsid,
+                    rkey,
+                    "Skipping invalid {} record {}: {e}",
+                    op.action,
+                    op.path
+                );
+                decode_errors.push(OpError {
+                    action: op.action.clone(),
+                    path: op.path.clone(),
+                    error: e.to_string(),
+                });
+                continue;
+            }
+        };
+        // the typed record decoded: the block is valid DAG-CBOR
+        let ipld = options
+            .keep_ipld
+            .then(|| serde_ipld_dagcbor::from_slice(block).ok())
+            .flatten();
+        let operation = match op.action.as_str() {
+            "create" => Operation::Create {
+                operation_meta: OperationMeta {
+                    collection: nsid.to_string(),
+                    rkey: rkey.to_string(),
+                },
+                record,
+                cid: op_cid.to_string(),
+                ipld,
+            },
+            "update" => Operation::Update {
+                operation_meta: OperationMeta {
+                    collection: nsid.to_string(),
+                    rkey: rkey.to_string(),
+                },
+                record,
+                cid: op_cid.to_string(),
+                ipld,
+            },
+            other => {
+                let error = Error::UnknownCommitOperation {
+                    operation: other.to_string(),
+                    operation_meta: OperationMeta {
+                        collection: nsid.to_string(),
+                        rkey: rkey.to_string(),
+                    },
+                    record,
+                    cid: op_cid.to_string(),
+                };
+                if options.mode == DecodeMode::Strict {
+                    return Err(error);
+                }
+                warn!(collection = %nsid, rkey, "Skipping {} {}: {error}", op.action, op.path);
+                decode_errors.push(OpError {
+                    action: op.action.clone(),
+                    path: op.path.clone(),
+                    error: error.to_string(),
+                });
+                continue;
+            }
+        };
+        if f(operation).is_break() {
+            break;
+        }
+    }
+    Ok((decode_errors, truncated_ops))
+}
+
+fn decode_record(nsid: &str, block: &[u8]) -> Result<Record, DecodeError<Infallible>> {
+    Ok(match nsid {
+        bsky::feed::Post::NSID => Record::Post(serde_ipld_dagcbor::from_slice(block)?),
+        bsky::graph::Follow::NSID => Record::Follow(serde_ipld_dagcbor::from_slice(block)?),
+        bsky::graph::Block::NSID => Record::Block(serde_ipld_dagcbor::from_slice(block)?),
+        bsky::feed::Repost::NSID => Record::Repost(serde_ipld_dagcbor::from_slice(block)?),
+        bsky::feed::Like::NSID => Record::Like(serde_ipld_dagcbor::from_slice(block)?),
+        bsky::graph::Listitem::NSID => Record::Listitem(serde_ipld_dagcbor::from_slice(block)?),
+        bsky::feed::Generator::NSID => Record::Generator(serde_ipld_dagcbor::from_slice(block)?),
+        bsky::actor::Profile::NSID => Record::Profile(serde_ipld_dagcbor::from_slice(block)?),
+        bsky::graph::List::NSID => Record::List(serde_ipld_dagcbor::from_slice(block)?),
+        bsky::graph::Starterpack::NSID => {
+            Record::Starterpack(serde_ipld_dagcbor::from_slice(block)?)
+        }
+        bsky::feed::Threadgate::NSID => Record::Threadgate(serde_ipld_dagcbor::from_slice(block)?),
+        bsky::feed::Postgate::NSID => Record::Postgate(serde_ipld_dagcbor::from_slice(block)?),
+        bsky::labeler::Service::NSID => {
+            Record::LabelerService(serde_ipld_dagcbor::from_slice(block)?)
+        }
+        _ => Record::Unknown(serde_ipld_dagcbor::from_slice(block)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(collection: &str, rkey: &str) -> OperationMeta {
+        OperationMeta {
+            collection: collection.to_string(),
+            rkey: rkey.to_string(),
+        }
+    }
+
+    #[test]
+    fn record_path() {
+        let did = Did::new("did:plc:ewvi7nxzyoun6zhxrhs64oiz".to_string()).unwrap();
+        assert_eq!(
+            meta("app.bsky.feed.post", "3kabc").record_path(&did),
+            "did:plc:ewvi7nxzyoun6zhxrhs64oiz/app.bsky.feed.post/3kabc"
+        );
+        assert_eq!(
+            meta("app.bsky.actor.profile", "self").record_path(&did),
+            "did:plc:ewvi7nxzyoun6zhxrhs64oiz/app.bsky.actor.profile/self"
+        );
+        assert_eq!(
+            meta("com.example.thing", "a:b~c_d-e.f").record_path(&did),
+            "did:plc:ewvi7nxzyoun6zhxrhs64oiz/com.example.thing/a:b~c_d-e.f"
+        );
+        assert_eq!(
+            meta("com.example.thing", "a/b c%").record_path(&did),
+            "did:plc:ewvi7nxzyoun6zhxrhs64oiz/com.example.thing/a%2Fb%20c%25"
+        );
+        assert_eq!(
+            meta("com.example.thing", "..").record_path(&did),
+            "did:plc:ewvi7nxzyoun6zhxrhs64oiz/com.example.thing/%2E%2E"
+        );
+    }
+
+    #[test]
+    fn rkey_kinds() {
+        assert_eq!(
+            meta("app.bsky.feed.post", "3jzfcijpj2z2a").rkey_kind(),
+            RkeyKind::Tid
+        );
+        assert_eq!(
+            meta("app.bsky.actor.profile", "self").rkey_kind(),
+            RkeyKind::Literal
+        );
+        assert_eq!(
+            meta("app.bsky.feed.generator", "whats-hot").rkey_kind(),
+            RkeyKind::Any
+        );
+        for invalid in ["", ".", "..", "a/b", "a b", &"a".repeat(513)] {
+            assert_eq!(
+                meta("com.example.thing", invalid).rkey_kind(),
+                RkeyKind::Invalid
+            );
+        }
+    }
+
+    #[test]
+    fn seq() {
+        use crate::test_util::{CommitBuilder, TEST_DID, account_frame, identity_frame};
+
+        let decode = |frame| FirehoseMessage::try_from(frame).unwrap().seq();
+        assert_eq!(decode(CommitBuilder::new(TEST_DID).seq(1).build()), Some(1));
+        assert_eq!(decode(identity_frame(TEST_DID, 2, None)), Some(2));
+        assert_eq!(decode(account_frame(TEST_DID, 3, true)), Some(3));
+
+        let commit = FirehoseMessage::try_from(CommitBuilder::new(TEST_DID).seq(5).build());
+        let ipld_core::ipld::Ipld::Map(serialized) =
+            ipld_core::serde::to_ipld(commit.unwrap()).unwrap()
+        else {
+            panic!("expected a map");
+        };
+        assert_eq!(serialized["seq"], ipld_core::ipld::Ipld::Integer(5));
+
+        let options = DecodeOptions {
+            wanted_dids: Some(Default::default()),
+            ..Default::default()
+        };
+        let skipped = FirehoseMessage::try_from_with_options(
+            CommitBuilder::new(TEST_DID).seq(4).build(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(skipped.seq(), Some(4));
+    }
+
+    #[test]
+    fn info_messages() {
+        use crate::test_util::{ipld_map, message_frame};
+        use ipld_core::ipld::Ipld;
+
+        let frame = message_frame(
+            "#info",
+            &ipld_map([("name", Ipld::String("OutdatedCursor".into()))]),
+        );
+        let message = FirehoseMessage::try_from(frame).unwrap();
+        assert!(matches!(
+            &message,
+            FirehoseMessage::Info { name, message: None } if name == "OutdatedCursor"
+        ));
+        assert_eq!(message.kind().as_str(), "info");
+        assert_eq!(message.did(), None);
+        assert_eq!(message.seq(), None);
+    }
+
+    #[test]
+    fn identity_extra_fields() {
+        use crate::test_util::{TEST_DID, TEST_TIME, identity_frame, ipld_map, message_frame};
+        use ipld_core::ipld::Ipld;
+
+        let pds = Ipld::String("https://pds.example.com".into());
+        let also_known_as = Ipld::List(vec![Ipld::String("at://alice.example.com".into())]);
+        let body = ipld_map([
+            ("seq", Ipld::Integer(1)),
+            ("did", Ipld::String(TEST_DID.into())),
+            ("time", Ipld::String(TEST_TIME.into())),
+            ("pds", pds.clone()),
+            ("alsoKnownAs", also_known_as.clone()),
+        ]);
+        let message = FirehoseMessage::try_from(message_frame("#identity", &body)).unwrap();
+        let FirehoseMessage::Identity(identity) = &message else {
+            panic!("expected an identity");
+        };
+        assert_eq!(identity.handle, None);
+        let extra_fields = message.extra_fields().unwrap();
+        assert_eq!(extra_fields.get("pds"), Some(&pds));
+        assert_eq!(extra_fields.get("alsoKnownAs"), Some(&also_known_as));
+        assert!(!extra_fields.contains_key("did"));
+
+        let identity = FirehoseMessage::try_from(identity_frame(TEST_DID, 2, None)).unwrap();
+        assert!(identity.extra_fields().unwrap().is_empty());
+    }
+
+    #[test]
+    fn receipt_time() {
+        use crate::{frame::Frame, test_util::CommitBuilder};
+
+        let mut frame = CommitBuilder::new(TEST_DID).build();
+        if let Frame::Message(_, message_frame) = &mut frame {
+            message_frame.received_at = Some(std::time::Instant::now());
+        }
+        let options = DecodeOptions {
+            receipt_time: true,
+            ..Default::default()
+        };
+        let before = chrono::Utc::now();
+        let message = FirehoseMessage::try_from_with_options(frame.clone(), &options).unwrap();
+        let FirehoseMessage::Commit {
+            received_at: Some(received_at),
+            ..
+        } = &message
+        else {
+            panic!("expected a commit received at some time");
+        };
+        let received_at = received_at.as_ref().to_utc();
+        assert!(before - chrono::TimeDelta::seconds(1) <= received_at);
+        assert!(received_at <= chrono::Utc::now());
+        // commits built for tests are from 2024
+        assert!(message.receipt_delay().unwrap() > chrono::TimeDelta::days(300));
+
+        let message = FirehoseMessage::try_from(frame).unwrap();
+        assert_eq!(message.receipt_delay(), None);
+    }
+
+    #[test]
+    fn delete_events() {
+        let deletion = Operation::Delete(meta("app.bsky.feed.post", "3kabc"));
+        let event = deletion.delete_event().unwrap();
+        assert_eq!(event.kind, RecordKind::Post);
+        assert_eq!(event.operation_meta.rkey, "3kabc");
+
+        let deletion = Operation::Delete(meta("com.example.thing", "3kabc"));
+        assert_eq!(deletion.delete_event().unwrap().kind, RecordKind::Unknown);
+    }
+
+    #[test]
+    fn gate_and_labeler_records() {
+        use crate::test_util::{CommitBuilder, TEST_DID, TEST_TIME, ipld_map};
+        use ipld_core::ipld::Ipld;
+
+        let string = |value: &str| Ipld::String(value.into());
+        let post_uri = format!("at://{TEST_DID}/app.bsky.feed.post/3kabc");
+        let threadgate = ipld_map([
+            ("$type", string("app.bsky.feed.threadgate")),
+            ("post", string(&post_uri)),
+            ("createdAt", string(TEST_TIME)),
+        ]);
+        let postgate = ipld_map([
+            ("$type", string("app.bsky.feed.postgate")),
+            ("post", string(&post_uri)),
+            ("createdAt", string(TEST_TIME)),
+            ("detachedEmbeddingUris", Ipld::List(Vec::new())),
+        ]);
+        let labeler = ipld_map([
+            ("$type", string("app.bsky.labeler.service")),
+            (
+                "policies",
+                ipld_map([("labelValues", Ipld::List(vec![string("spam")]))]),
+            ),
+            ("createdAt", string(TEST_TIME)),
+        ]);
+        let frame = CommitBuilder::new(TEST_DID)
+            .create("app.bsky.feed.threadgate", "3kabc", threadgate)
+            .create("app.bsky.feed.postgate", "3kabc", postgate)
+            .create("app.bsky.labeler.service", "self", labeler)
+            .build();
+        let FirehoseMessage::Commit { operations, .. } = FirehoseMessage::try_from(frame).unwrap()
+        else {
+            panic!("expected a commit");
+        };
+        let records = operations
+            .iter()
+            .filter_map(Operation::record)
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            records[..],
+            [
+                Record::Threadgate(_),
+                Record::Postgate(_),
+                Record::LabelerService(_)
+            ]
+        ));
+        assert_eq!(
+            RecordKind::of_collection("app.bsky.labeler.service"),
+            RecordKind::LabelerService
+        );
+    }
+
+    #[test]
+    fn lexicon_info() {
+        assert!(!LEXICON_INFO.atrium_api.is_empty());
+        assert!(LEXICON_INFO.nsids.contains(&"app.bsky.feed.post"));
+    }
+
+    #[test]
+    fn record_kind_nsids() {
+        for nsid in LEXICON_INFO.nsids {
+            assert_eq!(RecordKind::of_collection(nsid).nsid(), Some(*nsid));
+        }
+        assert_eq!(RecordKind::Unknown.nsid(), None);
+        assert_eq!(Record::Unknown(ipld_core::ipld::Ipld::Null).nsid(), None);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn serialized_messages_roundtrip() {
+        use crate::test_util::{CommitBuilder, TEST_DID, cid_for, like_record};
+
+        let frame = CommitBuilder::new(TEST_DID)
+            .seq(7)
+            .create_post("3kpost", "hello")
+            .update(
+                "app.bsky.feed.like",
+                "3klike",
+                like_record("at://did:plc:a/app.bsky.feed.post/1", &cid_for(b"post")),
+            )
+            .create("com.example.thing", "1", ipld_core::ipld::Ipld::Integer(1))
+            .delete("app.bsky.graph.follow", "3kfollow")
+            .build();
+        let message = FirehoseMessage::try_from(frame).unwrap();
+        let json = serde_json::to_string(&message).unwrap();
+
+        let deserialized = serde_json::from_str::<FirehoseMessage>(&json).unwrap();
+        assert_eq!(serde_json::to_string(&deserialized).unwrap(), json);
+        let FirehoseMessage::Commit {
+            operations, commit, ..
+        } = deserialized
+        else {
+            panic!("expected a commit");
+        };
+        let kinds = operations
+            .iter()
+            .map(|op| op.record().map(Record::kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                Some(RecordKind::Post),
+                Some(RecordKind::Like),
+                Some(RecordKind::Unknown),
+                None
+            ]
+        );
+        assert_eq!(commit.seq, 7);
+        assert_eq!(commit.repo.as_str(), TEST_DID);
+        assert_eq!(commit.ops.len(), 4);
+
+        let info = r#"{"kind":"info","name":"OutdatedCursor","message":null}"#;
+        let info = serde_json::from_str::<FirehoseMessage>(info).unwrap();
+        assert!(matches!(info, FirehoseMessage::Info { name, .. } if name == "OutdatedCursor"));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn operations_as_json_array() {
+        use crate::test_util::{CommitBuilder, TEST_DID, identity_frame};
+
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3kpost", "hello")
+            .delete("app.bsky.graph.follow", "3kfollow")
+            .build();
+        let message = FirehoseMessage::try_from(frame).unwrap();
+        let json = message.operations_json().unwrap().unwrap();
+        let operations = serde_json::from_str::<Vec<serde_json::Value>>(&json).unwrap();
+        assert_eq!(operations.len(), 2);
+        assert_eq!(
+            operations[0]["uri"],
+            format!("at://{TEST_DID}/app.bsky.feed.post/3kpost")
+        );
+        assert_eq!(operations[0]["operation"], "create");
+        assert_eq!(operations[0]["collection"], "app.bsky.feed.post");
+        assert_eq!(operations[0]["record"]["text"], "hello");
+        assert!(operations[0]["cid"].is_string());
+        assert_eq!(
+            operations[1]["uri"],
+            format!("at://{TEST_DID}/app.bsky.graph.follow/3kfollow")
+        );
+        assert_eq!(operations[1]["operation"], "delete");
+        assert_eq!(operations[1]["rkey"], "3kfollow");
+
+        let identity = FirehoseMessage::try_from(identity_frame(TEST_DID, 2, None)).unwrap();
+        assert_eq!(identity.operations_json().unwrap(), None);
     }
 }
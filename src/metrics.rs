@@ -1,4 +1,7 @@
-use prometheus::{IntCounter, IntCounterVec, Opts};
+use lazy_static::lazy_static;
+use prometheus::{Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts};
+
+use crate::RecordKind;
 
 pub fn create_counter(name: &str, help: &str) -> IntCounter {
     let counter = IntCounter::new(name, help).unwrap();
@@ -11,3 +14,139 @@ pub fn create_counter_with_labels(name: &str, help: &str, labels: &[&str]) -> In
     prometheus::register(Box::new(counter.clone())).unwrap();
     counter
 }
+
+pub fn create_histogram(name: &str, help: &str, buckets: Vec<f64>) -> Histogram {
+    let histogram = Histogram::with_opts(HistogramOpts::new(name, help).buckets(buckets)).unwrap();
+    prometheus::register(Box::new(histogram.clone())).unwrap();
+    histogram
+}
+
+lazy_static! {
+    /// From frame receipt in `RepoSubscription::next()` to the decoded `FirehoseMessage`.
+    pub(crate) static ref DECODE_LATENCY: Histogram = create_histogram(
+        "bluesky_firehose_streamer_decode_latency_seconds",
+        "Time spent decoding a firehose frame after its receipt",
+        prometheus::exponential_buckets(0.000_01, 4.0, 10).unwrap()
+    );
+    /// Decoding failures by `error` (`dag_cbor`, `car`, `no_block` or `record`) and
+    /// `collection`, empty for the failures of a whole frame. Operations dropped in
+    /// [`DecodeMode::Lenient`](crate::DecodeMode::Lenient) are counted too.
+    pub(crate) static ref DECODE_ERRORS: IntCounterVec = create_counter_with_labels(
+        "bluesky_firehose_streamer_decode_errors",
+        "Firehose frames and operations failing to decode",
+        &["error", "collection"]
+    );
+    /// Records decoded by `collection`.
+    pub(crate) static ref DECODED_RECORDS: IntCounterVec = create_counter_with_labels(
+        "bluesky_firehose_streamer_decoded_records",
+        "Records decoded from firehose commits",
+        &["collection"]
+    );
+    /// Lookups of [`CachedDidResolver`](crate::resolver::CachedDidResolver) by `result`,
+    /// `hit` or `miss`.
+    pub(crate) static ref DID_CACHE_COUNTER: IntCounterVec = create_counter_with_labels(
+        "bluesky_firehose_streamer_did_cache",
+        "DID document cache lookups",
+        &["result"]
+    );
+    /// See [`car::read_blocks`](crate::car::read_blocks).
+    pub(crate) static ref CAR_DUPLICATE_BLOCKS: IntCounter = create_counter(
+        "bluesky_firehose_streamer_car_duplicate_blocks",
+        "Blocks found more than once in the CAR file of a commit"
+    );
+}
+
+/// Collections are chosen by any PDS: those without a [`RecordKind`] share the `other` label
+/// to keep the number of series bounded.
+fn collection_label(collection: &str) -> &'static str {
+    match collection {
+        "" => "",
+        collection => RecordKind::of_collection(collection)
+            .nsid()
+            .unwrap_or("other"),
+    }
+}
+
+pub(crate) fn count_decode_error(error: &str, collection: &str) {
+    DECODE_ERRORS
+        .with_label_values(&[error, collection_label(collection)])
+        .inc();
+}
+
+pub(crate) fn count_decoded_record(collection: &str) {
+    DECODED_RECORDS
+        .with_label_values(&[collection_label(collection)])
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{
+        FirehoseMessage,
+        frame::Frame,
+        test_util::{CommitBuilder, TEST_DID, car_bytes, cid_for, ipld_map},
+    };
+
+    #[test]
+    fn decode_latency_is_observed() {
+        let before = DECODE_LATENCY.get_sample_count();
+
+        let mut frame = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .build();
+        if let Frame::Message(_, message_frame) = &mut frame {
+            message_frame.received_at = Some(Instant::now());
+        }
+        FirehoseMessage::try_from(frame).expect("failed to decode");
+
+        assert!(DECODE_LATENCY.get_sample_count() > before);
+    }
+
+    #[test]
+    fn decode_errors_are_counted() {
+        let no_block = DECODE_ERRORS.with_label_values(&["no_block", "app.bsky.feed.post"]);
+        let car = DECODE_ERRORS.with_label_values(&["car", ""]);
+        let (no_block_before, car_before) = (no_block.get(), car.get());
+
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .raw_blocks(car_bytes(&cid_for(b"commit"), &[]))
+            .build();
+        FirehoseMessage::try_from(frame).expect("failed to decode");
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .raw_blocks(vec![0xff, 0x00, 0x42])
+            .build();
+        assert!(FirehoseMessage::try_from(frame).is_err());
+
+        // other tests decode failing frames concurrently
+        assert!(no_block.get() > no_block_before);
+        assert!(car.get() > car_before);
+    }
+
+    #[test]
+    fn decoded_records_are_counted() {
+        let posts = DECODED_RECORDS.with_label_values(&["app.bsky.feed.post"]);
+        let other = DECODED_RECORDS.with_label_values(&["other"]);
+        let (posts_before, other_before) = (posts.get(), other.get());
+
+        let thing = ipld_map([(
+            "$type",
+            ipld_core::ipld::Ipld::String("com.example.thing".into()),
+        )]);
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .create("com.example.thing", "3kabc", thing)
+            .build();
+        FirehoseMessage::try_from(frame).expect("failed to decode");
+
+        // other tests decode records concurrently
+        assert!(posts.get() > posts_before);
+        assert!(other.get() > other_before);
+        assert_eq!(collection_label("com.example.thing"), "other");
+        assert_eq!(collection_label(""), "");
+    }
+}
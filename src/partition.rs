@@ -0,0 +1,54 @@
+//! Partition keys, spreading messages over partitions while keeping the events of each
+//! repository in order.
+use crate::{FirehoseMessage, event::OperationEvent};
+
+/// Key under which events must stay ordered: the DID of the repository.
+pub trait PartitionKey {
+    fn partition_key(&self) -> &str;
+}
+
+impl PartitionKey for FirehoseMessage {
+    fn partition_key(&self) -> &str {
+        self.did().map(|did| did.as_str()).unwrap_or_default()
+    }
+}
+
+impl PartitionKey for OperationEvent {
+    fn partition_key(&self) -> &str {
+        self.did.as_str()
+    }
+}
+
+/// Partition of `key` in `0..partitions`, stable across runs and platforms (FNV-1a).
+pub fn partition(key: &str, partitions: usize) -> usize {
+    assert!(partitions > 0, "partitions must be positive");
+    (fnv1a(key) % partitions as u64) as usize
+}
+
+pub(crate) fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{CommitBuilder, TEST_DID, identity_frame};
+
+    #[test]
+    fn events_of_a_repository_share_a_partition() {
+        let commit = FirehoseMessage::try_from(CommitBuilder::new(TEST_DID).build()).unwrap();
+        let identity = FirehoseMessage::try_from(identity_frame(TEST_DID, 2, None)).unwrap();
+        assert_eq!(commit.partition_key(), TEST_DID);
+        assert_eq!(identity.partition_key(), TEST_DID);
+
+        assert_eq!(partition(TEST_DID, 1), 0);
+        // stable across runs: do not change the hash function
+        assert_eq!(partition(TEST_DID, 16), 9);
+        let spread = (0..64)
+            .map(|i| partition(&format!("did:plc:{i}"), 4))
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(spread.len(), 4);
+    }
+}
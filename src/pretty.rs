@@ -0,0 +1,96 @@
+//! Compact human readable rendering of messages, for debugging and CLI tools.
+use crate::{FirehoseMessage, Operation, Record};
+
+const PREVIEW_LEN: usize = 80;
+
+/// One line per operation for commits: `did rkey collection action | preview`, one line for
+/// other messages.
+pub fn pretty(message: &FirehoseMessage) -> String {
+    match message {
+        FirehoseMessage::Commit {
+            did, operations, ..
+        } => operations
+            .iter()
+            .map(|operation| {
+                let meta = operation.operation_meta();
+                let line = format!(
+                    "{} {} {} {}",
+                    did.as_str(),
+                    meta.rkey,
+                    meta.collection,
+                    operation.kind().as_str()
+                );
+                match operation.record().and_then(preview) {
+                    Some(preview) => format!("{line} | {preview}"),
+                    None => line,
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        FirehoseMessage::Identity(identity) => format!(
+            "{} identity handle={}",
+            identity.did.as_str(),
+            identity
+                .handle
+                .as_ref()
+                .map(|handle| handle.as_str())
+                .unwrap_or("-")
+        ),
+        FirehoseMessage::Account(account) => format!(
+            "{} account active={} status={}",
+            account.did.as_str(),
+            account.active,
+            account.status.as_deref().unwrap_or("-")
+        ),
+        FirehoseMessage::Skipped { did, seq } => format!("{} skipped seq={seq}", did.as_str()),
+        FirehoseMessage::Info { name, message } => {
+            format!(
+                "info name={name} message={}",
+                message.as_deref().unwrap_or("-")
+            )
+        }
+    }
+}
+
+fn preview(record: &Record) -> Option<String> {
+    let preview = match record {
+        Record::Post(post) => post.text.replace(['\n', '\r'], " "),
+        Record::Like(like) => like.subject.uri.clone(),
+        Record::Repost(repost) => repost.subject.uri.clone(),
+        Record::Follow(follow) => follow.subject.as_str().to_string(),
+        Record::Block(block) => block.subject.as_str().to_string(),
+        _ => return None,
+    };
+    if preview.chars().count() > PREVIEW_LEN {
+        Some(format!(
+            "{}…",
+            preview.chars().take(PREVIEW_LEN).collect::<String>()
+        ))
+    } else {
+        Some(preview)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{CommitBuilder, TEST_DID};
+
+    #[test]
+    fn pretty_post_create() {
+        let message = FirehoseMessage::try_from(
+            CommitBuilder::new(TEST_DID)
+                .create_post("3kabc", "hello\nworld")
+                .delete("app.bsky.feed.post", "3kdef")
+                .build(),
+        )
+        .unwrap();
+        assert_eq!(
+            pretty(&message),
+            format!(
+                "{TEST_DID} 3kabc app.bsky.feed.post create | hello world\n\
+                 {TEST_DID} 3kdef app.bsky.feed.post delete"
+            )
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Flag repositories posting faster than a threshold, for anti-spam consumers.
+use std::num::{NonZeroU32, NonZeroUsize};
+
+use atrium_api::types::string::Did;
+use lru::LruCache;
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::{FirehoseMessage, Operation, Record};
+
+/// A repository creating posts faster than allowed by its [`PostingRateTracker`].
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RateFlag {
+    pub did: Did,
+    /// Sequence number of the commit exceeding the threshold.
+    pub seq: i64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    flagged: bool,
+}
+
+/// Posting rate of each repository, measured with a token bucket holding up to
+/// `posts_per_minute` posts and refilled at that rate.
+///
+/// A repository is flagged when its bucket runs out, then not again until it slows down. Memory
+/// is bounded: only the `max_dids` most recently posting repositories are tracked.
+pub struct PostingRateTracker {
+    posts_per_minute: f64,
+    buckets: LruCache<Did, Bucket>,
+}
+
+impl PostingRateTracker {
+    pub fn new(posts_per_minute: NonZeroU32, max_dids: NonZeroUsize) -> Self {
+        PostingRateTracker {
+            posts_per_minute: posts_per_minute.get().into(),
+            buckets: LruCache::new(max_dids),
+        }
+    }
+    /// Count the posts created by a commit, other messages are ignored.
+    pub fn observe(&mut self, message: &FirehoseMessage, now: Instant) -> Option<RateFlag> {
+        let FirehoseMessage::Commit {
+            did,
+            seq,
+            operations,
+            ..
+        } = message
+        else {
+            return None;
+        };
+        let posts = operations
+            .iter()
+            .filter(|operation| {
+                matches!(
+                    operation,
+                    Operation::Create {
+                        record: Record::Post(_),
+                        ..
+                    }
+                )
+            })
+            .count();
+        if posts == 0 {
+            return None;
+        }
+        let capacity = self.posts_per_minute;
+        let bucket = self.buckets.get_or_insert_mut(did.clone(), || Bucket {
+            tokens: capacity,
+            updated_at: now,
+            flagged: false,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * capacity / 60.0).min(capacity);
+        bucket.updated_at = now;
+        bucket.tokens -= posts as f64;
+        if bucket.tokens >= 0.0 {
+            bucket.flagged = false;
+            return None;
+        }
+        // posts over the threshold are not owed back
+        bucket.tokens = 0.0;
+        if bucket.flagged {
+            return None;
+        }
+        bucket.flagged = true;
+        Some(RateFlag {
+            did: did.clone(),
+            seq: *seq,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::{CommitBuilder, TEST_DID};
+
+    fn post(did: &str, seq: i64) -> FirehoseMessage {
+        let frame = CommitBuilder::new(did)
+            .seq(seq)
+            .create_post("3kabc", "buy now")
+            .build();
+        FirehoseMessage::try_from(frame).unwrap()
+    }
+
+    #[test]
+    fn fast_posters_are_flagged_once() {
+        let mut tracker =
+            PostingRateTracker::new(NonZeroU32::new(2).unwrap(), NonZeroUsize::new(16).unwrap());
+        let now = Instant::now();
+        assert_eq!(tracker.observe(&post(TEST_DID, 1), now), None);
+        assert_eq!(tracker.observe(&post(TEST_DID, 2), now), None);
+        let flag = tracker.observe(&post(TEST_DID, 3), now).unwrap();
+        assert_eq!((flag.did.as_str(), flag.seq), (TEST_DID, 3));
+        assert_eq!(tracker.observe(&post(TEST_DID, 4), now), None);
+        // other repositories have their own budget
+        assert_eq!(tracker.observe(&post("did:plc:other", 5), now), None);
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(tracker.observe(&post(TEST_DID, 6), later), None);
+    }
+}
@@ -0,0 +1,1095 @@
+//! Accessors on decoded records.
+use atrium_api::{
+    app::bsky::{
+        embed::{external::External, images, record_with_media::MainMediaRefs, video},
+        feed::{
+            post::{RecordEmbedRefs, RecordLabelsRefs},
+            threadgate::RecordAllowItem,
+        },
+        richtext::facet::MainFeaturesItem,
+    },
+    com::atproto::repo::strong_ref::Main as StrongRef,
+    types::{
+        BlobRef, TypedBlobRef, Union,
+        string::{Datetime, Did, Language},
+    },
+};
+use ipld_core::{cid::Cid, ipld::Ipld};
+use serde::{Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+
+use crate::{Error, FirehoseMessage, Record, uri::AtUri};
+
+/// Who is allowed to reply to a thread, as set by a threadgate.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", content = "rules", rename_all = "lowercase")]
+pub enum ReplyGate {
+    /// No `allow` field: anybody can reply.
+    Anyone,
+    /// Only accounts matching one of the rules can reply; no rules means nobody can.
+    Restricted(Vec<ReplyRule>),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "rule", content = "list", rename_all = "lowercase")]
+pub enum ReplyRule {
+    /// Accounts mentioned in the root post.
+    Mentioned,
+    /// Accounts followed by the root post author.
+    Following,
+    /// Accounts following the root post author.
+    Followers,
+    /// Members of the list with the given AT URI.
+    List(String),
+    /// A rule this crate does not know about.
+    Unknown(String),
+}
+
+/// Strong reference to a post: its AT URI and the CID of the version referenced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PostRef {
+    pub uri: String,
+    pub cid: Cid,
+}
+
+/// Posts a reply is about, as referenced when it was written: later edits of the parent or
+/// the root change their current CID, not the ones kept here.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyRefs {
+    pub root: PostRef,
+    pub parent: PostRef,
+}
+
+/// A GIF shared through an external embed.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Gif {
+    pub url: String,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+}
+
+/// An image embedded in a post.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImageEmbed {
+    /// CID of the image blob.
+    pub cid: String,
+    pub alt: String,
+    /// `(width, height)`, only a ratio: not necessarily the size of the image in pixels.
+    pub aspect_ratio: Option<(u64, u64)>,
+}
+
+/// Classification fields of a post, see [`Record::post_metadata`].
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct PostMetadata {
+    pub langs: Vec<Language>,
+    pub self_labels: Vec<String>,
+    pub tags: Vec<String>,
+    pub facet_tags: Vec<String>,
+}
+
+/// Mentions, links and hashtags of a post text, see [`Record::facets_summary`].
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct FacetsSummary {
+    /// DIDs of the mentioned accounts.
+    pub mentions: Vec<String>,
+    pub links: Vec<String>,
+    /// Hashtags, without the `#`.
+    pub tags: Vec<String>,
+}
+
+impl Record {
+    /// Text of a post record.
+    pub fn post_text(&self) -> Option<&str> {
+        match self {
+            Record::Post(post) => Some(&post.text),
+            _ => None,
+        }
+    }
+    /// Languages of a post, as declared by its author.
+    pub fn langs(&self) -> &[Language] {
+        match self {
+            Record::Post(post) => post.langs.as_deref().unwrap_or_default(),
+            _ => &[],
+        }
+    }
+    /// Values of the self labels (content warnings) set on a post by its author.
+    pub fn self_labels(&self) -> Vec<&str> {
+        match self {
+            Record::Post(post) => match &post.labels {
+                Some(Union::Refs(RecordLabelsRefs::ComAtprotoLabelDefsSelfLabels(labels))) => {
+                    labels
+                        .values
+                        .iter()
+                        .map(|label| label.val.as_str())
+                        .collect()
+                }
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+    /// Hashtags of a post that are not part of its text.
+    pub fn tags(&self) -> &[String] {
+        match self {
+            Record::Post(post) => post.tags.as_deref().unwrap_or_default(),
+            _ => &[],
+        }
+    }
+    /// Hashtags of a post text, from its rich text facets (without the `#`).
+    pub fn facet_tags(&self) -> Vec<&str> {
+        let Record::Post(post) = self else {
+            return Vec::new();
+        };
+        post.facets
+            .iter()
+            .flatten()
+            .flat_map(|facet| &facet.features)
+            .filter_map(|feature| match feature {
+                Union::Refs(MainFeaturesItem::Tag(tag)) => Some(tag.tag.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+    /// Rich text features of a post, `None` for other records.
+    ///
+    /// Posts written before rich text facets carry the deprecated `entities` field instead,
+    /// it is read when `facets` is absent: legacy entities have no hashtags.
+    pub fn facets_summary(&self) -> Option<FacetsSummary> {
+        let Record::Post(post) = self else {
+            return None;
+        };
+        let mut summary = FacetsSummary::default();
+        if let Some(facets) = &post.facets {
+            for feature in facets.iter().flat_map(|facet| &facet.features) {
+                match feature {
+                    Union::Refs(MainFeaturesItem::Mention(mention)) => {
+                        summary.mentions.push(mention.did.to_string())
+                    }
+                    Union::Refs(MainFeaturesItem::Link(link)) => {
+                        summary.links.push(link.uri.clone())
+                    }
+                    Union::Refs(MainFeaturesItem::Tag(tag)) => summary.tags.push(tag.tag.clone()),
+                    _ => {}
+                }
+            }
+        } else {
+            for entity in post.entities.iter().flatten() {
+                match entity.r#type.as_str() {
+                    "mention" => summary.mentions.push(entity.value.clone()),
+                    "link" => summary.links.push(entity.value.clone()),
+                    _ => {}
+                }
+            }
+        }
+        Some(summary)
+    }
+    /// [`langs`](Self::langs), [`self_labels`](Self::self_labels), [`tags`](Self::tags) and
+    /// [`facet_tags`](Self::facet_tags) of a post at once, `None` for other records.
+    pub fn post_metadata(&self) -> Option<PostMetadata> {
+        if !matches!(self, Record::Post(_)) {
+            return None;
+        }
+        Some(PostMetadata {
+            langs: self.langs().to_vec(),
+            self_labels: self.self_labels().into_iter().map(str::to_string).collect(),
+            tags: self.tags().to_vec(),
+            facet_tags: self.facet_tags().into_iter().map(str::to_string).collect(),
+        })
+    }
+    /// `createdAt` of the record, as claimed by the client that wrote it.
+    ///
+    /// See [`Timestamps`](crate::Timestamps) for why it should not be trusted blindly.
+    pub fn created_at(&self) -> Option<Datetime> {
+        match self {
+            Record::Post(record) => Some(record.created_at.clone()),
+            Record::Follow(record) => Some(record.created_at.clone()),
+            Record::Block(record) => Some(record.created_at.clone()),
+            Record::Repost(record) => Some(record.created_at.clone()),
+            Record::Like(record) => Some(record.created_at.clone()),
+            Record::Listitem(record) => Some(record.created_at.clone()),
+            Record::Generator(record) => Some(record.created_at.clone()),
+            Record::Profile(record) => record.created_at.clone(),
+            Record::List(record) => Some(record.created_at.clone()),
+            Record::Starterpack(record) => Some(record.created_at.clone()),
+            Record::Threadgate(record) => Some(record.created_at.clone()),
+            Record::Postgate(record) => Some(record.created_at.clone()),
+            Record::LabelerService(record) => Some(record.created_at.clone()),
+            Record::Unknown(Ipld::Map(map)) => match map.get("createdAt") {
+                Some(Ipld::String(created_at)) => created_at.parse().ok(),
+                _ => None,
+            },
+            Record::Unknown(_) => None,
+        }
+    }
+    /// Link card of a post, embedded alone or along with a quoted record.
+    pub fn external_embed(&self) -> Option<&External> {
+        let Record::Post(post) = self else {
+            return None;
+        };
+        match post.embed.as_ref()? {
+            Union::Refs(RecordEmbedRefs::AppBskyEmbedExternalMain(embed)) => Some(&embed.external),
+            Union::Refs(RecordEmbedRefs::AppBskyEmbedRecordWithMediaMain(embed)) => {
+                match &embed.media {
+                    Union::Refs(MainMediaRefs::AppBskyEmbedExternalMain(embed)) => {
+                        Some(&embed.external)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+    /// Images embedded in a post, alone or along with a quoted record.
+    pub fn image_embeds(&self) -> Vec<ImageEmbed> {
+        let Record::Post(post) = self else {
+            return Vec::new();
+        };
+        let images = match &post.embed {
+            Some(Union::Refs(RecordEmbedRefs::AppBskyEmbedImagesMain(embed))) => &embed.images,
+            Some(Union::Refs(RecordEmbedRefs::AppBskyEmbedRecordWithMediaMain(embed))) => {
+                match &embed.media {
+                    Union::Refs(MainMediaRefs::AppBskyEmbedImagesMain(embed)) => &embed.images,
+                    _ => return Vec::new(),
+                }
+            }
+            _ => return Vec::new(),
+        };
+        images.iter().map(image_embed).collect()
+    }
+    /// CIDs of the blobs referenced by a record, to fetch them with `com.atproto.sync.getBlob`:
+    /// images, videos, captions and link card thumbnails of posts, avatars and banners.
+    pub fn blob_cids(&self) -> Vec<Cid> {
+        let blobs: Vec<&BlobRef> = match self {
+            Record::Post(post) => match &post.embed {
+                Some(Union::Refs(RecordEmbedRefs::AppBskyEmbedImagesMain(embed))) => {
+                    embed.images.iter().map(|image| &image.image).collect()
+                }
+                Some(Union::Refs(RecordEmbedRefs::AppBskyEmbedVideoMain(embed))) => {
+                    video_blobs(embed)
+                }
+                Some(Union::Refs(RecordEmbedRefs::AppBskyEmbedExternalMain(embed))) => {
+                    embed.external.thumb.iter().collect()
+                }
+                Some(Union::Refs(RecordEmbedRefs::AppBskyEmbedRecordWithMediaMain(embed))) => {
+                    match &embed.media {
+                        Union::Refs(MainMediaRefs::AppBskyEmbedImagesMain(embed)) => {
+                            embed.images.iter().map(|image| &image.image).collect()
+                        }
+                        Union::Refs(MainMediaRefs::AppBskyEmbedVideoMain(embed)) => {
+                            video_blobs(embed)
+                        }
+                        Union::Refs(MainMediaRefs::AppBskyEmbedExternalMain(embed)) => {
+                            embed.external.thumb.iter().collect()
+                        }
+                        _ => Vec::new(),
+                    }
+                }
+                _ => Vec::new(),
+            },
+            Record::Profile(profile) => profile.avatar.iter().chain(&profile.banner).collect(),
+            Record::Generator(generator) => generator.avatar.iter().collect(),
+            Record::List(list) => list.avatar.iter().collect(),
+            _ => Vec::new(),
+        };
+        blobs.into_iter().filter_map(blob_cid).collect()
+    }
+    /// The external embed of a post when it links to a Tenor GIF.
+    ///
+    /// Clients share GIFs as `https://media.tenor.com/{id}/{name}.gif?hh={height}&ww={width}`
+    /// links, dimensions are read from the query string when present.
+    pub fn gif_embed(&self) -> Option<Gif> {
+        let url = &self.external_embed()?.uri;
+        let rest = url.strip_prefix("https://")?;
+        let (host, rest) = rest.split_once('/')?;
+        if host != "tenor.com" && !host.ends_with(".tenor.com") {
+            return None;
+        }
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+        if !path.ends_with(".gif") {
+            return None;
+        }
+        let param = |name: &str| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('=')?.parse().ok())
+        };
+        Some(Gif {
+            url: url.clone(),
+            width: param("ww"),
+            height: param("hh"),
+        })
+    }
+    /// AT URI of the post a like or a repost is about.
+    pub fn subject_uri(&self) -> Option<&str> {
+        match self {
+            Record::Like(like) => Some(&like.subject.uri),
+            Record::Repost(repost) => Some(&repost.subject.uri),
+            _ => None,
+        }
+    }
+    /// AT URI of the record quoted by a post, alone or along with media.
+    pub fn quoted_uri(&self) -> Option<&str> {
+        let Record::Post(post) = self else {
+            return None;
+        };
+        match post.embed.as_ref()? {
+            Union::Refs(RecordEmbedRefs::AppBskyEmbedRecordMain(embed)) => Some(&embed.record.uri),
+            Union::Refs(RecordEmbedRefs::AppBskyEmbedRecordWithMediaMain(embed)) => {
+                Some(&embed.record.record.uri)
+            }
+            _ => None,
+        }
+    }
+    /// AT URIs of the records a record points at, for building a reference graph: reply
+    /// parent and root and quoted record of a post, subject of a like or a repost, list of a
+    /// list item (and its subject, as an `at://{did}` URI), post of a threadgate or a postgate,
+    /// list of a starter pack.
+    pub fn referenced_uris(&self) -> Vec<String> {
+        let mut uris = Vec::new();
+        match self {
+            Record::Post(post) => {
+                if let Some(reply) = &post.reply {
+                    uris.push(reply.parent.uri.clone());
+                    uris.push(reply.root.uri.clone());
+                }
+                uris.extend(self.quoted_uri().map(str::to_string));
+            }
+            Record::Like(_) | Record::Repost(_) => {
+                uris.extend(self.subject_uri().map(str::to_string));
+            }
+            Record::Listitem(item) => {
+                uris.push(item.list.clone());
+                uris.push(format!("at://{}", item.subject.as_str()));
+            }
+            Record::Threadgate(threadgate) => uris.push(threadgate.post.clone()),
+            Record::Postgate(postgate) => uris.push(postgate.post.clone()),
+            Record::Starterpack(starterpack) => uris.push(starterpack.list.clone()),
+            _ => {}
+        }
+        uris
+    }
+    /// AT URI of the first post of the thread a post belongs to: the root of its reply refs,
+    /// or the post itself, written at `rkey` in the `did` repository, when it is not a reply.
+    pub fn thread_root_uri(&self, did: &Did, rkey: &str) -> Option<String> {
+        let Record::Post(post) = self else {
+            return None;
+        };
+        Some(match &post.reply {
+            Some(reply) => reply.root.uri.clone(),
+            None => format!("at://{}/app.bsky.feed.post/{rkey}", did.as_str()),
+        })
+    }
+    /// Root and parent of a reply, `None` for any other record.
+    pub fn reply_refs(&self) -> Option<ReplyRefs> {
+        let Record::Post(post) = self else {
+            return None;
+        };
+        let reply = post.reply.as_ref()?;
+        let post_ref = |strong_ref: &StrongRef| PostRef {
+            uri: strong_ref.uri.clone(),
+            cid: *strong_ref.cid.as_ref(),
+        };
+        Some(ReplyRefs {
+            root: post_ref(&reply.root),
+            parent: post_ref(&reply.parent),
+        })
+    }
+    /// Whether a post replies to a post of the `did` repository, its author's when `did` is
+    /// the repository of the commit: the post then continues a self-thread.
+    pub fn is_self_reply(&self, did: &Did) -> bool {
+        let Record::Post(post) = self else {
+            return false;
+        };
+        post.reply
+            .as_ref()
+            .and_then(|reply| AtUri::parse(&reply.parent.uri))
+            .is_some_and(|parent| parent.did() == Some(did.as_str()))
+    }
+    /// Deserialize a [`Record::Unknown`] into the type of a lexicon this crate does not know
+    /// about, from the IPLD kept when decoding it.
+    pub fn deserialize_unknown<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        match self {
+            Record::Unknown(ipld) => {
+                ipld_core::serde::from_ipld(ipld.clone()).map_err(Error::UnknownRecordDecodeError)
+            }
+            _ => Err(Error::NotAnUnknownRecord(self.kind())),
+        }
+    }
+    /// Reply rules of a threadgate record, `None` for any other record.
+    pub fn allowed_replies(&self) -> Option<ReplyGate> {
+        let Record::Threadgate(threadgate) = self else {
+            return None;
+        };
+        let Some(allow) = &threadgate.allow else {
+            return Some(ReplyGate::Anyone);
+        };
+        Some(ReplyGate::Restricted(
+            allow
+                .iter()
+                .map(|item| match item {
+                    Union::Refs(RecordAllowItem::MentionRule(_)) => ReplyRule::Mentioned,
+                    Union::Refs(RecordAllowItem::FollowingRule(_)) => ReplyRule::Following,
+                    Union::Refs(RecordAllowItem::FollowerRule(_)) => ReplyRule::Followers,
+                    Union::Refs(RecordAllowItem::ListRule(rule)) => {
+                        ReplyRule::List(rule.list.clone())
+                    }
+                    Union::Unknown(unknown) => ReplyRule::Unknown(unknown.r#type.clone()),
+                })
+                .collect(),
+        ))
+    }
+    /// AT URIs of replies hidden by a threadgate record.
+    pub fn hidden_replies(&self) -> Option<&[String]> {
+        match self {
+            Record::Threadgate(threadgate) => {
+                Some(threadgate.hidden_replies.as_deref().unwrap_or_default())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl Record {
+    /// The record as DAG-JSON, the JSON encoding of the ATProto wire format: links become
+    /// `{"$link": "<cid>"}` and bytes `{"$bytes": "<base64>"}`, where serializing a
+    /// [`Record::Unknown`] with serde would not tell them apart from lists and maps.
+    pub fn to_dag_json(&self) -> serde_json::Value {
+        match self {
+            Record::Unknown(ipld) => dag_json(ipld),
+            _ => ipld_core::serde::to_ipld(self)
+                .map(|ipld| dag_json(&ipld))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+fn dag_json(ipld: &Ipld) -> serde_json::Value {
+    use serde_json::{Number, Value, json};
+
+    match ipld {
+        Ipld::Null => Value::Null,
+        Ipld::Bool(b) => Value::Bool(*b),
+        Ipld::Integer(i) => Number::from_i128(*i).map_or(Value::Null, Value::Number),
+        Ipld::Float(f) => Number::from_f64(*f).map_or(Value::Null, Value::Number),
+        Ipld::String(s) => Value::String(s.clone()),
+        Ipld::Bytes(bytes) => {
+            // unpadded base64, without the multibase prefix
+            let base64 = cid::multibase::encode(cid::multibase::Base::Base64, bytes);
+            json!({ "$bytes": &base64[1..] })
+        }
+        Ipld::List(list) => Value::Array(list.iter().map(dag_json).collect()),
+        Ipld::Map(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| (key.clone(), dag_json(value)))
+                .collect(),
+        ),
+        Ipld::Link(cid) => json!({ "$link": cid.to_string() }),
+    }
+}
+
+fn video_blobs(video: &video::Main) -> Vec<&BlobRef> {
+    std::iter::once(&video.video)
+        .chain(video.captions.iter().flatten().map(|caption| &caption.file))
+        .collect()
+}
+
+/// `None` for legacy blob references holding an invalid CID.
+fn blob_cid(blob: &BlobRef) -> Option<Cid> {
+    match blob {
+        BlobRef::Typed(TypedBlobRef::Blob(blob)) => Some(blob.r#ref.0),
+        BlobRef::Untyped(blob) => blob.cid.parse().ok(),
+    }
+}
+
+fn image_embed(image: &images::Image) -> ImageEmbed {
+    let cid = match &image.image {
+        BlobRef::Typed(TypedBlobRef::Blob(blob)) => blob.r#ref.0.to_string(),
+        BlobRef::Untyped(blob) => blob.cid.clone(),
+    };
+    ImageEmbed {
+        cid,
+        alt: image.alt.clone(),
+        aspect_ratio: image
+            .aspect_ratio
+            .as_ref()
+            .map(|ratio| (ratio.width.get(), ratio.height.get())),
+    }
+}
+
+/// Whether a commit likes or reposts content of its own repository.
+pub fn is_self_interaction(message: &FirehoseMessage) -> bool {
+    let FirehoseMessage::Commit {
+        did, operations, ..
+    } = message
+    else {
+        return false;
+    };
+    operations
+        .iter()
+        .filter_map(|operation| operation.record()?.subject_uri())
+        .filter_map(AtUri::parse)
+        .any(|subject| subject.did() == Some(did.as_str()))
+}
+
+/// Fields left out by [`records_equivalent`]: they may differ when a record is re-emitted.
+const VOLATILE_FIELDS: &[&str] = &["createdAt"];
+
+/// Whether two records hold the same content, ignoring volatile fields like `createdAt`.
+///
+/// This is best effort: records of different kinds are never equivalent, records failing to
+/// serialize neither.
+pub fn records_equivalent(a: &Record, b: &Record) -> bool {
+    if std::mem::discriminant(a) != std::mem::discriminant(b) {
+        return false;
+    }
+    match (semantic_ipld(a), semantic_ipld(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Hash of the content of a record, equal for [equivalent](records_equivalent) records: a
+/// deduplication key, stable across processes and releases of this crate.
+///
+/// This is not the CID of the record, which covers every field: the hash is the first 8
+/// bytes of the SHA-256 of the collection and of the DAG-CBOR encoding of the record without
+/// its volatile fields. Records failing to serialize only hash their collection.
+pub fn record_content_hash(record: &Record) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(record.nsid().unwrap_or_default());
+    hasher.update([0]);
+    if let Some(ipld) = semantic_ipld(record)
+        && let Ok(cbor) = serde_ipld_dagcbor::to_vec(&ipld)
+    {
+        hasher.update(cbor);
+    }
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+fn semantic_ipld(record: &Record) -> Option<Ipld> {
+    let mut ipld = ipld_core::serde::to_ipld(record).ok()?;
+    if let Ipld::Map(map) = &mut ipld {
+        for field in VOLATILE_FIELDS {
+            map.remove(*field);
+        }
+    }
+    Some(ipld)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        FirehoseMessage,
+        test_util::{
+            CommitBuilder, TEST_DID, TEST_TIME, blob, cid_for, decode_single_record, ipld_map,
+            like_record, post_record, post_with, strong_ref,
+        },
+    };
+
+    #[test]
+    fn threadgate_rules() {
+        let post = format!("at://{TEST_DID}/app.bsky.feed.post/3kabc");
+        let list = format!("at://{TEST_DID}/app.bsky.graph.list/3klist");
+        let hidden = "at://did:plc:someoneelse/app.bsky.feed.post/3kreply";
+        let threadgate = ipld_map([
+            ("$type", Ipld::String("app.bsky.feed.threadgate".into())),
+            ("post", Ipld::String(post)),
+            ("createdAt", Ipld::String(TEST_TIME.into())),
+            (
+                "allow",
+                Ipld::List(vec![
+                    ipld_map([(
+                        "$type",
+                        Ipld::String("app.bsky.feed.threadgate#mentionRule".into()),
+                    )]),
+                    ipld_map([(
+                        "$type",
+                        Ipld::String("app.bsky.feed.threadgate#followingRule".into()),
+                    )]),
+                    ipld_map([
+                        (
+                            "$type",
+                            Ipld::String("app.bsky.feed.threadgate#listRule".into()),
+                        ),
+                        ("list", Ipld::String(list.clone())),
+                    ]),
+                ]),
+            ),
+            (
+                "hiddenReplies",
+                Ipld::List(vec![Ipld::String(hidden.into())]),
+            ),
+        ]);
+        let record = decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "app.bsky.feed.threadgate",
+            "3kabc",
+            threadgate,
+        ));
+
+        assert_eq!(
+            record.allowed_replies(),
+            Some(ReplyGate::Restricted(vec![
+                ReplyRule::Mentioned,
+                ReplyRule::Following,
+                ReplyRule::List(list),
+            ]))
+        );
+        assert_eq!(record.hidden_replies(), Some(&[hidden.to_string()][..]));
+    }
+
+    #[test]
+    fn blob_cids() {
+        let embed = ipld_map([
+            ("$type", Ipld::String("app.bsky.embed.images".into())),
+            (
+                "images",
+                Ipld::List(vec![
+                    ipld_map([
+                        ("alt", Ipld::String(String::new())),
+                        ("image", blob(b"first")),
+                    ]),
+                    ipld_map([
+                        ("alt", Ipld::String(String::new())),
+                        ("image", blob(b"second")),
+                    ]),
+                ]),
+            ),
+        ]);
+        let post = post_with("two pictures", [("embed", embed)]);
+        let post = decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "app.bsky.feed.post",
+            "3kabc",
+            post,
+        ));
+        assert_eq!(
+            post.blob_cids(),
+            vec![cid_for(b"first"), cid_for(b"second")]
+        );
+
+        let profile = decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "app.bsky.actor.profile",
+            "self",
+            ipld_map([
+                ("$type", Ipld::String("app.bsky.actor.profile".into())),
+                ("avatar", blob(b"avatar")),
+                ("banner", blob(b"banner")),
+            ]),
+        ));
+        assert_eq!(
+            profile.blob_cids(),
+            vec![cid_for(b"avatar"), cid_for(b"banner")]
+        );
+
+        let like = decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "app.bsky.feed.like",
+            "3kabc",
+            like_record(
+                "at://did:plc:other/app.bsky.feed.post/3kabc",
+                &cid_for(b"post"),
+            ),
+        ));
+        assert!(like.blob_cids().is_empty());
+    }
+
+    #[test]
+    fn post_timestamps() {
+        let commit_time = "2024-11-20T12:00:05.123Z";
+        let frame = CommitBuilder::new(TEST_DID)
+            .time(commit_time)
+            .create("app.bsky.feed.post", "3kabc", post_record("hello"))
+            .build();
+        let FirehoseMessage::Commit {
+            time, operations, ..
+        } = FirehoseMessage::try_from(frame).expect("failed to decode")
+        else {
+            panic!("expected a commit");
+        };
+        let timestamps = operations[0].timestamps(&time);
+        assert_eq!(
+            timestamps.record_created_at.as_ref().map(Datetime::as_str),
+            Some(TEST_TIME)
+        );
+        assert_eq!(timestamps.commit_time.as_str(), commit_time);
+    }
+
+    #[test]
+    fn equivalent_posts() {
+        let post = |text: &str, created_at: &str| {
+            decode_single_record(CommitBuilder::new(TEST_DID).create(
+                "app.bsky.feed.post",
+                "3kabc",
+                ipld_map([
+                    ("$type", Ipld::String("app.bsky.feed.post".into())),
+                    ("text", Ipld::String(text.into())),
+                    ("createdAt", Ipld::String(created_at.into())),
+                ]),
+            ))
+        };
+        let a = post("hello", "2024-11-20T12:00:00.000Z");
+        let b = post("hello", "2024-11-21T08:30:00.000Z");
+        let c = post("hello world", "2024-11-20T12:00:00.000Z");
+        assert!(records_equivalent(&a, &b));
+        assert!(!records_equivalent(&a, &c));
+
+        assert_eq!(record_content_hash(&a), record_content_hash(&b));
+        assert_ne!(record_content_hash(&a), record_content_hash(&c));
+    }
+
+    fn post_with_external_embed(uri: &str) -> Record {
+        let external = ipld_map([
+            ("uri", Ipld::String(uri.into())),
+            ("title", Ipld::String("title".into())),
+            ("description", Ipld::String("description".into())),
+        ]);
+        let embed = ipld_map([
+            ("$type", Ipld::String("app.bsky.embed.external".into())),
+            ("external", external),
+        ]);
+        let post = post_with("look", [("embed", embed)]);
+        decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "app.bsky.feed.post",
+            "3kabc",
+            post,
+        ))
+    }
+
+    #[test]
+    fn gif_embeds() {
+        let url = "https://media.tenor.com/9lhcR8vWnBMAAAAC/cat-dance.gif?hh=280&ww=498";
+        let gif = post_with_external_embed(url);
+        assert_eq!(gif.external_embed().map(|e| e.uri.as_str()), Some(url));
+        assert_eq!(
+            gif.gif_embed(),
+            Some(Gif {
+                url: url.to_string(),
+                width: Some(498),
+                height: Some(280),
+            })
+        );
+
+        let link = post_with_external_embed("https://example.com/article.html");
+        assert!(link.external_embed().is_some());
+        assert_eq!(link.gif_embed(), None);
+    }
+
+    #[test]
+    fn image_aspect_ratios() {
+        let image = |alt: &str, data: &[u8], aspect_ratio: Option<(i128, i128)>| {
+            let mut image = ipld_map([("alt", Ipld::String(alt.into())), ("image", blob(data))]);
+            if let (Ipld::Map(map), Some((width, height))) = (&mut image, aspect_ratio) {
+                let ratio = ipld_map([
+                    ("width", Ipld::Integer(width)),
+                    ("height", Ipld::Integer(height)),
+                ]);
+                map.insert("aspectRatio".to_string(), ratio);
+            }
+            image
+        };
+        let embed = ipld_map([
+            ("$type", Ipld::String("app.bsky.embed.images".into())),
+            (
+                "images",
+                Ipld::List(vec![
+                    image("landscape", b"first", Some((1200, 800))),
+                    image("", b"second", None),
+                ]),
+            ),
+        ]);
+        let post = post_with("two pictures", [("embed", embed)]);
+        let record = decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "app.bsky.feed.post",
+            "3kabc",
+            post,
+        ));
+
+        assert_eq!(
+            record.image_embeds(),
+            vec![
+                ImageEmbed {
+                    cid: cid_for(b"first").to_string(),
+                    alt: "landscape".to_string(),
+                    aspect_ratio: Some((1200, 800)),
+                },
+                ImageEmbed {
+                    cid: cid_for(b"second").to_string(),
+                    alt: String::new(),
+                    aspect_ratio: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn post_metadata() {
+        let string = |value: &str| Ipld::String(value.into());
+        let label = ipld_map([("val", string("graphic-media"))]);
+        let labels = ipld_map([
+            ("$type", string("com.atproto.label.defs#selfLabels")),
+            ("values", Ipld::List(vec![label])),
+        ]);
+        let facet = ipld_map([
+            (
+                "index",
+                ipld_map([
+                    ("byteStart", Ipld::Integer(6)),
+                    ("byteEnd", Ipld::Integer(11)),
+                ]),
+            ),
+            (
+                "features",
+                Ipld::List(vec![ipld_map([
+                    ("$type", string("app.bsky.richtext.facet#tag")),
+                    ("tag", string("rust")),
+                ])]),
+            ),
+        ]);
+        let post = post_with(
+            "hello #rust",
+            [
+                ("langs", Ipld::List(vec![string("en"), string("fr")])),
+                ("labels", labels),
+                ("tags", Ipld::List(vec![string("programming")])),
+                ("facets", Ipld::List(vec![facet])),
+            ],
+        );
+        let record = decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "app.bsky.feed.post",
+            "3kabc",
+            post,
+        ));
+
+        let metadata = record.post_metadata().unwrap();
+        assert_eq!(
+            ipld_core::serde::to_ipld(&metadata.langs).unwrap(),
+            Ipld::List(vec![string("en"), string("fr")])
+        );
+        assert_eq!(metadata.self_labels, vec!["graphic-media"]);
+        assert_eq!(metadata.tags, vec!["programming"]);
+        assert_eq!(metadata.facet_tags, vec!["rust"]);
+
+        let plain = decode_single_record(CommitBuilder::new(TEST_DID).create_post("3kdef", "hi"));
+        assert_eq!(plain.post_metadata(), Some(PostMetadata::default()));
+    }
+
+    #[test]
+    fn legacy_entities() {
+        let string = |value: &str| Ipld::String(value.into());
+        let entity = |start, end, kind: &str, value: &str| {
+            ipld_map([
+                (
+                    "index",
+                    ipld_map([("start", Ipld::Integer(start)), ("end", Ipld::Integer(end))]),
+                ),
+                ("type", string(kind)),
+                ("value", string(value)),
+            ])
+        };
+        let post = post_with(
+            "@alice.test see https://example.com",
+            [(
+                "entities",
+                Ipld::List(vec![
+                    entity(0, 11, "mention", "did:plc:alice"),
+                    entity(16, 35, "link", "https://example.com"),
+                ]),
+            )],
+        );
+        let record = decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "app.bsky.feed.post",
+            "3kabc",
+            post,
+        ));
+        assert_eq!(
+            record.facets_summary(),
+            Some(FacetsSummary {
+                mentions: vec!["did:plc:alice".to_string()],
+                links: vec!["https://example.com".to_string()],
+                tags: Vec::new(),
+            })
+        );
+
+        let plain = decode_single_record(CommitBuilder::new(TEST_DID).create_post("3kdef", "hi"));
+        assert_eq!(plain.facets_summary(), Some(FacetsSummary::default()));
+    }
+
+    #[test]
+    fn thread_roots() {
+        let did = Did::new(TEST_DID.to_string()).unwrap();
+        let root = format!("at://{TEST_DID}/app.bsky.feed.post/3kroot");
+        let parent = "at://did:plc:someoneelse/app.bsky.feed.post/3kparent";
+        let reply = post_with(
+            "me too",
+            [(
+                "reply",
+                ipld_map([
+                    ("root", strong_ref(&root, &cid_for(b"root"))),
+                    ("parent", strong_ref(parent, &cid_for(b"parent"))),
+                ]),
+            )],
+        );
+        let record = decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "app.bsky.feed.post",
+            "3kreply",
+            reply,
+        ));
+        assert_eq!(record.thread_root_uri(&did, "3kreply"), Some(root.clone()));
+
+        let record =
+            decode_single_record(CommitBuilder::new(TEST_DID).create_post("3kroot", "hello"));
+        assert_eq!(record.thread_root_uri(&did, "3kroot"), Some(root));
+    }
+
+    #[test]
+    fn referenced_uris() {
+        let root = "at://did:plc:someoneelse/app.bsky.feed.post/3kroot";
+        let parent = "at://did:plc:someoneelse/app.bsky.feed.post/3kparent";
+        let quoted = "at://did:plc:thirdparty/app.bsky.feed.post/3kquoted";
+        let post = post_with(
+            "replying with a quote",
+            [
+                (
+                    "reply",
+                    ipld_map([
+                        ("root", strong_ref(root, &cid_for(b"root"))),
+                        ("parent", strong_ref(parent, &cid_for(b"parent"))),
+                    ]),
+                ),
+                (
+                    "embed",
+                    ipld_map([
+                        ("$type", Ipld::String("app.bsky.embed.record".into())),
+                        ("record", strong_ref(quoted, &cid_for(b"quoted"))),
+                    ]),
+                ),
+            ],
+        );
+        let record = decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "app.bsky.feed.post",
+            "3kreply",
+            post,
+        ));
+        assert_eq!(record.quoted_uri(), Some(quoted));
+        assert_eq!(record.referenced_uris(), vec![parent, root, quoted]);
+
+        let like = decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "app.bsky.feed.like",
+            "3kabc",
+            like_record(quoted, &cid_for(b"quoted")),
+        ));
+        assert_eq!(like.referenced_uris(), vec![quoted]);
+    }
+
+    #[test]
+    fn unknown_records_into_custom_types() {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Thing {
+            name: String,
+            count: u32,
+        }
+
+        let record = decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "com.example.thing",
+            "3kabc",
+            ipld_map([
+                ("$type", Ipld::String("com.example.thing".into())),
+                ("name", Ipld::String("gizmo".into())),
+                ("count", Ipld::Integer(3)),
+            ]),
+        ));
+        assert_eq!(
+            record.deserialize_unknown::<Thing>().unwrap(),
+            Thing {
+                name: "gizmo".to_string(),
+                count: 3
+            }
+        );
+
+        let post = decode_single_record(CommitBuilder::new(TEST_DID).create_post("3kabc", "hi"));
+        assert!(matches!(
+            post.deserialize_unknown::<Thing>(),
+            Err(Error::NotAnUnknownRecord(crate::RecordKind::Post))
+        ));
+    }
+
+    #[test]
+    fn self_replies() {
+        let did = Did::new(TEST_DID.to_string()).unwrap();
+        let reply_to = |parent_did: &str| {
+            let root = format!("at://{parent_did}/app.bsky.feed.post/3kroot");
+            let reply = post_with(
+                "and another thing",
+                [(
+                    "reply",
+                    ipld_map([
+                        ("root", strong_ref(&root, &cid_for(b"root"))),
+                        ("parent", strong_ref(&root, &cid_for(b"root"))),
+                    ]),
+                )],
+            );
+            decode_single_record(CommitBuilder::new(TEST_DID).create(
+                "app.bsky.feed.post",
+                "3kreply",
+                reply,
+            ))
+        };
+        assert!(reply_to(TEST_DID).is_self_reply(&did));
+        assert!(!reply_to("did:plc:someoneelse").is_self_reply(&did));
+
+        let post = decode_single_record(CommitBuilder::new(TEST_DID).create_post("3kabc", "hi"));
+        assert!(!post.is_self_reply(&did));
+    }
+
+    #[test]
+    fn self_interactions() {
+        let like = |subject_did: &str| {
+            let subject = format!("at://{subject_did}/app.bsky.feed.post/3kabc");
+            let frame = CommitBuilder::new(TEST_DID)
+                .create(
+                    "app.bsky.feed.like",
+                    "3klike",
+                    like_record(&subject, &cid_for(b"post")),
+                )
+                .build();
+            FirehoseMessage::try_from(frame).expect("failed to decode")
+        };
+        assert!(is_self_interaction(&like(TEST_DID)));
+        assert!(!is_self_interaction(&like("did:plc:someoneelse")));
+
+        let post = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .build();
+        assert!(!is_self_interaction(
+            &FirehoseMessage::try_from(post).unwrap()
+        ));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn unknown_records_as_dag_json() {
+        use serde_json::json;
+
+        let cid = cid_for(b"blob");
+        let record = Record::Unknown(ipld_map([
+            ("$type", Ipld::String("com.example.thing".into())),
+            ("ref", Ipld::Link(cid)),
+            ("data", Ipld::Bytes(vec![1, 2, 3])),
+            ("tags", Ipld::List(vec![Ipld::Integer(1), Ipld::Null])),
+        ]));
+        assert_eq!(
+            record.to_dag_json(),
+            json!({
+                "$type": "com.example.thing",
+                "ref": { "$link": cid.to_string() },
+                "data": { "$bytes": "AQID" },
+                "tags": [1, null],
+            })
+        );
+
+        let post = decode_single_record(CommitBuilder::new(TEST_DID).create_post("3kabc", "hi"));
+        assert_eq!(post.to_dag_json()["text"], json!("hi"));
+    }
+}
@@ -0,0 +1,155 @@
+//! DID resolution, with a caching layer.
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use atrium_api::types::string::Did;
+use lru::LruCache;
+use tokio::time::Instant;
+
+/// Resolve a DID to its document (typically an `atrium_api::did_doc::DidDocument`).
+pub trait DidResolver {
+    type Document: Clone;
+    type Error;
+
+    fn resolve(
+        &self,
+        did: &Did,
+    ) -> impl Future<Output = Result<Self::Document, Self::Error>> + Send;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Cache the documents of a [`DidResolver`] for `ttl`, keeping at most `capacity` of them
+/// (least recently used are evicted first).
+pub struct CachedDidResolver<R: DidResolver> {
+    inner: R,
+    ttl: Duration,
+    cache: Mutex<LruCache<String, (Instant, R::Document)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<R: DidResolver> CachedDidResolver<R> {
+    pub fn new(inner: R, ttl: Duration, capacity: NonZeroUsize) -> Self {
+        CachedDidResolver {
+            inner,
+            ttl,
+            cache: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+    /// Forget the cached document of `did`, e.g. after an `#identity` event.
+    pub fn invalidate(&self, did: &Did) {
+        self.cache.lock().unwrap().pop(did.as_str());
+    }
+
+    fn cached(&self, did: &Did) -> Option<R::Document> {
+        let mut cache = self.cache.lock().unwrap();
+        let (resolved_at, document) = cache.get(did.as_str())?;
+        if resolved_at.elapsed() < self.ttl {
+            return Some(document.clone());
+        }
+        cache.pop(did.as_str());
+        None
+    }
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "prometheus")]
+        crate::metrics::DID_CACHE_COUNTER
+            .with_label_values(&[if hit { "hit" } else { "miss" }])
+            .inc();
+    }
+}
+
+impl<R> DidResolver for CachedDidResolver<R>
+where
+    R: DidResolver + Sync,
+    R::Document: Send + Sync,
+{
+    type Document = R::Document;
+    type Error = R::Error;
+
+    fn resolve(
+        &self,
+        did: &Did,
+    ) -> impl Future<Output = Result<Self::Document, Self::Error>> + Send {
+        async move {
+            if let Some(document) = self.cached(did) {
+                self.record(true);
+                return Ok(document);
+            }
+            self.record(false);
+            let document = self.inner.resolve(did).await?;
+            self.cache
+                .lock()
+                .unwrap()
+                .put(did.as_str().to_string(), (Instant::now(), document.clone()));
+            Ok(document)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::atomic::AtomicUsize};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingResolver {
+        calls: AtomicUsize,
+    }
+
+    impl DidResolver for CountingResolver {
+        type Document = String;
+        type Error = Infallible;
+
+        fn resolve(
+            &self,
+            did: &Did,
+        ) -> impl Future<Output = Result<Self::Document, Self::Error>> + Send {
+            let calls = self.calls.fetch_add(1, Ordering::Relaxed) + 1;
+            let document = format!("{} #{calls}", did.as_str());
+            async move { Ok(document) }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cached_until_ttl() {
+        let resolver = CachedDidResolver::new(
+            CountingResolver::default(),
+            Duration::from_secs(60),
+            NonZeroUsize::new(10).unwrap(),
+        );
+        let did = Did::new("did:plc:ewvi7nxzyoun6zhxrhs64oiz".to_string()).unwrap();
+
+        let first = resolver.resolve(&did).await.unwrap();
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(resolver.resolve(&did).await.unwrap(), first);
+        assert_eq!(resolver.stats(), CacheStats { hits: 1, misses: 1 });
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert_ne!(resolver.resolve(&did).await.unwrap(), first);
+        assert_eq!(resolver.stats(), CacheStats { hits: 1, misses: 2 });
+        assert_eq!(resolver.inner.calls.load(Ordering::Relaxed), 2);
+    }
+}
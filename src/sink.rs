@@ -0,0 +1,223 @@
+//! Write decoded messages to newline delimited JSON files.
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+pub use crate::archive::Rotation;
+use crate::{RecordKind, event::OperationEvent};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("JSON serialization error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Serialize items as JSON, one per line.
+pub struct JsonlSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonlSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonlSink { writer }
+    }
+    /// Write `item` and return the number of bytes written, newline included.
+    pub fn write<T: Serialize>(&mut self, item: &T) -> Result<usize, Error> {
+        let mut line = serde_json::to_vec(item)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        Ok(line.len())
+    }
+    pub fn flush(&mut self) -> Result<(), Error> {
+        Ok(self.writer.flush()?)
+    }
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+struct Partition {
+    sink: JsonlSink<BufWriter<File>>,
+    bytes: u64,
+    opened_at: Instant,
+    segment: u64,
+}
+
+/// Route operation events to one NDJSON file per collection.
+///
+/// Files are named `{collection}.{started}.{segment}.ndjson` in `dir`, `started` being the unix
+/// timestamp of the sink creation and `segment` incremented on each rotation.
+///
+/// Collections are chosen by any PDS: those without a [`RecordKind`] share the `other`
+/// partition, so that the number of open files stays bounded.
+pub struct PartitionedJsonlSink {
+    dir: PathBuf,
+    rotation: Rotation,
+    started: u64,
+    partitions: HashMap<String, Partition>,
+}
+
+impl PartitionedJsonlSink {
+    pub fn new(dir: impl Into<PathBuf>, rotation: Rotation) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(PartitionedJsonlSink {
+            dir,
+            rotation,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            partitions: HashMap::new(),
+        })
+    }
+
+    pub fn write(&mut self, event: &OperationEvent) -> Result<(), Error> {
+        let collection = RecordKind::of_collection(event.collection())
+            .nsid()
+            .unwrap_or("other");
+        let segment = match self.partitions.get_mut(collection) {
+            Some(partition) if !self.rotation.is_due(partition.bytes, partition.opened_at) => {
+                partition.bytes += partition.sink.write(event)? as u64;
+                return Ok(());
+            }
+            Some(partition) => {
+                partition.sink.flush()?;
+                partition.segment + 1
+            }
+            None => 0,
+        };
+        let path = self
+            .dir
+            .join(format!("{collection}.{}.{segment}.ndjson", self.started));
+        let mut partition = Partition {
+            sink: JsonlSink::new(BufWriter::new(File::create(path)?)),
+            bytes: 0,
+            opened_at: Instant::now(),
+            segment,
+        };
+        partition.bytes += partition.sink.write(event)? as u64;
+        self.partitions.insert(collection.to_string(), partition);
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        for partition in self.partitions.values_mut() {
+            partition.sink.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ipld_core::ipld::Ipld;
+
+    use super::*;
+    use crate::{
+        FirehoseMessage,
+        test_util::{CommitBuilder, TEST_DID, cid_for, ipld_map, like_record},
+    };
+
+    #[test]
+    fn collections_land_in_separate_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = PartitionedJsonlSink::new(dir.path(), Rotation::default()).unwrap();
+
+        let post_uri = format!("at://{TEST_DID}/app.bsky.feed.post/3kabc");
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .create(
+                "app.bsky.feed.like",
+                "3kdef",
+                like_record(&post_uri, &cid_for(b"post")),
+            )
+            .build();
+        for event in FirehoseMessage::try_from(frame)
+            .unwrap()
+            .into_operation_events()
+        {
+            sink.write(&event).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let mut files = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files.len(), 2);
+        let like = fs::read_to_string(&files[0]).unwrap();
+        let post = fs::read_to_string(&files[1]).unwrap();
+        assert!(files[0].to_string_lossy().contains("app.bsky.feed.like"));
+        assert!(like.contains("\"rkey\":\"3kdef\""));
+        assert_eq!(like.lines().count(), 1);
+        assert!(files[1].to_string_lossy().contains("app.bsky.feed.post"));
+        assert!(post.contains("\"text\":\"hello\""));
+        assert_eq!(post.lines().count(), 1);
+    }
+
+    #[test]
+    fn unknown_collections_share_a_partition() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = PartitionedJsonlSink::new(dir.path(), Rotation::default()).unwrap();
+        let mut builder = CommitBuilder::new(TEST_DID);
+        for collection in ["com.example.thing", "com.example.other"] {
+            let record = ipld_map([("$type", Ipld::String(collection.into()))]);
+            builder = builder.create(collection, "3kabc", record);
+        }
+        for event in FirehoseMessage::try_from(builder.build())
+            .unwrap()
+            .into_operation_events()
+        {
+            sink.write(&event).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let files = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        assert!(
+            files[0]
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("other.")
+        );
+        assert_eq!(fs::read_to_string(&files[0]).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn partitions_rotate_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = PartitionedJsonlSink::new(
+            dir.path(),
+            Rotation {
+                max_bytes: Some(1),
+                max_age: None,
+            },
+        )
+        .unwrap();
+        for rkey in ["3ka", "3kb"] {
+            let frame = CommitBuilder::new(TEST_DID).create_post(rkey, "hi").build();
+            for event in FirehoseMessage::try_from(frame)
+                .unwrap()
+                .into_operation_events()
+            {
+                sink.write(&event).unwrap();
+            }
+        }
+        sink.flush().unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,112 @@
+//! Apply commits to a mirror of the repositories.
+use atrium_api::types::string::Did;
+
+use crate::{FirehoseMessage, Operation, Record};
+
+/// Records of the mirrored repositories, keyed by DID, collection and rkey.
+pub trait RepoStore {
+    type Error;
+
+    /// Insert or replace a record.
+    fn put(
+        &mut self,
+        did: &Did,
+        collection: &str,
+        rkey: &str,
+        record: &Record,
+        cid: &str,
+    ) -> Result<(), Self::Error>;
+    /// Remove a record, deleting a missing record is not an error.
+    fn delete(&mut self, did: &Did, collection: &str, rkey: &str) -> Result<(), Self::Error>;
+}
+
+/// Apply the operations of a commit to `store`, in order, stopping at the first failure.
+///
+/// Other messages are ignored. Operations dropped while decoding (see
+/// [`DecodeMode::Lenient`](crate::DecodeMode::Lenient)) are not applied either: the mirror
+/// may then drift from the repository.
+pub fn apply_to<S: RepoStore>(message: &FirehoseMessage, store: &mut S) -> Result<(), S::Error> {
+    let FirehoseMessage::Commit {
+        did, operations, ..
+    } = message
+    else {
+        return Ok(());
+    };
+    for operation in operations {
+        match operation {
+            Operation::Create {
+                operation_meta,
+                record,
+                cid,
+                ..
+            }
+            | Operation::Update {
+                operation_meta,
+                record,
+                cid,
+                ..
+            } => store.put(
+                did,
+                &operation_meta.collection,
+                &operation_meta.rkey,
+                record,
+                cid,
+            )?,
+            Operation::Delete(operation_meta) => {
+                store.delete(did, &operation_meta.collection, &operation_meta.rkey)?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, convert::Infallible};
+
+    use super::*;
+    use crate::test_util::{CommitBuilder, TEST_DID};
+
+    #[derive(Default)]
+    struct MemoryStore(HashMap<(String, String, String), String>);
+
+    impl RepoStore for MemoryStore {
+        type Error = Infallible;
+
+        fn put(
+            &mut self,
+            did: &Did,
+            collection: &str,
+            rkey: &str,
+            _record: &Record,
+            cid: &str,
+        ) -> Result<(), Infallible> {
+            let key = (did.to_string(), collection.to_string(), rkey.to_string());
+            self.0.insert(key, cid.to_string());
+            Ok(())
+        }
+        fn delete(&mut self, did: &Did, collection: &str, rkey: &str) -> Result<(), Infallible> {
+            let key = (did.to_string(), collection.to_string(), rkey.to_string());
+            self.0.remove(&key);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn create_then_delete() {
+        let mut store = MemoryStore::default();
+        let created = CommitBuilder::new(TEST_DID)
+            .create_post("3kabc", "hello")
+            .create_post("3kdef", "world")
+            .build();
+        apply_to(&FirehoseMessage::try_from(created).unwrap(), &mut store).unwrap();
+        assert_eq!(store.0.len(), 2);
+
+        let deleted = CommitBuilder::new(TEST_DID)
+            .delete("app.bsky.feed.post", "3kabc")
+            .delete("app.bsky.feed.post", "3kdef")
+            .build();
+        apply_to(&FirehoseMessage::try_from(deleted).unwrap(), &mut store).unwrap();
+        assert!(store.0.is_empty());
+    }
+}
@@ -0,0 +1,626 @@
+//! Adapters over streams of frames and decoded messages.
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    num::{NonZeroU32, NonZeroUsize},
+    sync::Arc,
+    time::Duration,
+};
+
+use atrium_api::types::string::Did;
+use futures::{Stream, StreamExt, future, stream};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{Instant, interval_at, sleep_until},
+};
+
+use crate::{
+    DecodeOptions, Error, FirehoseMessage, Operation,
+    cardinality::DistinctDidCounter,
+    event::{OperationBatch, OperationEvent},
+    frame::Frame,
+    partition::partition,
+    rate::{PostingRateTracker, RateFlag},
+};
+
+/// Frames waiting to be decoded, and decoded messages waiting to be consumed, per worker.
+const DECODE_QUEUE_SIZE: usize = 64;
+
+pub trait FrameStreamExt: Stream<Item = Frame> + Sized + Send + 'static {
+    /// Decode frames on `workers` tasks.
+    ///
+    /// Frames are assigned to workers by [`partition`] of their DID: the messages of a
+    /// repository keep their order, messages of different repositories may be reordered.
+    /// Must be called within a tokio runtime.
+    fn decode_concurrently(
+        self,
+        workers: NonZeroUsize,
+        options: DecodeOptions,
+    ) -> impl Stream<Item = Result<FirehoseMessage, Error>> {
+        let options = Arc::new(options);
+        let (queues, outputs): (Vec<_>, Vec<_>) = (0..workers.get())
+            .map(|_| {
+                let (frame_tx, mut frame_rx) = mpsc::channel::<Frame>(DECODE_QUEUE_SIZE);
+                let (message_tx, message_rx) = mpsc::channel(DECODE_QUEUE_SIZE);
+                let options = options.clone();
+                tokio::spawn(async move {
+                    while let Some(frame) = frame_rx.recv().await {
+                        let message = FirehoseMessage::try_from_limited(frame, &options).await;
+                        if message_tx.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                let output = stream::unfold(message_rx, |mut message_rx| async move {
+                    let message = message_rx.recv().await?;
+                    Some((message, message_rx))
+                });
+                (frame_tx, Box::pin(output))
+            })
+            .unzip();
+        tokio::spawn(async move {
+            let mut frames = std::pin::pin!(self);
+            while let Some(frame) = frames.next().await {
+                let worker = partition(frame.did().as_deref().unwrap_or_default(), queues.len());
+                if queues[worker].send(frame).await.is_err() {
+                    break;
+                }
+            }
+        });
+        stream::select_all(outputs)
+    }
+}
+
+impl<S: Stream<Item = Frame> + Send + 'static> FrameStreamExt for S {}
+
+pub trait FirehoseStreamExt: Stream<Item = FirehoseMessage> + Sized {
+    /// Flatten commits into one event per operation.
+    fn operation_events(self) -> impl Stream<Item = OperationEvent> {
+        self.flat_map(|message| stream::iter(message.into_operation_events()))
+    }
+    /// Every `period`, the approximate number of distinct repositories seen in messages during
+    /// the last `window`, see [`DistinctDidCounter`].
+    ///
+    /// Must be called within a tokio runtime.
+    fn distinct_dids(self, window: Duration, period: Duration) -> impl Stream<Item = u64> {
+        let ticks = interval_at(Instant::now() + period, period);
+        let state = (Box::pin(self), ticks, DistinctDidCounter::new(window));
+        stream::unfold(state, |(mut messages, mut ticks, mut counter)| async move {
+            loop {
+                tokio::select! {
+                    // ticks first, a busy stream must not delay the estimates
+                    biased;
+                    _ = ticks.tick() => {
+                        let estimate = counter.estimate(Instant::now());
+                        return Some((estimate, (messages, ticks, counter)));
+                    }
+                    message = messages.next() => {
+                        if let Some(did) = message?.did() {
+                            counter.insert(did, Instant::now());
+                        }
+                    }
+                }
+            }
+        })
+    }
+    /// Flag repositories creating more than `posts_per_minute` posts, tracking at most
+    /// `max_dids` of them, see [`PostingRateTracker`].
+    fn posting_rate_flags(
+        self,
+        posts_per_minute: NonZeroU32,
+        max_dids: NonZeroUsize,
+    ) -> impl Stream<Item = RateFlag> {
+        let mut tracker = PostingRateTracker::new(posts_per_minute, max_dids);
+        self.filter_map(move |message| future::ready(tracker.observe(&message, Instant::now())))
+    }
+    /// Group the operations of each repository received within `window` of its first one
+    /// into a single [`OperationBatch`], emitted once the window is over.
+    ///
+    /// Only repositories with a pending batch are tracked: a repository is forgotten as soon
+    /// as its batch is emitted. Pending batches are flushed when the stream ends.
+    /// Must be called within a tokio runtime.
+    fn coalesce_by_did(self, window: Duration) -> impl Stream<Item = OperationBatch> {
+        struct State<M> {
+            messages: M,
+            done: bool,
+            batches: HashMap<Did, Vec<OperationEvent>>,
+            /// Repositories with a pending batch, by deadline.
+            deadlines: VecDeque<(Instant, Did)>,
+        }
+        let state = State {
+            messages: Box::pin(self),
+            done: false,
+            batches: HashMap::new(),
+            deadlines: VecDeque::new(),
+        };
+        stream::unfold(state, move |mut state| async move {
+            loop {
+                let next_deadline = state.deadlines.front().map(|(deadline, _)| *deadline);
+                if let Some(deadline) = next_deadline
+                    && (state.done || deadline <= Instant::now())
+                {
+                    let (_, did) = state.deadlines.pop_front()?;
+                    let events = state.batches.remove(&did).unwrap_or_default();
+                    return Some((OperationBatch { did, events }, state));
+                }
+                if state.done {
+                    return None;
+                }
+                tokio::select! {
+                    biased;
+                    _ = sleep_until(next_deadline.unwrap_or_else(Instant::now)),
+                        if next_deadline.is_some() => {}
+                    message = state.messages.next() => {
+                        let Some(message) = message else {
+                            state.done = true;
+                            continue;
+                        };
+                        let events = message.into_operation_events();
+                        let Some(did) = events.first().map(|event| event.did.clone()) else {
+                            continue;
+                        };
+                        state
+                            .batches
+                            .entry(did.clone())
+                            .or_insert_with(|| {
+                                state.deadlines.push_back((Instant::now() + window, did));
+                                Vec::new()
+                            })
+                            .extend(events);
+                    }
+                }
+            }
+        })
+    }
+    /// Group messages into batches of at most `max_items`, e.g. for bulk inserts: a batch is
+    /// emitted once full, or `max_delay` after its first message so that messages keep
+    /// flowing when traffic is low. The last batch is emitted when the stream ends.
+    ///
+    /// Must be called within a tokio runtime.
+    fn batched(
+        self,
+        max_items: NonZeroUsize,
+        max_delay: Duration,
+    ) -> impl Stream<Item = Vec<FirehoseMessage>> {
+        let state = (Box::pin(self), false);
+        stream::unfold(state, move |(mut messages, done)| async move {
+            if done {
+                return None;
+            }
+            let mut batch = vec![messages.next().await?];
+            let deadline = Instant::now() + max_delay;
+            let mut done = false;
+            while batch.len() < max_items.get() {
+                match tokio::time::timeout_at(deadline, messages.next()).await {
+                    Ok(Some(message)) => batch.push(message),
+                    Ok(None) => {
+                        done = true;
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            }
+            Some((batch, (messages, done)))
+        })
+    }
+    /// Tee the stream to several consumers, each receiving every message: the messages are
+    /// consumed on a task and kept in a buffer of `capacity` messages shared by the
+    /// subscribers, see [`Broadcast`].
+    ///
+    /// Must be called within a tokio runtime.
+    fn broadcast(self, capacity: NonZeroUsize) -> Broadcast
+    where
+        Self: Send + 'static,
+    {
+        let (sender, receiver) = broadcast::channel(capacity.get());
+        tokio::spawn(async move {
+            let mut messages = std::pin::pin!(self);
+            while let Some(message) = messages.next().await {
+                // without subscribers, the message is dropped
+                let _ = sender.send(Arc::new(message));
+            }
+        });
+        Broadcast { receiver }
+    }
+}
+
+impl<S: Stream<Item = FirehoseMessage>> FirehoseStreamExt for S {}
+
+/// What a [`Broadcast`] subscriber does when it falls more than the capacity behind the
+/// stream, the buffered messages it did not consume yet being overwritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LagPolicy {
+    /// Drop the overwritten messages, logging how many were missed, and resume from the
+    /// oldest buffered one.
+    #[default]
+    Skip,
+    /// End the subscriber stream.
+    End,
+}
+
+/// Messages of a stream teed by [`FirehoseStreamExt::broadcast`].
+///
+/// The buffer is shared: a slow subscriber does not slow down the stream nor the other
+/// subscribers, it lags behind and follows its [`LagPolicy`]. Subscribers only receive the
+/// messages sent after they subscribed, subscribe before the runtime gets a chance to run
+/// the stream task to receive all of them.
+pub struct Broadcast {
+    /// Never consumed, only kept to create subscribers.
+    receiver: broadcast::Receiver<Arc<FirehoseMessage>>,
+}
+
+impl Broadcast {
+    /// Subscribe to the messages, the stream ends with the broadcast one.
+    pub fn subscribe(&self, policy: LagPolicy) -> impl Stream<Item = Arc<FirehoseMessage>> {
+        stream::unfold(
+            self.receiver.resubscribe(),
+            move |mut receiver| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(message) => return Some((message, receiver)),
+                        Err(broadcast::error::RecvError::Lagged(missed)) => match policy {
+                            LagPolicy::Skip => {
+                                tracing::warn!(
+                                    "Broadcast subscriber lagged, {missed} messages skipped"
+                                )
+                            }
+                            LagPolicy::End => {
+                                tracing::warn!(
+                                    "Broadcast subscriber lagged by {missed} messages, ending"
+                                );
+                                return None;
+                            }
+                        },
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            },
+        )
+    }
+}
+
+pub trait OperationStreamExt: Stream<Item = OperationEvent> + Sized {
+    /// Only keep operations changing the social graph, see
+    /// [`Operation::is_graph_event`](crate::Operation::is_graph_event).
+    fn graph_events(self) -> impl Stream<Item = OperationEvent> {
+        self.filter(|event| future::ready(event.operation.is_graph_event()))
+    }
+    /// Only keep post creations whose text contains one of `keywords`.
+    fn text_contains(
+        self,
+        keywords: &[&str],
+        case_insensitive: bool,
+    ) -> impl Stream<Item = OperationEvent> {
+        let keywords = keywords
+            .iter()
+            .map(|keyword| {
+                if case_insensitive {
+                    keyword.to_lowercase()
+                } else {
+                    keyword.to_string()
+                }
+            })
+            .collect::<Vec<_>>();
+        self.filter(move |event| {
+            let Operation::Create { record, .. } = &event.operation else {
+                return future::ready(false);
+            };
+            let Some(text) = record.post_text() else {
+                return future::ready(false);
+            };
+            let text = if case_insensitive {
+                Cow::Owned(text.to_lowercase())
+            } else {
+                Cow::Borrowed(text)
+            };
+            future::ready(
+                keywords
+                    .iter()
+                    .any(|keyword| text.contains(keyword.as_str())),
+            )
+        })
+    }
+}
+
+impl<S: Stream<Item = OperationEvent>> OperationStreamExt for S {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ipld_core::ipld::Ipld;
+
+    use super::*;
+    use crate::test_util::{
+        CommitBuilder, TEST_DID, TEST_TIME, cid_for, follow_record, identity_frame, ipld_map,
+        like_record, post_record,
+    };
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_decoding_keeps_repository_order() {
+        let dids = (0..8)
+            .map(|i| format!("did:plc:repo{i}"))
+            .collect::<Vec<_>>();
+        let frames = (0..400).map(move |seq| {
+            let did = &dids[seq as usize % dids.len()];
+            if seq % 10 == 0 {
+                identity_frame(did, seq, None)
+            } else {
+                CommitBuilder::new(did)
+                    .seq(seq)
+                    .create_post("3kabc", &"a".repeat(seq as usize))
+                    .build()
+            }
+        });
+        let frame = frames.clone().next().unwrap();
+        assert_eq!(frame.did().as_deref(), Some("did:plc:repo0"));
+
+        let messages = stream::iter(frames)
+            .decode_concurrently(NonZeroUsize::new(4).unwrap(), DecodeOptions::default())
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(messages.len(), 400);
+        let mut last_seqs = HashMap::new();
+        for message in &messages {
+            let seq = message.seq().unwrap();
+            let did = message.did().unwrap().as_str();
+            if let Some(last_seq) = last_seqs.insert(did, seq) {
+                assert!(last_seq < seq, "{did} out of order");
+            }
+        }
+        assert_eq!(last_seqs.len(), 8);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn decode_permits_are_shared_by_streams() {
+        use tokio::sync::Semaphore;
+
+        let permits = Arc::new(Semaphore::new(1));
+        let options = DecodeOptions {
+            decode_permits: Some(permits.clone()),
+            ..Default::default()
+        };
+        let held = permits.clone().acquire_owned().await.unwrap();
+        let streams = (0..2).map(|seq| {
+            let frames = stream::iter([CommitBuilder::new(TEST_DID).seq(seq).build()]);
+            Box::pin(frames.decode_concurrently(NonZeroUsize::MIN, options.clone()))
+        });
+        let mut messages = stream::select_all(streams);
+
+        // both streams wait for the only permit
+        let next = tokio::time::timeout(Duration::from_millis(100), messages.next()).await;
+        assert!(next.is_err());
+        drop(held);
+        let messages = messages.map(Result::unwrap).collect::<Vec<_>>().await;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(permits.available_permits(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn decoding_scales_with_workers() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use tokio::sync::Semaphore;
+
+        const PERMITS: usize = 64;
+        const FRAMES: i64 = 200;
+
+        // largest number of commits decoded at once, as seen from the decode permits in use
+        async fn max_concurrent_decodes(workers: usize) -> usize {
+            let permits = Arc::new(Semaphore::new(PERMITS));
+            let options = DecodeOptions {
+                decode_permits: Some(permits.clone()),
+                ..Default::default()
+            };
+            let frames = (0..FRAMES)
+                .map(|seq| {
+                    let did = format!("did:plc:repo{}", seq % 16);
+                    (0..50)
+                        .fold(CommitBuilder::new(&did).seq(seq), |builder, rkey| {
+                            builder.create_post(&format!("3k{rkey}"), &"a".repeat(1000))
+                        })
+                        .build()
+                })
+                .collect::<Vec<_>>();
+            let done = Arc::new(AtomicBool::new(false));
+            let sampler = {
+                let (permits, done) = (permits.clone(), done.clone());
+                std::thread::spawn(move || {
+                    let mut max = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        max = max.max(PERMITS - permits.available_permits());
+                    }
+                    max
+                })
+            };
+            let decoded = stream::iter(frames)
+                .decode_concurrently(NonZeroUsize::new(workers).unwrap(), options)
+                .filter(|message| future::ready(message.is_ok()))
+                .count()
+                .await;
+            done.store(true, Ordering::Relaxed);
+            assert_eq!(decoded, FRAMES as usize);
+            sampler.join().unwrap()
+        }
+
+        assert!(max_concurrent_decodes(1).await <= 1);
+        assert!(max_concurrent_decodes(4).await > 1);
+    }
+
+    #[tokio::test]
+    async fn graph_events_only() {
+        let other = "did:plc:someoneelse";
+        let block = ipld_map([
+            ("$type", Ipld::String("app.bsky.graph.block".into())),
+            ("subject", Ipld::String(other.into())),
+            ("createdAt", Ipld::String(TEST_TIME.into())),
+        ]);
+        let frame = CommitBuilder::new(TEST_DID)
+            .create("app.bsky.graph.follow", "3ka", follow_record(other))
+            .create_post("3kb", "hello")
+            .create("app.bsky.graph.block", "3kc", block)
+            .create(
+                "app.bsky.feed.like",
+                "3kd",
+                like_record(
+                    &format!("at://{other}/app.bsky.feed.post/3kx"),
+                    &cid_for(b"x"),
+                ),
+            )
+            .delete("app.bsky.graph.follow", "3ke")
+            .build();
+        let message = FirehoseMessage::try_from(frame).unwrap();
+
+        let rkeys = stream::iter([message])
+            .operation_events()
+            .graph_events()
+            .map(|event| event.operation.operation_meta().rkey.clone())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(rkeys, vec!["3ka", "3kc", "3ke"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn distinct_did_estimates() {
+        let messages = (0..5000)
+            .map(|seq| {
+                let did = format!("did:plc:repo{}", seq % 1000);
+                FirehoseMessage::try_from(identity_frame(&did, seq, None)).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut estimates = std::pin::pin!(
+            stream::iter(messages)
+                .chain(stream::pending())
+                .distinct_dids(Duration::from_secs(60), Duration::from_secs(10))
+        );
+
+        let estimate = estimates.next().await.unwrap();
+        assert!((950..=1050).contains(&estimate), "estimated {estimate}");
+        // nothing is received afterwards, the repositories leave the window
+        for _ in 0..6 {
+            estimates.next().await;
+        }
+        assert_eq!(estimates.next().await, Some(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quick_commits_are_coalesced() {
+        let other = "did:plc:someoneelse";
+        let messages = [
+            CommitBuilder::new(TEST_DID).create_post("3ka", "first"),
+            CommitBuilder::new(TEST_DID).create_post("3kb", "second"),
+            CommitBuilder::new(other).create_post("3kc", "third"),
+        ]
+        .map(|builder| FirehoseMessage::try_from(builder.build()).unwrap());
+        let mut batches = std::pin::pin!(
+            stream::iter(messages)
+                .chain(stream::pending())
+                .coalesce_by_did(Duration::from_secs(1))
+        );
+
+        let started = Instant::now();
+        let batch = batches.next().await.unwrap();
+        assert_eq!(batch.did.as_str(), TEST_DID);
+        let rkeys = batch
+            .events
+            .iter()
+            .map(|event| event.operation.operation_meta().rkey.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(rkeys, vec!["3ka", "3kb"]);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        let batch = batches.next().await.unwrap();
+        assert_eq!(batch.did.as_str(), other);
+        assert_eq!(batch.events.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keyword_filter() {
+        let frame = CommitBuilder::new(TEST_DID)
+            .create_post("3ka", "Loving my new Rust project")
+            .create_post("3kb", "nothing to see here")
+            .update("app.bsky.feed.post", "3kc", post_record("rust"))
+            .create(
+                "app.bsky.graph.follow",
+                "3kd",
+                follow_record("did:plc:rust"),
+            )
+            .build();
+        let matching = |case_insensitive| {
+            stream::iter([FirehoseMessage::try_from(frame.clone()).unwrap()])
+                .operation_events()
+                .text_contains(&["rust", "ferris"], case_insensitive)
+                .map(|event| event.operation.operation_meta().rkey.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(matching(true).await, vec!["3ka"]);
+        assert!(matching(false).await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_are_flushed_when_full_or_late() {
+        let messages = |count| {
+            stream::iter(0..count).map(|seq| {
+                FirehoseMessage::try_from(CommitBuilder::new(TEST_DID).seq(seq).build()).unwrap()
+            })
+        };
+        let seqs = |batch: Vec<FirehoseMessage>| {
+            batch
+                .iter()
+                .map(|message| message.seq().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let mut batches = std::pin::pin!(
+            messages(5)
+                .chain(stream::pending())
+                .batched(NonZeroUsize::new(2).unwrap(), Duration::from_secs(1))
+        );
+
+        let started = Instant::now();
+        assert_eq!(seqs(batches.next().await.unwrap()), vec![0, 1]);
+        assert_eq!(seqs(batches.next().await.unwrap()), vec![2, 3]);
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        // not full, flushed after the delay
+        assert_eq!(seqs(batches.next().await.unwrap()), vec![4]);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        let batches = messages(3)
+            .batched(NonZeroUsize::new(5).unwrap(), Duration::from_secs(1))
+            .map(seqs)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(batches, vec![vec![0, 1, 2]]);
+    }
+
+    #[tokio::test]
+    async fn broadcast_subscribers() {
+        let messages = |count| {
+            stream::iter(0..count).map(|seq| {
+                FirehoseMessage::try_from(CommitBuilder::new(TEST_DID).seq(seq).build()).unwrap()
+            })
+        };
+        async fn seqs(subscriber: impl Stream<Item = Arc<FirehoseMessage>>) -> Vec<i64> {
+            subscriber
+                .map(|message| message.seq().unwrap())
+                .collect()
+                .await
+        }
+
+        let broadcast = messages(5).broadcast(NonZeroUsize::new(16).unwrap());
+        let first = broadcast.subscribe(LagPolicy::Skip);
+        let second = broadcast.subscribe(LagPolicy::End);
+        drop(broadcast);
+        let (first, second) = tokio::join!(seqs(first), seqs(second));
+        assert_eq!(first, vec![0, 1, 2, 3, 4]);
+        assert_eq!(second, first);
+
+        // the whole stream is sent before the subscribers get to read it
+        let broadcast = messages(5).broadcast(NonZeroUsize::new(2).unwrap());
+        let skipping = broadcast.subscribe(LagPolicy::Skip);
+        let ending = broadcast.subscribe(LagPolicy::End);
+        drop(broadcast);
+        tokio::task::yield_now().await;
+        assert_eq!(seqs(skipping).await, vec![3, 4]);
+        assert!(seqs(ending).await.is_empty());
+    }
+}
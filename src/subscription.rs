@@ -2,13 +2,29 @@ pub const BLUESKY_FEED_DOMAIN: &str = "bsky.network";
 use atrium_api::{com::atproto::sync::subscribe_repos::NSID, types::CidLink};
 
 use futures::StreamExt;
+use ipld_core::ipld::Ipld;
+use std::{
+    borrow::Cow,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use crate::frame::{Error as FrameError, Frame};
-use tokio::net::TcpStream;
+use crate::{
+    DecodeOptions, FirehoseMessage,
+    cursor::{self, BoxedCursorStore, CursorStore},
+    frame::{Error as FrameError, Frame},
+};
+use tokio::{net::TcpStream, sync::Notify, task::JoinHandle};
 use tokio_tungstenite::{
-    connect_async,
-    tungstenite::{client::IntoClientRequest, Message},
-    MaybeTlsStream, WebSocketStream,
+    connect_async_tls_with_config,
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        handshake::client::Request,
+        http::{header::RETRY_AFTER, StatusCode},
+        Message,
+    },
+    Connector, MaybeTlsStream, WebSocketStream,
 };
 
 #[derive(thiserror::Error, Debug)]
@@ -23,30 +39,987 @@ pub enum Error {
     InvalidFrame(#[from] FrameError),
     #[error("ATrium error: {0}")]
     AtriumError(String),
+    #[error("No data received from the relay after connecting")]
+    NoInitialData,
+    #[error("Refusing plaintext websocket connection to {0}")]
+    Plaintext(String),
+    #[error("Not a websocket URL: {0}")]
+    InvalidUrl(String),
+    #[error("Timed out connecting to the relay after {0:?}")]
+    ConnectTimeout(Duration),
+    #[error("No pong received from the relay within {0:?} of a ping")]
+    KeepaliveTimeout(Duration),
+    #[error("Unexpected raw websocket frame ({0})")]
+    RawFrame(String),
+    #[error("Failed to set up TLS: {0}")]
+    Tls(String),
+    #[cfg(feature = "compress")]
+    #[error("Failed to decompress frame: {0}")]
+    Decompress(std::io::Error),
+    #[error("Failed to transform frame: {0}")]
+    Transform(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    /// Delay requested by the relay when it refused the connection (`429` or `503` with a
+    /// `Retry-After` header).
+    pub fn retry_after(&self) -> Option<Duration> {
+        let Error::Connect(tungstenite::Error::Http(response)) = self else {
+            return None;
+        };
+        if response.status() != StatusCode::TOO_MANY_REQUESTS
+            && response.status() != StatusCode::SERVICE_UNAVAILABLE
+        {
+            return None;
+        }
+        let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+        if let Ok(seconds) = value.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+        // Retry-After may also be an HTTP date
+        let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+        (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+            .to_std()
+            .ok()
+    }
+}
+
+/// Exponential backoff between reconnection attempts, honoring relay retry hints.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+    hint: Option<Duration>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+            hint: None,
+        }
+    }
+    /// Delay to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        if let Some(hint) = self.hint.take() {
+            return hint;
+        }
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+    /// Record a failed attempt; a retry hint from the relay replaces the next computed delay.
+    pub fn on_error(&mut self, error: &Error) {
+        if let Some(retry_after) = error.retry_after() {
+            self.hint = Some(retry_after);
+        }
+    }
+    /// Go back to the initial delay, typically once connected.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+        self.hint = None;
+    }
+}
+
+/// Outcome of [`RepoSubscription::check_cursor`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CursorStatus {
+    /// The relay resumes from the cursor; `first_seq` is the first event it sent, if any.
+    Valid { first_seq: Option<i64> },
+    /// The cursor is older than what the relay retains: events were lost, backfill is needed.
+    Outdated,
+    /// The relay answered with an error frame (e.g. `FutureCursor`) or closed the connection.
+    Rejected,
+}
+
+/// HTTP version negotiated for the websocket upgrade, see [`ConnectOptions::http_version`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// Let the TLS backend and the server settle the protocol, no ALPN is sent.
+    #[default]
+    Auto,
+    /// Only offer `http/1.1` through ALPN, for proxies answering websocket upgrades with
+    /// HTTP/2 when it is not ruled out.
+    Http11,
+}
+
+impl HttpVersion {
+    /// Protocols advertised through ALPN, empty when none is sent.
+    fn alpn_protocols(self) -> &'static [&'static str] {
+        match self {
+            HttpVersion::Auto => &[],
+            HttpVersion::Http11 => &["http/1.1"],
+        }
+    }
+
+    /// TLS connector advertising the ALPN protocols, `None` for the default one.
+    fn connector(self) -> Result<Option<Connector>, Error> {
+        if self.alpn_protocols().is_empty() {
+            return Ok(None);
+        }
+        #[cfg(feature = "native-tls")]
+        {
+            let connector = native_tls::TlsConnector::builder()
+                .request_alpns(self.alpn_protocols())
+                .build()
+                .map_err(|e| Error::Tls(e.to_string()))?;
+            Ok(Some(Connector::NativeTls(connector)))
+        }
+        #[cfg(all(
+            not(feature = "native-tls"),
+            any(
+                feature = "rustls-tls-native-roots",
+                feature = "rustls-tls-webpki-roots"
+            )
+        ))]
+        {
+            let mut roots = rustls::RootCertStore::empty();
+            #[cfg(feature = "rustls-tls-native-roots")]
+            roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+            #[cfg(feature = "rustls-tls-webpki-roots")]
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            let mut config = rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            config.alpn_protocols = self
+                .alpn_protocols()
+                .iter()
+                .map(|protocol| protocol.as_bytes().to_vec())
+                .collect();
+            Ok(Some(Connector::Rustls(Arc::new(config))))
+        }
+        #[cfg(not(any(
+            feature = "native-tls",
+            feature = "rustls-tls-native-roots",
+            feature = "rustls-tls-webpki-roots"
+        )))]
+        {
+            Ok(None)
+        }
+    }
+}
+
+/// How long to wait for the relay's first frame when checking a cursor; relays report
+/// outdated or future cursors right away.
+const CURSOR_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// Sequence number to resume from, the relay replays the events following it.
+    pub cursor: Option<i64>,
+    /// Fail with [`Error::ConnectTimeout`] when the TCP, TLS and websocket handshakes take
+    /// longer than this, 10 seconds by default.
+    pub connect_timeout: Duration,
+    /// Fail with [`Error::NoInitialData`] when the relay accepts the connection but does not
+    /// send any frame within this delay.
+    pub initial_data_timeout: Option<Duration>,
+    /// Close the connection and end the subscription after this many frames. A
+    /// [`ReconnectingSubscription`] counts them across reconnections.
+    pub max_events: Option<u64>,
+    /// Close the connection and end the subscription this long after connecting. A
+    /// [`ReconnectingSubscription`] ends this long after its first connection attempt,
+    /// reconnections included.
+    pub max_duration: Option<Duration>,
+    /// Send a ping this often, failing with [`Error::KeepaliveTimeout`] when the relay does
+    /// not answer within `pong_timeout`. Detects half-open connections on quiet relays.
+    pub ping_interval: Option<Duration>,
+    pub pong_timeout: Duration,
+    /// Force the HTTP version of the websocket upgrade, for proxies mishandling some of them.
+    pub http_version: HttpVersion,
+    /// Allow `ws://` connections, refused with [`Error::Plaintext`] otherwise. Only meant for
+    /// local development against an unencrypted relay.
+    pub allow_plaintext: bool,
+    /// Applied to each message before it is decoded as a frame: [`ZstdTransform`] with the
+    /// `compress` feature, [`IdentityTransform`] otherwise.
+    pub transform: Arc<dyn FrameTransform>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            cursor: None,
+            connect_timeout: Duration::from_secs(10),
+            initial_data_timeout: None,
+            max_events: None,
+            max_duration: None,
+            ping_interval: None,
+            pong_timeout: Duration::from_secs(10),
+            http_version: HttpVersion::Auto,
+            allow_plaintext: false,
+            #[cfg(feature = "compress")]
+            transform: Arc::new(ZstdTransform),
+            #[cfg(not(feature = "compress"))]
+            transform: Arc::new(IdentityTransform),
+        }
+    }
+}
+
+/// First bytes of a zstd frame, DAG-CBOR frames start with their header map instead.
+#[cfg(feature = "compress")]
+pub(crate) const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Largest decompressed message, the default `max_message_size` of tungstenite for
+/// uncompressed ones.
+#[cfg(feature = "compress")]
+pub const MAX_DECOMPRESSED_BYTES: u64 = 64 << 20;
+
+/// Read a decompressing `reader` to the end, failing once more than `limit` bytes come out
+/// so that a small message cannot inflate without bound.
+#[cfg(feature = "compress")]
+pub(crate) fn inflate(reader: impl std::io::Read, limit: u64) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut inflated = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut inflated)?;
+    if inflated.len() as u64 > limit {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("more than {limit} bytes once decompressed"),
+        ));
+    }
+    Ok(inflated)
+}
+
+/// Turn the bytes of a websocket message into the bytes of a frame, e.g. to decompress it or
+/// unwrap the envelope of a proxy, see [`ConnectOptions::transform`].
+///
+/// Failures of custom transforms are reported as [`Error::Transform`].
+pub trait FrameTransform: std::fmt::Debug + Send + Sync {
+    /// `data` is borrowed back when left as is.
+    fn apply<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>, Error>;
+}
+
+/// Messages are frames as is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityTransform;
+
+impl FrameTransform for IdentityTransform {
+    fn apply<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+        Ok(Cow::Borrowed(data))
+    }
+}
+
+/// Decompress zstd compressed messages, others are returned as is. Messages inflating to
+/// more than [`MAX_DECOMPRESSED_BYTES`] fail with [`Error::Decompress`].
+#[cfg(feature = "compress")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZstdTransform;
+
+#[cfg(feature = "compress")]
+impl FrameTransform for ZstdTransform {
+    fn apply<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+        if !data.starts_with(&ZSTD_MAGIC) {
+            return Ok(Cow::Borrowed(data));
+        }
+        let inflated = zstd::stream::read::Decoder::new(data)
+            .and_then(|decoder| inflate(decoder, MAX_DECOMPRESSED_BYTES))
+            .map_err(Error::Decompress)?;
+        #[cfg(feature = "prometheus")]
+        metrics::count_zstd_bytes(data.len(), inflated.len());
+        Ok(Cow::Owned(inflated))
+    }
+}
+
+fn subscribe_url(bgs: &str, cursor: Option<i64>) -> String {
+    with_cursor(&format!("wss://{bgs}/xrpc/{NSID}"), cursor)
+}
+
+pub(crate) fn with_cursor(url: &str, cursor: Option<i64>) -> String {
+    match cursor {
+        Some(cursor) if url.contains('?') => format!("{url}&cursor={cursor}"),
+        Some(cursor) => format!("{url}?cursor={cursor}"),
+        None => url.to_string(),
+    }
 }
 
 pub struct RepoSubscription {
     stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    connected: bool,
+    tls: bool,
+    initial_data_deadline: Option<tokio::time::Instant>,
+    remaining_events: Option<u64>,
+    end_deadline: Option<tokio::time::Instant>,
+    ping_interval: Option<Duration>,
+    pong_timeout: Duration,
+    next_ping: Option<tokio::time::Instant>,
+    pong_deadline: Option<tokio::time::Instant>,
+    transform: Arc<dyn FrameTransform>,
 }
 
 impl RepoSubscription {
     pub async fn new(bgs: &str) -> Result<Self, Error> {
-        // todo: somehow get the websocket to update the damn params
-        let request = format!("wss://{bgs}/xrpc/{NSID}").into_client_request()?;
-        // request.
-        let (stream, res) = connect_async(request).await?;
-        tracing::debug!("Connected to websocket: {:?}", res);
-        Ok(RepoSubscription { stream })
+        Self::new_with_cursor(bgs, None).await
+    }
+    /// Resume from `cursor`, typically the [`seq`](crate::FirehoseMessage::seq) of the last
+    /// message processed before a disconnection.
+    pub async fn new_with_cursor(bgs: &str, cursor: Option<i64>) -> Result<Self, Error> {
+        let options = ConnectOptions {
+            cursor,
+            ..Default::default()
+        };
+        Self::new_with_options(bgs, &options).await
+    }
+    pub async fn new_with_options(bgs: &str, options: &ConnectOptions) -> Result<Self, Error> {
+        let request = subscribe_url(bgs, options.cursor).into_client_request()?;
+        Self::from_request(request, options).await
+    }
+    /// Connect to a complete `ws://` or `wss://` URL, e.g. a local development relay or a PDS
+    /// (`wss://{pds}/xrpc/com.atproto.sync.subscribeRepos`).
+    ///
+    /// The scheme being explicit, `ws://` URLs are accepted.
+    pub async fn from_url(url: &str) -> Result<Self, Error> {
+        let options = ConnectOptions {
+            allow_plaintext: true,
+            ..Default::default()
+        };
+        Self::from_url_with_options(url, &options).await
+    }
+    /// Connect to a complete `ws://` or `wss://` URL, the cursor of `options` being appended to
+    /// its query.
+    pub async fn from_url_with_options(url: &str, options: &ConnectOptions) -> Result<Self, Error> {
+        if !url.starts_with("wss://") && !url.starts_with("ws://") {
+            return Err(Error::InvalidUrl(url.to_string()));
+        }
+        let request = with_cursor(url, options.cursor).into_client_request()?;
+        Self::from_request(request, options).await
+    }
+    pub(crate) async fn from_request(
+        request: Request,
+        options: &ConnectOptions,
+    ) -> Result<Self, Error> {
+        if request.uri().scheme_str() == Some("ws") && !options.allow_plaintext {
+            return Err(Error::Plaintext(request.uri().to_string()));
+        }
+        let connector = options.http_version.connector()?;
+        let (stream, res) = tokio::time::timeout(
+            options.connect_timeout,
+            connect_async_tls_with_config(request, None, false, connector),
+        )
+        .await
+        .map_err(|_elapsed| Error::ConnectTimeout(options.connect_timeout))??;
+        let tls = !matches!(stream.get_ref(), MaybeTlsStream::Plain(_));
+        tracing::debug!("Connected to websocket (tls: {tls}): {:?}", res);
+        let now = tokio::time::Instant::now();
+        Ok(RepoSubscription {
+            stream,
+            connected: true,
+            tls,
+            initial_data_deadline: options.initial_data_timeout.map(|timeout| now + timeout),
+            remaining_events: options.max_events,
+            end_deadline: options.max_duration.map(|duration| now + duration),
+            ping_interval: options.ping_interval,
+            pong_timeout: options.pong_timeout,
+            next_ping: options.ping_interval.map(|interval| now + interval),
+            pong_deadline: None,
+            transform: options.transform.clone(),
+        })
+    }
+    /// Check a persisted cursor against the relay before resuming from it.
+    ///
+    /// The protocol does not expose the sequence range retained by a relay, so this briefly
+    /// subscribes from `cursor` and inspects the first frame the relay sends back.
+    pub async fn check_cursor(bgs: &str, cursor: i64) -> Result<CursorStatus, Error> {
+        let request = subscribe_url(bgs, Some(cursor)).into_client_request()?;
+        Self::check_cursor_with_request(request, &ConnectOptions::default()).await
+    }
+    pub(crate) async fn check_cursor_with_request(
+        request: Request,
+        options: &ConnectOptions,
+    ) -> Result<CursorStatus, Error> {
+        let mut subscription = Self::from_request(request, options).await?;
+        let first_frame = tokio::time::timeout(CURSOR_CHECK_TIMEOUT, subscription.next()).await;
+        let status = match first_frame {
+            Err(_elapsed) => CursorStatus::Valid { first_seq: None },
+            Ok(Some(Ok(Frame::Message(t, message_frame)))) => {
+                let body = serde_ipld_dagcbor::from_slice::<Ipld>(&message_frame.body)
+                    .map_err(FrameError::from)?;
+                match (t.as_deref(), body) {
+                    (Some("#info"), Ipld::Map(map))
+                        if map.get("name") == Some(&Ipld::String("OutdatedCursor".into())) =>
+                    {
+                        CursorStatus::Outdated
+                    }
+                    (_, Ipld::Map(map)) => CursorStatus::Valid {
+                        first_seq: match map.get("seq") {
+                            Some(Ipld::Integer(seq)) => i64::try_from(*seq).ok(),
+                            _ => None,
+                        },
+                    },
+                    _ => CursorStatus::Valid { first_seq: None },
+                }
+            }
+            Ok(Some(Ok(Frame::Error(_)))) | Ok(None) => CursorStatus::Rejected,
+            Ok(Some(Err(e))) => return Err(e),
+        };
+        if !matches!(status, CursorStatus::Valid { .. }) {
+            tracing::warn!("Cursor {status:?} by the relay");
+        }
+        let _ = subscription.close().await;
+        Ok(status)
+    }
+    /// Whether the websocket is encrypted (`wss://`).
+    pub fn is_tls(&self) -> bool {
+        self.tls
+    }
+    /// Cleanly close the websocket.
+    pub async fn close(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.stream.close(None).await?;
+        Ok(())
     }
     pub async fn next(&mut self) -> Option<Result<Frame, Error>> {
-        if let Some(Ok(Message::Binary(data))) = self.stream.next().await {
-            #[cfg(feature = "prometheus")]
+        if !self.connected {
+            return None;
+        }
+        if self.remaining_events == Some(0) {
+            let _ = self.close().await;
+            return None;
+        }
+        loop {
+            let deadline = [
+                self.initial_data_deadline,
+                self.end_deadline,
+                self.next_ping,
+                self.pong_deadline,
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            let message = match deadline {
+                Some(deadline) => {
+                    match tokio::time::timeout_at(deadline, self.stream.next()).await {
+                        Ok(message) => message,
+                        Err(_elapsed) if Some(deadline) == self.end_deadline => {
+                            let _ = self.close().await;
+                            return None;
+                        }
+                        Err(_elapsed) if Some(deadline) == self.initial_data_deadline => {
+                            self.connected = false;
+                            return Some(Err(Error::NoInitialData));
+                        }
+                        Err(_elapsed) if Some(deadline) == self.pong_deadline => {
+                            self.connected = false;
+                            return Some(Err(Error::KeepaliveTimeout(self.pong_timeout)));
+                        }
+                        Err(_elapsed) => {
+                            if let Err(e) = self.ping().await {
+                                self.connected = false;
+                                return Some(Err(e));
+                            }
+                            continue;
+                        }
+                    }
+                }
+                None => self.stream.next().await,
+            };
+            if let Some(item) = self.on_message(message) {
+                return item;
+            }
+        }
+    }
+    /// Handle a websocket message: `None` to read the next one, the item to return otherwise.
+    fn on_message(
+        &mut self,
+        message: Option<Result<Message, tungstenite::Error>>,
+    ) -> Option<Option<Result<Frame, Error>>> {
+        match message {
+            Some(Ok(Message::Binary(data))) => {
+                self.initial_data_deadline = None;
+                if let Some(remaining_events) = &mut self.remaining_events {
+                    *remaining_events -= 1;
+                }
+                let received_at = Instant::now();
+                #[cfg(feature = "prometheus")]
+                {
+                    metrics::FIREHOSE_BYTE_COUNTER.inc_by(data.len() as u64);
+                }
+                let data = match self.transform.apply(&data) {
+                    Ok(data) => data,
+                    Err(e) => return Some(Some(Err(e))),
+                };
+                #[cfg(feature = "prometheus")]
+                {
+                    metrics::FIREHOSE_DECOMPRESSED_BYTE_COUNTER.inc_by(data.len() as u64);
+                }
+                Some(Some(
+                    Frame::try_from(&data[..])
+                        .map(|mut frame| {
+                            #[cfg(feature = "prometheus")]
+                            metrics::count_frame_bytes(&frame, data.len());
+                            if let Frame::Message(_, message_frame) = &mut frame {
+                                message_frame.received_at = Some(received_at);
+                            }
+                            frame
+                        })
+                        .map_err(Error::from),
+                ))
+            }
+            // pings are answered by tungstenite itself
+            Some(Ok(Message::Ping(_))) => {
+                #[cfg(feature = "prometheus")]
+                metrics::count_control_frame("ping");
+                None
+            }
+            Some(Ok(Message::Pong(_))) => {
+                #[cfg(feature = "prometheus")]
+                metrics::count_control_frame("pong");
+                self.pong_deadline = None;
+                None
+            }
+            Some(Ok(Message::Close(_))) => {
+                #[cfg(feature = "prometheus")]
+                metrics::count_control_frame("close");
+                self.connected = false;
+                Some(None)
+            }
+            // not surfaced by tungstenite when reading, only by custom configurations: reported
+            // without ending the subscription
+            Some(Ok(Message::Frame(frame))) => Some(Some(Err(Error::RawFrame(
+                frame.header().opcode.to_string(),
+            )))),
+            _ => {
+                self.connected = false;
+                Some(None)
+            }
+        }
+    }
+    /// Send a keepalive ping and schedule the next one, see [`ConnectOptions::ping_interval`].
+    async fn ping(&mut self) -> Result<(), Error> {
+        use futures::SinkExt;
+
+        let now = tokio::time::Instant::now();
+        self.next_ping = self.ping_interval.map(|interval| now + interval);
+        self.pong_deadline.get_or_insert(now + self.pong_timeout);
+        self.stream.send(Message::Ping(Default::default())).await?;
+        Ok(())
+    }
+    /// Wait for the next frame, then drain up to `max` frames in total, waiting at most
+    /// `max_wait` for the following ones.
+    ///
+    /// An empty batch means the subscription ended, like `None` from [`next`](Self::next).
+    pub async fn next_batch(
+        &mut self,
+        max: usize,
+        max_wait: Duration,
+    ) -> Vec<Result<Frame, Error>> {
+        let mut batch = Vec::with_capacity(max);
+        if max == 0 {
+            return batch;
+        }
+        let Some(first) = self.next().await else {
+            return batch;
+        };
+        batch.push(first);
+        let deadline = tokio::time::Instant::now() + max_wait;
+        while batch.len() < max {
+            match tokio::time::timeout_at(deadline, self.next()).await {
+                Ok(Some(frame)) => batch.push(frame),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        batch
+    }
+}
+
+impl Drop for RepoSubscription {
+    fn drop(&mut self) {
+        if self.connected {
+            tracing::debug!(
+                "RepoSubscription dropped while still connected, use close() for a clean disconnect"
+            );
+        }
+    }
+}
+
+/// State of a [`FirehoseClient`], see [`ControlHandle::status`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientStatus {
+    pub connected: bool,
+    /// Sequence number of the last message handled, the client resumes from it when it
+    /// reconnects.
+    pub cursor: Option<i64>,
+    pub messages: u64,
+    pub reconnections: u64,
+}
+
+/// Control a client started with [`FirehoseClient::spawn`].
+#[derive(Clone)]
+pub struct ControlHandle {
+    shutdown: Arc<Notify>,
+    status: Arc<Mutex<ClientStatus>>,
+}
+
+impl ControlHandle {
+    /// Ask the client to close its connection and stop; the task completes once done.
+    pub fn shutdown(&self) {
+        self.shutdown.notify_one();
+    }
+    pub fn status(&self) -> ClientStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// Consume the firehose on a background task, driving a [`ReconnectingSubscription`]: it
+/// reconnects with [`Backoff`] when the connection drops or stalls and resumes from the last
+/// message handled.
+pub struct FirehoseClient {
+    subscription: ReconnectingSubscription,
+}
+
+impl FirehoseClient {
+    pub fn new(bgs: &str) -> Self {
+        Self::new_with_options(bgs, ConnectOptions::default(), DecodeOptions::default())
+    }
+    pub fn new_with_options(
+        bgs: &str,
+        connect_options: ConnectOptions,
+        decode_options: DecodeOptions,
+    ) -> Self {
+        FirehoseClient {
+            subscription: ReconnectingSubscription::new_with_options(
+                bgs,
+                connect_options,
+                decode_options,
+            ),
+        }
+    }
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.subscription = self.subscription.backoff(backoff);
+        self
+    }
+    /// See [`ReconnectingSubscription::idle_timeout`].
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.subscription = self.subscription.idle_timeout(idle_timeout);
+        self
+    }
+    /// Run the consume loop on a tokio task, calling `handler` with each decoded message. The
+    /// task completes on shutdown or once the [`ConnectOptions::max_events`] or
+    /// [`ConnectOptions::max_duration`] budget is spent.
+    ///
+    /// Frames and messages failing to decode are logged and skipped.
+    pub fn spawn<H>(self, handler: H) -> (JoinHandle<()>, ControlHandle)
+    where
+        H: FnMut(FirehoseMessage) + Send + 'static,
+    {
+        let control = ControlHandle {
+            shutdown: Arc::new(Notify::new()),
+            status: Arc::new(Mutex::new(ClientStatus {
+                cursor: self.subscription.cursor(),
+                ..Default::default()
+            })),
+        };
+        let status = control.status.clone();
+        let subscription = self.subscription.on_connection_event(move |event| {
+            let mut status = status.lock().unwrap();
+            match event {
+                ConnectionEvent::Connected { .. } => status.connected = true,
+                ConnectionEvent::Disconnected { .. } => {
+                    status.connected = false;
+                    status.reconnections += 1;
+                }
+                ConnectionEvent::Reconnecting { .. } => {}
+            }
+        });
+        let task = tokio::spawn(Self::run(subscription, handler, control.clone()));
+        (task, control)
+    }
+
+    async fn run<H: FnMut(FirehoseMessage)>(
+        mut subscription: ReconnectingSubscription,
+        mut handler: H,
+        control: ControlHandle,
+    ) {
+        let shutdown = control.shutdown.notified();
+        tokio::pin!(shutdown);
+        loop {
+            let message = tokio::select! {
+                _ = &mut shutdown => {
+                    subscription.close().await;
+                    control.status.lock().unwrap().connected = false;
+                    return;
+                }
+                message = subscription.next() => message,
+            };
+            match message {
+                Some(Ok(message)) => {
+                    handler(message);
+                    let mut status = control.status.lock().unwrap();
+                    status.cursor = subscription.cursor();
+                    status.messages += 1;
+                }
+                Some(Err(e)) => tracing::warn!("Unable to decode message, skipping it: {e}"),
+                None => {
+                    control.status.lock().unwrap().connected = false;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Lifecycle of the connection of a [`ReconnectingSubscription`], see
+/// [`on_connection_event`](ReconnectingSubscription::on_connection_event).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected {
+        endpoint: String,
+    },
+    /// The connection was lost, or abandoned because the relay stalled.
+    Disconnected {
+        reason: String,
+    },
+    /// Waiting `delay` before the `attempt`-th connection since the last successful one.
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+}
+
+/// Silence after which a [`ReconnectingSubscription`] considers the connection dead.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pull decoded messages from the firehose, reconnecting with [`Backoff`] when the connection
+/// drops or stalls and resuming from the last message returned.
+pub struct ReconnectingSubscription {
+    endpoint: String,
+    connect_options: ConnectOptions,
+    decode_options: DecodeOptions,
+    backoff: Backoff,
+    idle_timeout: Duration,
+    on_cursor: Option<Box<dyn FnMut(i64) + Send>>,
+    on_connection_event: Option<Box<dyn FnMut(ConnectionEvent) + Send>>,
+    cursor_store: Option<BoxedCursorStore>,
+    /// Sequence number of the message last returned, processed once `next` is called again.
+    returned_seq: Option<i64>,
+    attempt: u32,
+    subscription: Option<RepoSubscription>,
+    /// Budget of [`ConnectOptions::max_events`], spanning reconnections.
+    remaining_events: Option<u64>,
+    /// Budget of [`ConnectOptions::max_duration`], spanning reconnections.
+    max_duration: Option<Duration>,
+    end_deadline: Option<tokio::time::Instant>,
+}
+
+impl ReconnectingSubscription {
+    pub fn new(bgs: &str) -> Self {
+        Self::new_with_options(bgs, ConnectOptions::default(), DecodeOptions::default())
+    }
+    pub fn new_with_options(
+        bgs: &str,
+        mut connect_options: ConnectOptions,
+        decode_options: DecodeOptions,
+    ) -> Self {
+        // enforced here: each connection would start over
+        let remaining_events = connect_options.max_events.take();
+        let max_duration = connect_options.max_duration.take();
+        ReconnectingSubscription {
+            endpoint: subscribe_url(bgs, None),
+            connect_options,
+            decode_options,
+            backoff: Backoff::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            on_cursor: None,
+            on_connection_event: None,
+            cursor_store: None,
+            returned_seq: None,
+            attempt: 0,
+            subscription: None,
+            remaining_events,
+            max_duration,
+            end_deadline: None,
+        }
+    }
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+    /// Reconnect when no frame is received for `idle_timeout`, 30 seconds by default.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+    /// Call `on_cursor` with the sequence number of each message processed, e.g. to persist it
+    /// and resume from it after a restart. A message is processed once [`next`](Self::next)
+    /// is called again: the last message returned before a crash is replayed on restart.
+    pub fn on_cursor(mut self, on_cursor: impl FnMut(i64) + Send + 'static) -> Self {
+        self.on_cursor = Some(Box::new(on_cursor));
+        self
+    }
+    /// Call `on_connection_event` when the subscription connects, disconnects or waits to
+    /// reconnect, e.g. to monitor the relay.
+    pub fn on_connection_event(
+        mut self,
+        on_connection_event: impl FnMut(ConnectionEvent) + Send + 'static,
+    ) -> Self {
+        self.on_connection_event = Some(Box::new(on_connection_event));
+        self
+    }
+    /// Resume from the cursor of `store` when no cursor is set on the first connection, and
+    /// store the sequence number of each message processed, see
+    /// [`on_cursor`](Self::on_cursor).
+    pub fn cursor_store(mut self, store: impl CursorStore + Send + 'static) -> Self {
+        self.cursor_store = Some(cursor::boxed(store));
+        self
+    }
+    /// Sequence number the subscription resumes from when it reconnects.
+    pub fn cursor(&self) -> Option<i64> {
+        self.connect_options.cursor
+    }
+    /// Next message of the firehose, waiting as long as needed for the relay to come back.
+    ///
+    /// Frames failing to decode are logged and skipped, messages failing to decode are
+    /// returned as errors: the subscription is still usable afterwards. `None` once the
+    /// [`ConnectOptions::max_events`] or [`ConnectOptions::max_duration`] budget is spent, the
+    /// subscription never ends otherwise.
+    pub async fn next(&mut self) -> Option<Result<FirehoseMessage, crate::Error>> {
+        if let Some(seq) = self.returned_seq.take() {
+            self.processed(seq);
+        }
+        let Some(max_duration) = self.max_duration else {
+            return self.next_message().await;
+        };
+        let deadline = *self
+            .end_deadline
+            .get_or_insert_with(|| tokio::time::Instant::now() + max_duration);
+        if deadline <= tokio::time::Instant::now() {
+            self.close().await;
+            return None;
+        }
+        match tokio::time::timeout_at(deadline, self.next_message()).await {
+            Ok(message) => message,
+            Err(_elapsed) => {
+                self.close().await;
+                None
+            }
+        }
+    }
+    async fn next_message(&mut self) -> Option<Result<FirehoseMessage, crate::Error>> {
+        loop {
+            if self.remaining_events == Some(0) {
+                self.close().await;
+                return None;
+            }
+            if self.subscription.is_none() {
+                match self.connect().await {
+                    Ok(subscription) => self.subscription = Some(subscription),
+                    Err(e) => {
+                        tracing::warn!("Connecting to the firehose: {e}");
+                        self.backoff.on_error(&e);
+                        let delay = self.backoff.next_delay();
+                        self.reconnecting(delay);
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                }
+            }
+            let Some(subscription) = &mut self.subscription else {
+                continue;
+            };
+            let frame = match tokio::time::timeout(self.idle_timeout, subscription.next()).await {
+                Ok(Some(frame)) => {
+                    if let Some(remaining_events) = &mut self.remaining_events {
+                        *remaining_events -= 1;
+                    }
+                    match frame {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            tracing::warn!("Unable to decode frame, skipping it: {e}");
+                            continue;
+                        }
+                    }
+                }
+                Ok(None) => {
+                    tracing::warn!("Disconnected from the firehose, reconnecting");
+                    self.subscription = None;
+                    self.emit(ConnectionEvent::Disconnected {
+                        reason: "connection closed".to_string(),
+                    });
+                    let delay = self.backoff.next_delay();
+                    self.reconnecting(delay);
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+                Err(_elapsed) => {
+                    tracing::warn!("No frame for {:?}, reconnecting", self.idle_timeout);
+                    let _ = subscription.close().await;
+                    self.subscription = None;
+                    self.emit(ConnectionEvent::Disconnected {
+                        reason: format!("no frame for {:?}", self.idle_timeout),
+                    });
+                    self.reconnecting(Duration::ZERO);
+                    continue;
+                }
+            };
+            self.backoff.reset();
+            let message = match FirehoseMessage::try_from_limited(frame, &self.decode_options).await
             {
-                metrics::FIREHOSE_BYTE_COUNTER.inc_by(data.len() as u64);
+                Ok(message) => message,
+                Err(e) => return Some(Err(e)),
+            };
+            if let Some(seq) = message.seq() {
+                self.connect_options.cursor = Some(seq);
+                self.returned_seq = Some(seq);
             }
-            Some(Frame::try_from(data.as_ref()).map_err(Error::from))
-        } else {
-            None
+            return Some(Ok(message));
+        }
+    }
+    /// Cleanly close the current connection, if any. The next call to [`next`](Self::next)
+    /// connects again.
+    pub async fn close(&mut self) {
+        if let Some(mut subscription) = self.subscription.take()
+            && let Err(e) = subscription.close().await
+        {
+            tracing::debug!("Closing the firehose connection: {e}");
+        }
+    }
+    async fn connect(&mut self) -> Result<RepoSubscription, Error> {
+        if self.connect_options.cursor.is_none()
+            && let Some(store) = &mut self.cursor_store
+        {
+            match store.load() {
+                Ok(cursor) => self.connect_options.cursor = cursor,
+                Err(e) => tracing::warn!("Unable to load the stored cursor: {e}"),
+            }
+        }
+        let url = with_cursor(&self.endpoint, self.connect_options.cursor);
+        let subscription = RepoSubscription::from_request(
+            url.clone().into_client_request()?,
+            &self.connect_options,
+        )
+        .await?;
+        self.attempt = 0;
+        self.emit(ConnectionEvent::Connected { endpoint: url });
+        Ok(subscription)
+    }
+    fn processed(&mut self, seq: i64) {
+        if let Some(on_cursor) = &mut self.on_cursor {
+            on_cursor(seq);
+        }
+        if let Some(store) = &mut self.cursor_store
+            && let Err(e) = store.store(seq)
+        {
+            tracing::warn!("Unable to store cursor {seq}: {e}");
+        }
+    }
+    fn reconnecting(&mut self, delay: Duration) {
+        self.attempt += 1;
+        self.emit(ConnectionEvent::Reconnecting {
+            attempt: self.attempt,
+            delay,
+        });
+    }
+    fn emit(&mut self, event: ConnectionEvent) {
+        if let Some(on_connection_event) = &mut self.on_connection_event {
+            on_connection_event(event);
         }
     }
 }
@@ -54,12 +1027,873 @@ impl RepoSubscription {
 #[cfg(feature = "prometheus")]
 mod metrics {
     use lazy_static::lazy_static;
-    use prometheus::IntCounter;
+    use prometheus::{IntCounter, IntCounterVec};
+
+    use crate::frame::Frame;
 
     lazy_static! {
         pub(crate) static ref FIREHOSE_BYTE_COUNTER: IntCounter = crate::metrics::create_counter(
             "bluesky_firehose_streamer_bytes_in",
-            "Input bytes from bluesky firehose"
+            "Input bytes from bluesky firehose, compressed if the relay compresses frames"
+        );
+        pub(crate) static ref FIREHOSE_DECOMPRESSED_BYTE_COUNTER: IntCounter =
+            crate::metrics::create_counter(
+                "bluesky_firehose_streamer_decompressed_bytes_in",
+                "Input bytes from bluesky firehose, after decompression"
+            );
+        pub(crate) static ref FIREHOSE_FRAME_BYTE_COUNTER: IntCounterVec =
+            crate::metrics::create_counter_with_labels(
+                "bluesky_firehose_streamer_frame_bytes_in",
+                "Input bytes from bluesky firehose by frame type",
+                &["type"]
+            );
+        pub(crate) static ref FIREHOSE_CONTROL_FRAME_COUNTER: IntCounterVec =
+            crate::metrics::create_counter_with_labels(
+                "bluesky_firehose_streamer_control_frames_in",
+                "Websocket control frames (ping, pong, close) from bluesky firehose",
+                &["type"]
+            );
+    }
+
+    #[cfg(feature = "compress")]
+    lazy_static! {
+        pub(crate) static ref FIREHOSE_ZSTD_BYTE_COUNTER: IntCounterVec =
+            crate::metrics::create_counter_with_labels(
+                "bluesky_firehose_streamer_zstd_bytes",
+                "Size of the zstd compressed frames from bluesky firehose, before and after decompression",
+                &["stage"]
+            );
+    }
+
+    /// Only compressed frames are counted, the ratio of both stages is the compression ratio.
+    #[cfg(feature = "compress")]
+    pub(crate) fn count_zstd_bytes(compressed: usize, decompressed: usize) {
+        FIREHOSE_ZSTD_BYTE_COUNTER
+            .with_label_values(&["compressed"])
+            .inc_by(compressed as u64);
+        FIREHOSE_ZSTD_BYTE_COUNTER
+            .with_label_values(&["decompressed"])
+            .inc_by(decompressed as u64);
+    }
+
+    pub(crate) fn count_control_frame(frame_type: &str) {
+        FIREHOSE_CONTROL_FRAME_COUNTER
+            .with_label_values(&[frame_type])
+            .inc();
+    }
+
+    pub(crate) fn count_frame_bytes(frame: &Frame, len: usize) {
+        let frame_type = match frame {
+            Frame::Message(Some(t), _) => t.trim_start_matches('#'),
+            Frame::Message(None, _) => "unknown",
+            Frame::Error(_) => "error",
+        };
+        FIREHOSE_FRAME_BYTE_COUNTER
+            .with_label_values(&[frame_type])
+            .inc_by(len as u64);
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::test_util::{ipld_map, message_frame, CommitBuilder, TEST_DID};
+        use ipld_core::ipld::Ipld;
+
+        #[test]
+        fn frame_bytes_are_split_by_type() {
+            let commit = FIREHOSE_FRAME_BYTE_COUNTER.with_label_values(&["commit"]);
+            let identity = FIREHOSE_FRAME_BYTE_COUNTER.with_label_values(&["identity"]);
+            let (commit_before, identity_before) = (commit.get(), identity.get());
+
+            count_frame_bytes(&CommitBuilder::new(TEST_DID).build(), 100);
+            let identity_frame = message_frame(
+                "#identity",
+                &ipld_map([("did", Ipld::String(TEST_DID.into()))]),
+            );
+            count_frame_bytes(&identity_frame, 10);
+
+            assert_eq!(commit.get() - commit_before, 100);
+            assert_eq!(identity.get() - identity_before, 10);
+        }
+
+        #[cfg(feature = "compress")]
+        #[test]
+        fn zstd_bytes_are_counted() {
+            use super::super::{FrameTransform, ZstdTransform};
+
+            let compressed = FIREHOSE_ZSTD_BYTE_COUNTER.with_label_values(&["compressed"]);
+            let decompressed = FIREHOSE_ZSTD_BYTE_COUNTER.with_label_values(&["decompressed"]);
+            let (compressed_before, decompressed_before) = (compressed.get(), decompressed.get());
+
+            let frame = CommitBuilder::new(TEST_DID)
+                .create_post("3kabc", &"compressible ".repeat(100))
+                .to_bytes();
+            let data = zstd::stream::encode_all(frame.as_slice(), 3).unwrap();
+            ZstdTransform.apply(&data).unwrap();
+
+            assert_eq!(compressed.get() - compressed_before, data.len() as u64);
+            assert_eq!(decompressed.get() - decompressed_before, frame.len() as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        future::Future,
+        io,
+        sync::{Arc, Mutex},
+    };
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::http::Response;
+
+    /// Accept a single websocket connection and hand it to `handler`.
+    async fn mock_relay<F, Fut>(handler: F) -> Request
+    where
+        F: FnOnce(WebSocketStream<TcpStream>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            handler(ws).await;
+        });
+        format!("ws://{addr}/xrpc/{NSID}")
+            .into_client_request()
+            .unwrap()
+    }
+
+    /// Options accepting the `ws://` connections to [`mock_relay`].
+    fn plaintext() -> ConnectOptions {
+        ConnectOptions {
+            allow_plaintext: true,
+            ..Default::default()
+        }
+    }
+
+    /// Keep the relay side of the connection open until the client goes away.
+    async fn idle(mut ws: WebSocketStream<TcpStream>) {
+        while let Some(Ok(_)) = ws.next().await {}
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn control_frames_are_counted() {
+        use futures::SinkExt;
+
+        let ping = metrics::FIREHOSE_CONTROL_FRAME_COUNTER.with_label_values(&["ping"]);
+        let pong = metrics::FIREHOSE_CONTROL_FRAME_COUNTER.with_label_values(&["pong"]);
+        let (ping_before, pong_before) = (ping.get(), pong.get());
+
+        let frame = crate::test_util::CommitBuilder::new(crate::test_util::TEST_DID).to_bytes();
+        let request = mock_relay(|mut ws| async move {
+            ws.send(Message::Ping(vec![1].into())).await.unwrap();
+            ws.send(Message::Pong(vec![2].into())).await.unwrap();
+            ws.send(Message::Binary(frame.into())).await.unwrap();
+            idle(ws).await;
+        })
+        .await;
+        let mut subscription = RepoSubscription::from_request(request, &plaintext())
+            .await
+            .unwrap();
+
+        // control frames do not end the stream
+        assert!(matches!(subscription.next().await, Some(Ok(_))));
+        assert_eq!(ping.get() - ping_before, 1);
+        assert_eq!(pong.get() - pong_before, 1);
+        subscription.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscription_ends_after_max_events() {
+        use crate::test_util::{CommitBuilder, TEST_DID};
+        use futures::SinkExt;
+
+        let request = mock_relay(|mut ws| async move {
+            for seq in 0..5 {
+                let frame = CommitBuilder::new(TEST_DID).seq(seq).to_bytes();
+                ws.send(Message::Binary(frame.into())).await.unwrap();
+            }
+            idle(ws).await;
+        })
+        .await;
+        let options = ConnectOptions {
+            max_events: Some(3),
+            ..plaintext()
+        };
+        let mut subscription = RepoSubscription::from_request(request, &options)
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            assert!(matches!(subscription.next().await, Some(Ok(_))));
+        }
+        assert!(subscription.next().await.is_none());
+        assert!(!subscription.connected);
+    }
+
+    #[tokio::test]
+    async fn queued_frames_are_batched() {
+        use crate::test_util::{CommitBuilder, TEST_DID};
+        use futures::SinkExt;
+
+        let request = mock_relay(|mut ws| async move {
+            for seq in 0..5 {
+                let frame = CommitBuilder::new(TEST_DID).seq(seq).to_bytes();
+                ws.send(Message::Binary(frame.into())).await.unwrap();
+            }
+            idle(ws).await;
+        })
+        .await;
+        let mut subscription = RepoSubscription::from_request(request, &plaintext())
+            .await
+            .unwrap();
+
+        let batch = subscription.next_batch(3, Duration::from_secs(1)).await;
+        assert_eq!(batch.len(), 3);
+        assert!(batch.iter().all(Result::is_ok));
+        // the relay is idle after the remaining frames
+        let batch = subscription
+            .next_batch(10, Duration::from_millis(100))
+            .await;
+        assert_eq!(batch.len(), 2);
+        subscription.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn outdated_cursor_is_detected() {
+        use crate::test_util::{ipld_map, message_frame_bytes};
+        use futures::SinkExt;
+
+        let info = message_frame_bytes(
+            "#info",
+            &ipld_map([("name", Ipld::String("OutdatedCursor".into()))]),
+        );
+        let request = mock_relay(|mut ws| async move {
+            ws.send(Message::Binary(info.into())).await.unwrap();
+            idle(ws).await;
+        })
+        .await;
+        assert_eq!(
+            RepoSubscription::check_cursor_with_request(request, &plaintext())
+                .await
+                .unwrap(),
+            CursorStatus::Outdated
+        );
+
+        let commit = crate::test_util::CommitBuilder::new(crate::test_util::TEST_DID)
+            .seq(1234)
+            .to_bytes();
+        let request = mock_relay(|mut ws| async move {
+            ws.send(Message::Binary(commit.into())).await.unwrap();
+            idle(ws).await;
+        })
+        .await;
+        assert_eq!(
+            RepoSubscription::check_cursor_with_request(request, &plaintext())
+                .await
+                .unwrap(),
+            CursorStatus::Valid {
+                first_seq: Some(1234)
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn stalled_relay_errors() {
+        let options = ConnectOptions {
+            initial_data_timeout: Some(Duration::from_millis(200)),
+            ..plaintext()
+        };
+        let mut subscription = RepoSubscription::from_request(mock_relay(idle).await, &options)
+            .await
+            .unwrap();
+        assert!(matches!(
+            subscription.next().await,
+            Some(Err(Error::NoInitialData))
+        ));
+        assert!(subscription.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        let options = ConnectOptions {
+            ping_interval: Some(Duration::from_millis(20)),
+            pong_timeout: Duration::from_millis(100),
+            ..plaintext()
+        };
+        // a live relay answers pings while reading, the subscription stays up
+        let request = mock_relay(idle).await;
+        let mut subscription = RepoSubscription::from_request(request, &options)
+            .await
+            .unwrap();
+        let next = tokio::time::timeout(Duration::from_millis(300), subscription.next()).await;
+        assert!(next.is_err(), "expected no frame nor error");
+
+        // a relay which stopped reading never answers
+        let request = mock_relay(|ws| async move {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(ws);
+        })
+        .await;
+        let mut subscription = RepoSubscription::from_request(request, &options)
+            .await
+            .unwrap();
+        assert!(matches!(
+            subscription.next().await,
+            Some(Err(Error::KeepaliveTimeout(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn hanging_handshake_times_out() {
+        // the TCP connection is accepted by the kernel, the websocket handshake never answered
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let request = format!("ws://{}/xrpc/{NSID}", listener.local_addr().unwrap())
+            .into_client_request()
+            .unwrap();
+        let options = ConnectOptions {
+            connect_timeout: Duration::from_millis(100),
+            ..plaintext()
+        };
+        let result = RepoSubscription::from_request(request, &options).await;
+        assert!(matches!(result, Err(Error::ConnectTimeout(_))));
+    }
+
+    #[test]
+    fn http11_connector_only_offers_http1() {
+        assert!(HttpVersion::Auto.connector().unwrap().is_none());
+        assert_eq!(HttpVersion::Http11.alpn_protocols(), ["http/1.1"]);
+        let connector = HttpVersion::Http11.connector().unwrap();
+        #[cfg(feature = "native-tls")]
+        assert!(matches!(connector, Some(Connector::NativeTls(_))));
+        #[cfg(all(
+            not(feature = "native-tls"),
+            any(
+                feature = "rustls-tls-native-roots",
+                feature = "rustls-tls-webpki-roots"
+            )
+        ))]
+        {
+            let Some(Connector::Rustls(config)) = connector else {
+                panic!("expected a rustls connector");
+            };
+            assert_eq!(config.alpn_protocols, [b"http/1.1".to_vec()]);
+        }
+        #[cfg(not(any(
+            feature = "native-tls",
+            feature = "rustls-tls-native-roots",
+            feature = "rustls-tls-webpki-roots"
+        )))]
+        assert!(connector.is_none());
+    }
+
+    /// ALPN protocols of the TLS ClientHello sent when connecting with `http_version`.
+    #[cfg(any(
+        feature = "native-tls",
+        feature = "rustls-tls-native-roots",
+        feature = "rustls-tls-webpki-roots"
+    ))]
+    async fn offered_alpn_protocols(http_version: HttpVersion) -> Vec<String> {
+        use tokio::io::AsyncReadExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let request = format!("wss://{}/xrpc/{NSID}", listener.local_addr().unwrap())
+            .into_client_request()
+            .unwrap();
+        let options = ConnectOptions {
+            http_version,
+            ..Default::default()
+        };
+        let client =
+            tokio::spawn(async move { RepoSubscription::from_request(request, &options).await });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        // TLS record header: content type, version and length
+        let mut header = [0; 5];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], 22, "expected a handshake record");
+        let mut hello = vec![0; u16::from_be_bytes([header[3], header[4]]) as usize];
+        stream.read_exact(&mut hello).await.unwrap();
+        // the handshake fails without a server answer, only the ClientHello matters
+        drop(stream);
+        assert!(client.await.unwrap().is_err());
+
+        let u16_at = |at: usize| u16::from_be_bytes([hello[at], hello[at + 1]]) as usize;
+        // handshake type and length, client version and random
+        let mut at = 4 + 2 + 32;
+        at += 1 + hello[at] as usize; // session id
+        at += 2 + u16_at(at); // cipher suites
+        at += 1 + hello[at] as usize; // compression methods
+        let extensions_end = at + 2 + u16_at(at);
+        at += 2;
+        while at < extensions_end {
+            let (kind, len) = (u16_at(at), u16_at(at + 2));
+            at += 4;
+            if kind == 0x0010 {
+                // list length, then length prefixed protocol names
+                let mut names = &hello[at + 2..at + len];
+                let mut protocols = Vec::new();
+                while let Some((&name_len, rest)) = names.split_first() {
+                    let (name, rest) = rest.split_at(name_len as usize);
+                    protocols.push(String::from_utf8(name.to_vec()).unwrap());
+                    names = rest;
+                }
+                return protocols;
+            }
+            at += len;
+        }
+        Vec::new()
+    }
+
+    #[cfg(any(
+        feature = "native-tls",
+        feature = "rustls-tls-native-roots",
+        feature = "rustls-tls-webpki-roots"
+    ))]
+    #[tokio::test]
+    async fn http_version_is_offered_in_the_tls_handshake() {
+        assert_eq!(
+            offered_alpn_protocols(HttpVersion::Http11).await,
+            ["http/1.1"]
+        );
+        assert!(offered_alpn_protocols(HttpVersion::Auto).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn raw_frames_do_not_end_the_subscription() {
+        use tokio_tungstenite::tungstenite::protocol::frame::Frame as RawFrame;
+
+        let mut subscription = RepoSubscription::from_request(mock_relay(idle).await, &plaintext())
+            .await
+            .unwrap();
+        let item = subscription.on_message(Some(Ok(Message::Frame(RawFrame::close(None)))));
+        assert!(matches!(item, Some(Some(Err(Error::RawFrame(_))))));
+        assert!(subscription.connected);
+        subscription.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn plaintext_is_refused_by_default() {
+        let request = mock_relay(idle).await;
+        let uri = request.uri().to_string();
+        match RepoSubscription::from_request(request, &ConnectOptions::default()).await {
+            Err(Error::Plaintext(refused)) => assert_eq!(refused, uri),
+            _ => panic!("expected a plaintext error"),
+        }
+
+        let mut subscription = RepoSubscription::from_request(mock_relay(idle).await, &plaintext())
+            .await
+            .unwrap();
+        assert!(!subscription.is_tls());
+        subscription.close().await.unwrap();
+    }
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        fn capture<R>(&self, f: impl FnOnce() -> R) -> R {
+            let logs = self.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::DEBUG)
+                .with_ansi(false)
+                .with_writer(move || logs.clone())
+                .finish();
+            tracing::subscriber::with_default(subscriber, f)
+        }
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn unclean_drop_is_logged() {
+        let subscription = RepoSubscription::from_request(mock_relay(idle).await, &plaintext())
+            .await
+            .unwrap();
+        let logs = CapturedLogs::default();
+        logs.capture(|| drop(subscription));
+        assert!(logs.contents().contains("dropped while still connected"));
+
+        let mut subscription = RepoSubscription::from_request(mock_relay(idle).await, &plaintext())
+            .await
+            .unwrap();
+        subscription.close().await.unwrap();
+        let logs = CapturedLogs::default();
+        logs.capture(|| drop(subscription));
+        assert!(logs.contents().is_empty());
+    }
+
+    fn http_error(status: u16, retry_after: Option<&str>) -> Error {
+        let mut response = Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            response = response.header(RETRY_AFTER, retry_after);
+        }
+        Error::Connect(tungstenite::Error::Http(Box::new(
+            response.body(None).unwrap(),
+        )))
+    }
+
+    #[tokio::test]
+    async fn client_shutdown() {
+        use crate::test_util::{CommitBuilder, TEST_DID};
+        use futures::SinkExt;
+        use tokio::sync::mpsc;
+
+        let request = mock_relay(|mut ws| async move {
+            for seq in 1..=3 {
+                let frame = CommitBuilder::new(TEST_DID).seq(seq).to_bytes();
+                ws.send(Message::Binary(frame.into())).await.unwrap();
+            }
+            idle(ws).await;
+        })
+        .await;
+        let mut client =
+            FirehoseClient::new_with_options("", plaintext(), DecodeOptions::default());
+        client.subscription.endpoint = request.uri().to_string();
+
+        let (seq_tx, mut seq_rx) = mpsc::unbounded_channel();
+        let (task, control) = client.spawn(move |message| {
+            let _ = seq_tx.send(message.seq());
+        });
+        for seq in 1..=3 {
+            assert_eq!(seq_rx.recv().await, Some(Some(seq)));
+        }
+        assert!(control.status().connected);
+
+        control.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("client did not stop")
+            .unwrap();
+        assert_eq!(
+            control.status(),
+            ClientStatus {
+                connected: false,
+                cursor: Some(3),
+                messages: 3,
+                reconnections: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnecting_subscription_resumes_from_cursor() {
+        use crate::test_util::{CommitBuilder, TEST_DID};
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::handshake::server::ErrorResponse;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let uris = Arc::new(Mutex::new(Vec::new()));
+        let relay_uris = uris.clone();
+        tokio::spawn(async move {
+            for seq in 1..=2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let uris = relay_uris.clone();
+                let record_uri =
+                    move |request: &Request, response: Response<()>| -> Result<_, ErrorResponse> {
+                        uris.lock().unwrap().push(request.uri().to_string());
+                        Ok(response)
+                    };
+                let mut ws = tokio_tungstenite::accept_hdr_async(stream, record_uri)
+                    .await
+                    .unwrap();
+                let frame = CommitBuilder::new(TEST_DID).seq(seq).to_bytes();
+                ws.send(Message::Binary(frame.into())).await.unwrap();
+                if seq == 1 {
+                    // drop the first connection right away
+                    ws.close(None).await.unwrap();
+                } else {
+                    idle(ws).await;
+                }
+            }
+        });
+
+        let cursors = Arc::new(Mutex::new(Vec::new()));
+        let persisted = cursors.clone();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let observed = events.clone();
+        let mut subscription =
+            ReconnectingSubscription::new_with_options("", plaintext(), DecodeOptions::default())
+                .backoff(Backoff::new(
+                    Duration::from_millis(10),
+                    Duration::from_millis(10),
+                ))
+                .on_cursor(move |cursor| persisted.lock().unwrap().push(cursor))
+                .on_connection_event(move |event| observed.lock().unwrap().push(event));
+        let endpoint = format!("ws://{addr}/xrpc/{NSID}");
+        subscription.endpoint = endpoint.clone();
+
+        for seq in 1..=2 {
+            assert_eq!(subscription.next().await.unwrap().unwrap().seq(), Some(seq));
+        }
+        // the second message is not processed yet
+        assert_eq!(*cursors.lock().unwrap(), vec![1]);
+        assert_eq!(
+            uris.lock().unwrap().as_slice(),
+            [format!("/xrpc/{NSID}"), format!("/xrpc/{NSID}?cursor=1")]
+        );
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                ConnectionEvent::Connected {
+                    endpoint: endpoint.clone()
+                },
+                ConnectionEvent::Disconnected {
+                    reason: "connection closed".to_string()
+                },
+                ConnectionEvent::Reconnecting {
+                    attempt: 1,
+                    delay: Duration::from_millis(10)
+                },
+                ConnectionEvent::Connected {
+                    endpoint: format!("{endpoint}?cursor=1")
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn budget_spans_reconnections() {
+        use crate::test_util::{CommitBuilder, TEST_DID};
+        use futures::SinkExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for seqs in [1..=2, 3..=5] {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                let last = *seqs.end();
+                for seq in seqs {
+                    let frame = CommitBuilder::new(TEST_DID).seq(seq).to_bytes();
+                    ws.send(Message::Binary(frame.into())).await.unwrap();
+                }
+                if last == 2 {
+                    ws.close(None).await.unwrap();
+                } else {
+                    idle(ws).await;
+                }
+            }
+        });
+        let options = ConnectOptions {
+            max_events: Some(3),
+            ..plaintext()
+        };
+        let mut subscription =
+            ReconnectingSubscription::new_with_options("", options, DecodeOptions::default())
+                .backoff(Backoff::new(
+                    Duration::from_millis(10),
+                    Duration::from_millis(10),
+                ));
+        subscription.endpoint = format!("ws://{addr}/xrpc/{NSID}");
+
+        for seq in 1..=3 {
+            assert_eq!(subscription.next().await.unwrap().unwrap().seq(), Some(seq));
+        }
+        assert!(subscription.next().await.is_none());
+        assert!(subscription.next().await.is_none());
+        assert!(subscription.subscription.is_none());
+
+        let request = mock_relay(idle).await;
+        let options = ConnectOptions {
+            max_duration: Some(Duration::from_millis(100)),
+            ..plaintext()
+        };
+        let mut subscription =
+            ReconnectingSubscription::new_with_options("", options, DecodeOptions::default());
+        subscription.endpoint = request.uri().to_string();
+        let next = tokio::time::timeout(Duration::from_secs(5), subscription.next()).await;
+        assert!(next.expect("the subscription did not end").is_none());
+    }
+
+    #[tokio::test]
+    async fn stored_cursor_is_resumed() {
+        use crate::{
+            cursor::FileCursorStore,
+            test_util::{CommitBuilder, TEST_DID},
+        };
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::handshake::server::ErrorResponse;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut uri = String::new();
+            let record_uri =
+                |request: &Request, response: Response<()>| -> Result<_, ErrorResponse> {
+                    uri = request.uri().to_string();
+                    Ok(response)
+                };
+            let mut ws = tokio_tungstenite::accept_hdr_async(stream, record_uri)
+                .await
+                .unwrap();
+            for seq in [42, 43] {
+                let frame = CommitBuilder::new(TEST_DID).seq(seq).to_bytes();
+                ws.send(Message::Binary(frame.into())).await.unwrap();
+            }
+            tokio::spawn(idle(ws));
+            uri
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cursor");
+        FileCursorStore::new(&path).store(41).unwrap();
+        let mut subscription =
+            ReconnectingSubscription::new_with_options("", plaintext(), DecodeOptions::default())
+                .cursor_store(FileCursorStore::new(&path));
+        subscription.endpoint = format!("ws://{addr}/xrpc/{NSID}");
+
+        assert_eq!(subscription.next().await.unwrap().unwrap().seq(), Some(42));
+        assert_eq!(relay.await.unwrap(), format!("/xrpc/{NSID}?cursor=41"));
+        // stored once processed, when the next message is requested
+        assert_eq!(FileCursorStore::new(&path).load().unwrap(), Some(41));
+        assert_eq!(subscription.next().await.unwrap().unwrap().seq(), Some(43));
+        drop(subscription);
+        assert_eq!(FileCursorStore::new(&path).load().unwrap(), Some(42));
+    }
+
+    #[cfg(feature = "compress")]
+    #[test]
+    fn inflating_is_bounded() {
+        let data = vec![0; 1024];
+        let compressed = zstd::stream::encode_all(data.as_slice(), 3).unwrap();
+        let decoder = || zstd::stream::read::Decoder::new(compressed.as_slice()).unwrap();
+        assert_eq!(inflate(decoder(), 1024).unwrap(), data);
+        let error = inflate(decoder(), 1023).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "compress")]
+    #[tokio::test]
+    async fn compressed_frames_are_inflated() {
+        use crate::test_util::{CommitBuilder, TEST_DID};
+        use futures::SinkExt;
+
+        let frame = CommitBuilder::new(TEST_DID)
+            .seq(42)
+            .create_post("3kabc", &"compressible ".repeat(100))
+            .to_bytes();
+        let compressed = zstd::stream::encode_all(frame.as_slice(), 3).unwrap();
+        assert!(compressed.len() < frame.len());
+        let request = mock_relay(|mut ws| async move {
+            ws.send(Message::Binary(compressed.into())).await.unwrap();
+            ws.send(Message::Binary(frame.into())).await.unwrap();
+            idle(ws).await;
+        })
+        .await;
+        let mut subscription = RepoSubscription::from_request(request, &plaintext())
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let frame = subscription.next().await.unwrap().unwrap();
+            assert_eq!(FirehoseMessage::try_from(frame).unwrap().seq(), Some(42));
+        }
+        subscription.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn frame_transform_is_applied() {
+        use crate::test_util::{CommitBuilder, TEST_DID};
+        use futures::SinkExt;
+
+        #[derive(Debug)]
+        struct Reverse;
+
+        impl FrameTransform for Reverse {
+            fn apply<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+                Ok(Cow::Owned(data.iter().rev().copied().collect()))
+            }
+        }
+
+        let frame = CommitBuilder::new(TEST_DID).seq(42).to_bytes();
+        let reversed = frame.iter().rev().copied().collect::<Vec<_>>();
+        for (transform, data) in [
+            (
+                Arc::new(IdentityTransform) as Arc<dyn FrameTransform>,
+                frame,
+            ),
+            (Arc::new(Reverse), reversed),
+        ] {
+            let request = mock_relay(|mut ws| async move {
+                ws.send(Message::Binary(data.into())).await.unwrap();
+                idle(ws).await;
+            })
+            .await;
+            let options = ConnectOptions {
+                transform,
+                ..plaintext()
+            };
+            let mut subscription = RepoSubscription::from_request(request, &options)
+                .await
+                .unwrap();
+            let frame = subscription.next().await.unwrap().unwrap();
+            assert_eq!(FirehoseMessage::try_from(frame).unwrap().seq(), Some(42));
+            subscription.close().await.unwrap();
+        }
+    }
+
+    #[test]
+    fn cursor_is_sent_to_the_relay() {
+        assert_eq!(
+            subscribe_url(BLUESKY_FEED_DOMAIN, None),
+            "wss://bsky.network/xrpc/com.atproto.sync.subscribeRepos"
+        );
+        assert_eq!(
+            subscribe_url(BLUESKY_FEED_DOMAIN, Some(1234)),
+            "wss://bsky.network/xrpc/com.atproto.sync.subscribeRepos?cursor=1234"
+        );
+        assert_eq!(
+            with_cursor("ws://localhost:2470/xrpc/sub?foo=bar", Some(1234)),
+            "ws://localhost:2470/xrpc/sub?foo=bar&cursor=1234"
+        );
+    }
+
+    #[tokio::test]
+    async fn subscription_from_url() {
+        let url = mock_relay(idle).await.uri().to_string();
+        let mut subscription = RepoSubscription::from_url(&url).await.unwrap();
+        assert!(!subscription.is_tls());
+        subscription.close().await.unwrap();
+
+        assert!(matches!(
+            RepoSubscription::from_url("https://bsky.network").await,
+            Err(Error::InvalidUrl(_))
+        ));
+    }
+
+    #[test]
+    fn retry_after_sets_next_backoff_delay() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+
+        backoff.on_error(&http_error(429, Some("120")));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(120));
+        // back to the regular schedule afterwards
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn retry_after_is_ignored_without_hint() {
+        assert_eq!(http_error(429, None).retry_after(), None);
+        assert_eq!(http_error(404, Some("120")).retry_after(), None);
+        assert_eq!(
+            http_error(503, Some("5")).retry_after(),
+            Some(Duration::from_secs(5))
         );
     }
 }
@@ -0,0 +1,375 @@
+//! Helpers to build synthetic firehose frames for tests.
+//!
+//! Frames produced here are byte-for-byte what a relay would send: a DAG-CBOR
+//! header, a DAG-CBOR body and, for commits, a CAR file holding the record blocks.
+use std::collections::BTreeMap;
+
+use ipld_core::{cid::Cid, ipld::Ipld};
+use multihash::Multihash;
+use sha2::{Digest, Sha256};
+
+use crate::{FirehoseMessage, Operation, Record, frame::Frame};
+
+pub const TEST_DID: &str = "did:plc:ewvi7nxzyoun6zhxrhs64oiz";
+pub const TEST_REV: &str = "3jzfcijpj2z2a";
+pub const TEST_TIME: &str = "2024-11-20T12:00:00.000Z";
+
+const DAG_CBOR: u64 = 0x71;
+const SHA2_256: u64 = 0x12;
+
+/// Build an `Ipld::Map` from string keys.
+pub fn ipld_map<const N: usize>(entries: [(&str, Ipld); N]) -> Ipld {
+    Ipld::Map(
+        entries
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect::<BTreeMap<_, _>>(),
+    )
+}
+
+/// CIDv1 (dag-cbor, sha2-256) of a block.
+pub fn cid_for(data: &[u8]) -> Cid {
+    let digest = Sha256::digest(data);
+    Cid::new_v1(DAG_CBOR, Multihash::<64>::wrap(SHA2_256, &digest).unwrap())
+}
+
+pub fn post_record(text: &str) -> Ipld {
+    ipld_map([
+        ("$type", Ipld::String("app.bsky.feed.post".into())),
+        ("text", Ipld::String(text.into())),
+        ("createdAt", Ipld::String(TEST_TIME.into())),
+    ])
+}
+
+/// Blob reference to an image holding `data`.
+pub fn blob(data: &[u8]) -> Ipld {
+    ipld_map([
+        ("$type", Ipld::String("blob".into())),
+        ("ref", Ipld::Link(cid_for(data))),
+        ("mimeType", Ipld::String("image/jpeg".into())),
+        ("size", Ipld::Integer(data.len() as i128)),
+    ])
+}
+
+/// A post with extra fields, e.g. a `reply` or an `embed`.
+pub fn post_with<const N: usize>(text: &str, fields: [(&str, Ipld); N]) -> Ipld {
+    let mut post = post_record(text);
+    if let Ipld::Map(map) = &mut post {
+        map.extend(fields.into_iter().map(|(k, v)| (k.to_string(), v)));
+    }
+    post
+}
+
+pub fn strong_ref(uri: &str, cid: &Cid) -> Ipld {
+    ipld_map([
+        ("uri", Ipld::String(uri.into())),
+        ("cid", Ipld::String(cid.to_string())),
+    ])
+}
+
+pub fn like_record(subject_uri: &str, subject_cid: &Cid) -> Ipld {
+    ipld_map([
+        ("$type", Ipld::String("app.bsky.feed.like".into())),
+        ("subject", strong_ref(subject_uri, subject_cid)),
+        ("createdAt", Ipld::String(TEST_TIME.into())),
+    ])
+}
+
+pub fn follow_record(subject: &str) -> Ipld {
+    ipld_map([
+        ("$type", Ipld::String("app.bsky.graph.follow".into())),
+        ("subject", Ipld::String(subject.into())),
+        ("createdAt", Ipld::String(TEST_TIME.into())),
+    ])
+}
+
+/// Encode a full frame (header + body) for message type `t`.
+pub fn message_frame_bytes(t: &str, body: &Ipld) -> Vec<u8> {
+    let header = ipld_map([("op", Ipld::Integer(1)), ("t", Ipld::String(t.into()))]);
+    let mut bytes = serde_ipld_dagcbor::to_vec(&header).unwrap();
+    bytes.extend(serde_ipld_dagcbor::to_vec(body).unwrap());
+    bytes
+}
+
+pub fn message_frame(t: &str, body: &Ipld) -> Frame {
+    Frame::try_from(message_frame_bytes(t, body).as_slice()).expect("invalid test frame")
+}
+
+pub fn identity_frame(did: &str, seq: i64, handle: Option<&str>) -> Frame {
+    let body = ipld_map([
+        ("seq", Ipld::Integer(seq.into())),
+        ("did", Ipld::String(did.into())),
+        ("time", Ipld::String(TEST_TIME.into())),
+        (
+            "handle",
+            handle.map_or(Ipld::Null, |h| Ipld::String(h.into())),
+        ),
+    ]);
+    message_frame("#identity", &body)
+}
+
+pub fn account_frame(did: &str, seq: i64, active: bool) -> Frame {
+    let body = ipld_map([
+        ("seq", Ipld::Integer(seq.into())),
+        ("did", Ipld::String(did.into())),
+        ("time", Ipld::String(TEST_TIME.into())),
+        ("active", Ipld::Bool(active)),
+    ]);
+    message_frame("#account", &body)
+}
+
+/// Decode a commit and return the record of its first operation, a creation.
+pub fn decode_single_record(builder: CommitBuilder) -> Record {
+    let FirehoseMessage::Commit { operations, .. } =
+        FirehoseMessage::try_from(builder.build()).expect("failed to decode")
+    else {
+        panic!("expected a commit");
+    };
+    match operations.into_iter().next() {
+        Some(Operation::Create { record, .. }) => record,
+        _ => panic!("expected a creation"),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TestOp {
+    action: &'static str,
+    collection: String,
+    rkey: String,
+    record: Option<Ipld>,
+}
+
+/// Assemble a `#commit` frame from high level operations.
+///
+/// ```ignore
+/// let frame = CommitBuilder::new(TEST_DID)
+///     .create_post("3kabc", "hello world")
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct CommitBuilder {
+    repo: String,
+    rev: String,
+    seq: i64,
+    time: String,
+    too_big: bool,
+    ops: Vec<TestOp>,
+    blocks: Option<Vec<u8>>,
+    extra_blocks: Vec<Vec<u8>>,
+}
+
+impl CommitBuilder {
+    pub fn new(repo: &str) -> Self {
+        Self {
+            repo: repo.to_string(),
+            rev: TEST_REV.to_string(),
+            seq: 1,
+            time: TEST_TIME.to_string(),
+            too_big: false,
+            ops: Vec::new(),
+            blocks: None,
+            extra_blocks: Vec::new(),
+        }
+    }
+    pub fn seq(mut self, seq: i64) -> Self {
+        self.seq = seq;
+        self
+    }
+    pub fn rev(mut self, rev: &str) -> Self {
+        self.rev = rev.to_string();
+        self
+    }
+    pub fn time(mut self, time: &str) -> Self {
+        self.time = time.to_string();
+        self
+    }
+    pub fn too_big(mut self, too_big: bool) -> Self {
+        self.too_big = too_big;
+        self
+    }
+    /// Replace the generated CAR file with arbitrary bytes.
+    pub fn raw_blocks(mut self, blocks: Vec<u8>) -> Self {
+        self.blocks = Some(blocks);
+        self
+    }
+    /// Add an extra raw block to the CAR file, not referenced by any op.
+    pub fn extra_block(mut self, data: Vec<u8>) -> Self {
+        self.extra_blocks.push(data);
+        self
+    }
+    pub fn create(mut self, collection: &str, rkey: &str, record: Ipld) -> Self {
+        self.ops.push(TestOp {
+            action: "create",
+            collection: collection.to_string(),
+            rkey: rkey.to_string(),
+            record: Some(record),
+        });
+        self
+    }
+    pub fn update(mut self, collection: &str, rkey: &str, record: Ipld) -> Self {
+        self.ops.push(TestOp {
+            action: "update",
+            collection: collection.to_string(),
+            rkey: rkey.to_string(),
+            record: Some(record),
+        });
+        self
+    }
+    pub fn delete(mut self, collection: &str, rkey: &str) -> Self {
+        self.ops.push(TestOp {
+            action: "delete",
+            collection: collection.to_string(),
+            rkey: rkey.to_string(),
+            record: None,
+        });
+        self
+    }
+    /// An operation with any `action`, e.g. one the decoder does not know.
+    pub fn action(
+        mut self,
+        action: &'static str,
+        collection: &str,
+        rkey: &str,
+        record: Ipld,
+    ) -> Self {
+        self.ops.push(TestOp {
+            action,
+            collection: collection.to_string(),
+            rkey: rkey.to_string(),
+            record: Some(record),
+        });
+        self
+    }
+    pub fn create_post(self, rkey: &str, text: &str) -> Self {
+        self.create("app.bsky.feed.post", rkey, post_record(text))
+    }
+
+    /// The commit envelope, as found in the frame body.
+    pub fn body(&self) -> Ipld {
+        let commit_block = serde_ipld_dagcbor::to_vec(&ipld_map([
+            ("did", Ipld::String(self.repo.clone())),
+            ("version", Ipld::Integer(3)),
+            ("data", Ipld::Link(cid_for(&[]))),
+            ("rev", Ipld::String(self.rev.clone())),
+            ("prev", Ipld::Null),
+            ("sig", Ipld::Bytes(vec![0; 64])),
+        ]))
+        .unwrap();
+        let commit_cid = cid_for(&commit_block);
+
+        let mut blocks = vec![commit_block];
+        let mut ops = Vec::new();
+        for op in &self.ops {
+            let cid = op.record.as_ref().map(|record| {
+                let block = serde_ipld_dagcbor::to_vec(record).unwrap();
+                let cid = cid_for(&block);
+                blocks.push(block);
+                cid
+            });
+            ops.push(ipld_map([
+                ("action", Ipld::String(op.action.into())),
+                (
+                    "path",
+                    Ipld::String(format!("{}/{}", op.collection, op.rkey)),
+                ),
+                ("cid", cid.map(Ipld::Link).unwrap_or(Ipld::Null)),
+            ]));
+        }
+        blocks.extend(self.extra_blocks.iter().cloned());
+
+        let car = self
+            .blocks
+            .clone()
+            .unwrap_or_else(|| car_bytes(&commit_cid, &blocks));
+
+        ipld_map([
+            ("seq", Ipld::Integer(self.seq.into())),
+            ("rebase", Ipld::Bool(false)),
+            ("tooBig", Ipld::Bool(self.too_big)),
+            ("repo", Ipld::String(self.repo.clone())),
+            ("commit", Ipld::Link(commit_cid)),
+            ("rev", Ipld::String(self.rev.clone())),
+            ("since", Ipld::Null),
+            ("blocks", Ipld::Bytes(car)),
+            ("ops", Ipld::List(ops)),
+            ("blobs", Ipld::List(vec![])),
+            ("time", Ipld::String(self.time.clone())),
+        ])
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        message_frame_bytes("#commit", &self.body())
+    }
+    pub fn build(&self) -> Frame {
+        message_frame("#commit", &self.body())
+    }
+}
+
+/// Encode blocks as a CARv1 file with a single root.
+pub fn car_bytes(root: &Cid, blocks: &[Vec<u8>]) -> Vec<u8> {
+    let header = serde_ipld_dagcbor::to_vec(&ipld_map([
+        ("roots", Ipld::List(vec![Ipld::Link(*root)])),
+        ("version", Ipld::Integer(1)),
+    ]))
+    .unwrap();
+    let mut car = Vec::new();
+    write_varint(&mut car, header.len() as u64);
+    car.extend(header);
+    for block in blocks {
+        let cid = cid_for(block).to_bytes();
+        write_varint(&mut car, (cid.len() + block.len()) as u64);
+        car.extend(cid);
+        car.extend(block);
+    }
+    car
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FirehoseMessage, Operation, Record};
+
+    #[test]
+    fn built_commit_decodes() {
+        let frame = CommitBuilder::new(TEST_DID)
+            .seq(42)
+            .create_post("3kabc", "hello world")
+            .delete("app.bsky.feed.like", "3kdef")
+            .build();
+        let FirehoseMessage::Commit {
+            did, operations, ..
+        } = FirehoseMessage::try_from(frame).expect("failed to decode")
+        else {
+            panic!("expected a commit");
+        };
+        assert_eq!(did.as_str(), TEST_DID);
+        assert_eq!(operations.len(), 2);
+        match &operations[0] {
+            Operation::Create {
+                operation_meta,
+                record: Record::Post(post),
+                ..
+            } => {
+                assert_eq!(operation_meta.collection, "app.bsky.feed.post");
+                assert_eq!(operation_meta.rkey, "3kabc");
+                assert_eq!(post.text, "hello world");
+            }
+            _ => panic!("expected a post creation"),
+        }
+        match &operations[1] {
+            Operation::Delete(meta) => assert_eq!(meta.rkey, "3kdef"),
+            _ => panic!("expected a deletion"),
+        }
+    }
+}
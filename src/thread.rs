@@ -0,0 +1,130 @@
+//! Thread integrity of replies.
+//!
+//! A reply references the version of its parent it was written against. When the parent was
+//! edited since, the CID of the reference no longer matches the current one: the reply
+//! may answer content that changed. Knowing the current CID of a post needs external state,
+//! provided by a [`ParentResolver`].
+use std::future::Future;
+
+use ipld_core::cid::Cid;
+
+use crate::Record;
+
+/// Current version of posts, e.g. from an AppView or a local index of the firehose.
+pub trait ParentResolver {
+    type Error;
+
+    /// CID of the current version of the post at `uri`, `None` when the post is not known.
+    fn current_cid(
+        &self,
+        uri: &str,
+    ) -> impl Future<Output = Result<Option<Cid>, Self::Error>> + Send;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParentCheck {
+    /// The reply references the current version of its parent.
+    Current,
+    /// The parent changed since the reply was written.
+    Mismatch { referenced: Cid, current: Cid },
+    /// The resolver does not know the parent, e.g. it was deleted.
+    Unknown,
+}
+
+/// Compare the parent CID referenced by a reply with the current one, `None` when `record`
+/// is not a reply.
+pub async fn check_parent<R: ParentResolver>(
+    record: &Record,
+    resolver: &R,
+) -> Result<Option<ParentCheck>, R::Error> {
+    let Some(reply) = record.reply_refs() else {
+        return Ok(None);
+    };
+    let referenced = reply.parent.cid;
+    Ok(Some(
+        match resolver.current_cid(&reply.parent.uri).await? {
+            Some(current) if current == referenced => ParentCheck::Current,
+            Some(current) => ParentCheck::Mismatch {
+                referenced,
+                current,
+            },
+            None => ParentCheck::Unknown,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, convert::Infallible};
+
+    use super::*;
+    use crate::test_util::{
+        CommitBuilder, TEST_DID, cid_for, decode_single_record, ipld_map, post_with, strong_ref,
+    };
+
+    struct MockResolver(HashMap<String, Cid>);
+
+    impl ParentResolver for MockResolver {
+        type Error = Infallible;
+
+        async fn current_cid(&self, uri: &str) -> Result<Option<Cid>, Infallible> {
+            Ok(self.0.get(uri).copied())
+        }
+    }
+
+    fn reply(parent: &str, parent_cid: &Cid) -> Record {
+        let root = format!("at://{TEST_DID}/app.bsky.feed.post/3kroot");
+        let reply = post_with(
+            "me too",
+            [(
+                "reply",
+                ipld_map([
+                    ("root", strong_ref(&root, &cid_for(b"root"))),
+                    ("parent", strong_ref(parent, parent_cid)),
+                ]),
+            )],
+        );
+        decode_single_record(CommitBuilder::new(TEST_DID).create(
+            "app.bsky.feed.post",
+            "3kreply",
+            reply,
+        ))
+    }
+
+    #[tokio::test]
+    async fn parent_cid_mismatch() {
+        let parent = "at://did:plc:someoneelse/app.bsky.feed.post/3kparent";
+        let edited = "at://did:plc:someoneelse/app.bsky.feed.post/3kedited";
+        let resolver = MockResolver(HashMap::from([
+            (parent.to_string(), cid_for(b"parent")),
+            (edited.to_string(), cid_for(b"edited v2")),
+        ]));
+
+        let record = reply(parent, &cid_for(b"parent"));
+        let refs = record.reply_refs().unwrap();
+        assert_eq!(refs.parent.uri, parent);
+        assert_eq!(refs.parent.cid, cid_for(b"parent"));
+        assert_eq!(refs.root.cid, cid_for(b"root"));
+        let check = check_parent(&record, &resolver).await.unwrap();
+        assert_eq!(check, Some(ParentCheck::Current));
+
+        let record = reply(edited, &cid_for(b"edited v1"));
+        let check = check_parent(&record, &resolver).await.unwrap();
+        assert_eq!(
+            check,
+            Some(ParentCheck::Mismatch {
+                referenced: cid_for(b"edited v1"),
+                current: cid_for(b"edited v2"),
+            })
+        );
+
+        let record = reply("at://did:plc:gone/app.bsky.feed.post/3k", &cid_for(b"x"));
+        let check = check_parent(&record, &resolver).await.unwrap();
+        assert_eq!(check, Some(ParentCheck::Unknown));
+
+        let record =
+            decode_single_record(CommitBuilder::new(TEST_DID).create_post("3kpost", "hello"));
+        assert_eq!(record.reply_refs(), None);
+        assert_eq!(check_parent(&record, &resolver).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,121 @@
+//! Timestamp identifiers (TIDs), the format of commit revisions and of most record keys.
+//!
+//! A TID is a 64-bit integer, written as 13 characters of sortable base32: the top bit is zero,
+//! then come 53 bits of microseconds since the unix epoch and a 10-bit clock identifier.
+use std::{fmt, str::FromStr};
+
+use atrium_api::types::string::Datetime;
+
+const ALPHABET: &[u8; 32] = b"234567abcdefghijklmnopqrstuvwxyz";
+const LEN: usize = 13;
+const CLOCK_ID_BITS: u32 = 10;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid TID {0:?}")]
+pub struct InvalidTid(pub String);
+
+/// A parsed TID.
+///
+/// The timestamp is set by the PDS when writing the commit, which makes it a wall-clock time
+/// independent from the `time` field of the commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tid(u64);
+
+impl Tid {
+    /// `None` when `timestamp_micros` does not fit in 53 bits or `clock_id` in 10 bits.
+    pub fn new(timestamp_micros: u64, clock_id: u16) -> Option<Self> {
+        if timestamp_micros >> 53 != 0 || clock_id >> CLOCK_ID_BITS != 0 {
+            return None;
+        }
+        Some(Tid(
+            (timestamp_micros << CLOCK_ID_BITS) | u64::from(clock_id)
+        ))
+    }
+    pub fn timestamp_micros(&self) -> u64 {
+        self.0 >> CLOCK_ID_BITS
+    }
+    pub fn timestamp(&self) -> Datetime {
+        let micros = i64::try_from(self.timestamp_micros()).unwrap_or_default();
+        Datetime::new(
+            chrono::DateTime::from_timestamp_micros(micros)
+                .unwrap_or_default()
+                .fixed_offset(),
+        )
+    }
+    /// Random identifier of the clock which generated the TID, telling apart TIDs generated
+    /// during the same microsecond.
+    pub fn clock_id(&self) -> u16 {
+        (self.0 & ((1 << CLOCK_ID_BITS) - 1)) as u16
+    }
+}
+
+impl FromStr for Tid {
+    type Err = InvalidTid;
+
+    fn from_str(s: &str) -> Result<Self, InvalidTid> {
+        let invalid = || InvalidTid(s.to_string());
+        if s.len() != LEN {
+            return Err(invalid());
+        }
+        // the first character carries the top bit, which must be zero
+        if s.as_bytes()[0] >= b'k' {
+            return Err(invalid());
+        }
+        s.bytes()
+            .try_fold(0u64, |value, c| {
+                let digit = ALPHABET.iter().position(|a| *a == c)?;
+                Some((value << 5) | digit as u64)
+            })
+            .map(Tid)
+            .ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for Tid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut chars = [0; LEN];
+        for (i, c) in chars.iter_mut().enumerate() {
+            let shift = 5 * (LEN - 1 - i);
+            *c = ALPHABET[((self.0 >> shift) & 31) as usize];
+        }
+        // the alphabet is ASCII
+        f.write_str(std::str::from_utf8(&chars).unwrap())
+    }
+}
+
+impl TryFrom<&atrium_api::types::string::Tid> for Tid {
+    type Error = InvalidTid;
+
+    fn try_from(tid: &atrium_api::types::string::Tid) -> Result<Self, InvalidTid> {
+        tid.as_str().parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TEST_REV;
+
+    #[test]
+    fn parse_tids() {
+        let tid = TEST_REV.parse::<Tid>().unwrap();
+        assert_eq!(tid.timestamp_micros(), 1688137381887007);
+        assert_eq!(
+            *tid.timestamp().as_ref(),
+            chrono::DateTime::parse_from_rfc3339("2023-06-30T15:03:01.887007Z").unwrap()
+        );
+        assert_eq!(tid.clock_id(), 6);
+        assert_eq!(tid.to_string(), TEST_REV);
+        assert_eq!(Tid::new(tid.timestamp_micros(), tid.clock_id()), Some(tid));
+
+        for invalid in [
+            "",
+            "3jzfcijpj2z2",
+            "3jzfcijpj2z2a2",
+            "3jzfcijpj2z2!",
+            "kjzfcijpj2z2a",
+        ] {
+            assert_eq!(invalid.parse::<Tid>(), Err(InvalidTid(invalid.to_string())));
+        }
+    }
+}
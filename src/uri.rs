@@ -0,0 +1,57 @@
+//! Parsing of the `at://` URIs records use to reference each other.
+
+/// Parts of an `at://{authority}/{collection}/{rkey}` URI.
+///
+/// The authority is usually a DID but may be a handle, collection and rkey are optional.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtUri<'a> {
+    pub authority: &'a str,
+    pub collection: Option<&'a str>,
+    pub rkey: Option<&'a str>,
+}
+
+impl<'a> AtUri<'a> {
+    /// `None` when `uri` is not an `at://` URI or has an empty authority.
+    ///
+    /// Query and fragment are ignored.
+    pub fn parse(uri: &'a str) -> Option<Self> {
+        let rest = uri.strip_prefix("at://")?;
+        let rest = rest.split(['?', '#']).next().unwrap_or_default();
+        let mut segments = rest.split('/');
+        let authority = segments.next().filter(|authority| !authority.is_empty())?;
+        let mut segment = || segments.next().filter(|segment| !segment.is_empty());
+        let collection = segment();
+        let rkey = segment();
+        Some(AtUri {
+            authority,
+            collection,
+            rkey,
+        })
+    }
+    /// The authority when it is a DID rather than a handle.
+    pub fn did(&self) -> Option<&'a str> {
+        self.authority.starts_with("did:").then_some(self.authority)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            AtUri::parse("at://did:plc:ewvi7nxzyoun6zhxrhs64oiz/app.bsky.feed.post/3kabc"),
+            Some(AtUri {
+                authority: "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+                collection: Some("app.bsky.feed.post"),
+                rkey: Some("3kabc"),
+            })
+        );
+        let handle = AtUri::parse("at://alice.bsky.social").unwrap();
+        assert_eq!(handle.collection, None);
+        assert_eq!(handle.did(), None);
+        assert_eq!(AtUri::parse("https://bsky.app/profile/alice"), None);
+        assert_eq!(AtUri::parse("at:///app.bsky.feed.post"), None);
+    }
+}
@@ -0,0 +1,165 @@
+//! Verification of commit signatures against the signing key of the repository.
+use std::collections::HashSet;
+
+use atrium_api::{com::atproto::sync::subscribe_repos::Commit, did_doc::DidDocument};
+use ipld_core::ipld::Ipld;
+
+use crate::{Error, car};
+
+/// Multicodec prefixes of the compressed public keys found in `did:key`s.
+const SECP256K1_PUB: [u8; 2] = [0xe7, 0x01];
+const P256_PUB: [u8; 2] = [0x80, 0x24];
+
+/// Signing key of a repository, found in the `#atproto` verification method of its DID
+/// document.
+#[derive(Debug, Clone)]
+pub enum PublicKey {
+    K256(k256::ecdsa::VerifyingKey),
+    P256(p256::ecdsa::VerifyingKey),
+}
+
+impl PublicKey {
+    /// Parse a `did:key:z...`, or a `publicKeyMultibase` (the same without `did:key:`).
+    pub fn from_did_key(did_key: &str) -> Result<Self, Error> {
+        let invalid = || Error::SignatureVerificationFailed(format!("invalid did:key {did_key}"));
+        let multibase = did_key.strip_prefix("did:key:").unwrap_or(did_key);
+        let bytes = multibase
+            .strip_prefix('z')
+            .and_then(|base58| bs58::decode(base58).into_vec().ok())
+            .ok_or_else(invalid)?;
+        match bytes.split_at_checked(2) {
+            Some((prefix, key)) if prefix == SECP256K1_PUB => {
+                k256::ecdsa::VerifyingKey::from_sec1_bytes(key)
+                    .map(PublicKey::K256)
+                    .map_err(|_| invalid())
+            }
+            Some((prefix, key)) if prefix == P256_PUB => {
+                p256::ecdsa::VerifyingKey::from_sec1_bytes(key)
+                    .map(PublicKey::P256)
+                    .map_err(|_| invalid())
+            }
+            _ => Err(invalid()),
+        }
+    }
+    /// The `#atproto` signing key of a resolved DID document, see
+    /// [`DidResolver`](crate::resolver::DidResolver).
+    pub fn from_did_document(document: &DidDocument) -> Result<Self, Error> {
+        let multibase = document
+            .verification_method
+            .iter()
+            .flatten()
+            .find(|method| method.id.ends_with("#atproto"))
+            .and_then(|method| method.public_key_multibase.as_deref())
+            .ok_or_else(|| {
+                Error::SignatureVerificationFailed(format!(
+                    "no #atproto signing key for {}",
+                    document.id
+                ))
+            })?;
+        Self::from_did_key(multibase)
+    }
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        use k256::ecdsa::signature::Verifier;
+
+        match self {
+            PublicKey::K256(key) => k256::ecdsa::Signature::from_slice(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+            PublicKey::P256(key) => p256::ecdsa::Signature::from_slice(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+        }
+    }
+}
+
+pub trait VerifySignature {
+    /// Whether the signed commit object was signed by `public_key`.
+    ///
+    /// Fails with [`Error::SignatureVerificationFailed`] when the signature cannot be checked
+    /// at all, e.g. the commit object is missing from the blocks.
+    fn verify_signature(&self, public_key: &PublicKey) -> Result<bool, Error>;
+}
+
+impl VerifySignature for Commit {
+    fn verify_signature(&self, public_key: &PublicKey) -> Result<bool, Error> {
+        let failed = |reason: String| Error::SignatureVerificationFailed(reason);
+        let commit_cid = self.commit.0;
+        let blocks = car::read_blocks(&self.blocks, &HashSet::from([commit_cid]))
+            .map_err(|e| Error::InvalidCar(e, self.clone()))?;
+        let block = blocks
+            .get(&commit_cid)
+            .ok_or_else(|| failed(format!("no block for commit object {commit_cid}")))?;
+        let signed = serde_ipld_dagcbor::from_slice::<Ipld>(block)
+            .map_err(|e| failed(format!("invalid commit object: {e}")))?;
+        let Ipld::Map(mut unsigned) = signed else {
+            return Err(failed("commit object is not a map".to_string()));
+        };
+        let Some(Ipld::Bytes(signature)) = unsigned.remove("sig") else {
+            return Err(failed("commit object is not signed".to_string()));
+        };
+        let unsigned = serde_ipld_dagcbor::to_vec(&Ipld::Map(unsigned))
+            .map_err(|e| failed(format!("unable to encode the unsigned commit: {e}")))?;
+        Ok(public_key.verify(&unsigned, &signature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k256::ecdsa::{SigningKey, signature::Signer};
+
+    use super::*;
+    use crate::{
+        FirehoseMessage,
+        test_util::{
+            CommitBuilder, TEST_DID, TEST_REV, car_bytes, cid_for, ipld_map, message_frame,
+        },
+    };
+
+    #[test]
+    fn commit_signatures() {
+        let signing_key = SigningKey::from_slice(&[7; 32]).unwrap();
+        let Ipld::Map(mut commit_object) = ipld_map([
+            ("did", Ipld::String(TEST_DID.into())),
+            ("version", Ipld::Integer(3)),
+            ("data", Ipld::Link(cid_for(&[]))),
+            ("rev", Ipld::String(TEST_REV.into())),
+            ("prev", Ipld::Null),
+        ]) else {
+            unreachable!()
+        };
+        let unsigned = serde_ipld_dagcbor::to_vec(&Ipld::Map(commit_object.clone())).unwrap();
+        let signature: k256::ecdsa::Signature = signing_key.sign(&unsigned);
+        commit_object.insert("sig".into(), Ipld::Bytes(signature.to_vec()));
+        let block = serde_ipld_dagcbor::to_vec(&Ipld::Map(commit_object)).unwrap();
+        let commit_cid = cid_for(&block);
+
+        let Ipld::Map(mut body) = CommitBuilder::new(TEST_DID).body() else {
+            panic!("expected a map");
+        };
+        body.insert("commit".into(), Ipld::Link(commit_cid));
+        body.insert(
+            "blocks".into(),
+            Ipld::Bytes(car_bytes(&commit_cid, &[block])),
+        );
+        let frame = message_frame("#commit", &Ipld::Map(body));
+        let FirehoseMessage::Commit { commit, .. } = FirehoseMessage::try_from(frame).unwrap()
+        else {
+            panic!("expected a commit");
+        };
+
+        let verifying_key = signing_key.verifying_key().to_sec1_bytes();
+        let did_key = format!(
+            "did:key:z{}",
+            bs58::encode([&SECP256K1_PUB[..], &verifying_key].concat()).into_string()
+        );
+        let public_key = PublicKey::from_did_key(&did_key).unwrap();
+        assert!(commit.verify_signature(&public_key).unwrap());
+
+        let other_key = SigningKey::from_slice(&[8; 32]).unwrap();
+        let other_key = PublicKey::K256(k256::ecdsa::VerifyingKey::from(&other_key));
+        assert!(!commit.verify_signature(&other_key).unwrap());
+
+        assert!(matches!(
+            PublicKey::from_did_key("did:key:zInvalid"),
+            Err(Error::SignatureVerificationFailed(_))
+        ));
+    }
+}